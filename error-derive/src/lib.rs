@@ -0,0 +1,342 @@
+//! `#[derive(Error)]` for the crate's error types.
+//!
+//! Generates the `Display`, `std::error::Error` and `From` impls that the
+//! error-handling exercises otherwise write by hand:
+//!
+//! - `#[error("...")]` on a struct or on every enum variant gives the
+//!   `Display` text. Named fields are used as `{field}` and tuple fields as
+//!   `{0}`, `{1}`, with the usual format specs (`{0:?}`, `{code:>4}`).
+//! - `#[error(transparent)]` forwards both `Display` and `source` to the
+//!   single field.
+//! - `#[source]` marks the field returned by `Error::source`. A field named
+//!   `source` is the source without the attribute. The field may be any
+//!   error type, a `Box<dyn Error + ...>`, or an `Option` of either.
+//! - `#[from]` marks the source and also generates `From<FieldType>`; the
+//!   field must be the only one in its variant.
+//!
+//! ```ignore
+//! #[derive(Debug, Error)]
+//! pub enum ServiceError {
+//!     #[error("Service database error: {0}")]
+//!     Database(#[from] DatabaseError),
+//!     #[error("Validation error: {0}")]
+//!     Validation(String),
+//! }
+//! ```
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    Attribute, Data, DeriveInput, Fields, Ident, LitStr, Member, Result, Type, parse_macro_input,
+};
+
+#[proc_macro_derive(Error, attributes(error, source, from))]
+pub fn derive_error(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// What `#[error(...)]` says to display.
+enum Display {
+    Format(LitStr),
+    /// Spanned on the `transparent` keyword for error reporting.
+    Transparent(Span),
+}
+
+/// One field, with the name it is bound to inside generated `match` arms.
+struct Field<'a> {
+    member: Member,
+    binding: Ident,
+    ty: &'a Type,
+    source: bool,
+    from: bool,
+    /// The `#[from]` or `#[source]` attribute, or the field itself, for
+    /// pointing errors at it.
+    span: TokenStream2,
+}
+
+/// A struct or one enum variant.
+struct Shape<'a> {
+    display: Display,
+    fields: Vec<Field<'a>>,
+    named: bool,
+    /// `Self` for structs, `Self::Variant` for enum variants.
+    path: TokenStream2,
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2> {
+    let shapes = match &input.data {
+        Data::Struct(data) => {
+            let display = display_attr(&input.attrs, &input.ident)?;
+            vec![shape(display, &data.fields, quote!(Self))?]
+        }
+        Data::Enum(data) => data
+            .variants
+            .iter()
+            .map(|variant| {
+                let display = display_attr(&variant.attrs, &variant.ident)?;
+                let ident = &variant.ident;
+                shape(display, &variant.fields, quote!(Self::#ident))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Data::Union(_) => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "#[derive(Error)] does not support unions",
+            ));
+        }
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let display_arms = shapes.iter().map(display_arm);
+    let source_arms = shapes.iter().map(source_arm);
+    let as_dyn_error = shapes
+        .iter()
+        .any(|s| matches!(s.display, Display::Transparent(_)) || s.fields.iter().any(|f| f.source))
+        .then(as_dyn_error);
+    let from_impls = shapes
+        .iter()
+        .filter_map(|s| s.fields.iter().find(|f| f.from).map(|f| (s, f)))
+        .map(|(shape, field)| {
+            let ty = field.ty;
+            let path = &shape.path;
+            let construct = match &field.member {
+                Member::Named(ident) => quote!(#path { #ident: value }),
+                Member::Unnamed(_) => quote!(#path(value)),
+            };
+            quote! {
+                impl #impl_generics ::core::convert::From<#ty> for #name #ty_generics #where_clause {
+                    fn from(value: #ty) -> Self {
+                        #construct
+                    }
+                }
+            }
+        });
+
+    Ok(quote! {
+        impl #impl_generics ::core::fmt::Display for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn fmt(&self, __formatter: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                match self {
+                    #(#display_arms)*
+                }
+            }
+        }
+
+        impl #impl_generics ::std::error::Error for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn source(&self) -> ::core::option::Option<&(dyn ::std::error::Error + 'static)> {
+                #as_dyn_error
+                match self {
+                    #(#source_arms)*
+                }
+            }
+        }
+
+        #(#from_impls)*
+    })
+}
+
+fn display_attr(attrs: &[Attribute], owner: &Ident) -> Result<Display> {
+    let mut found = None;
+    for attr in attrs.iter().filter(|a| a.path().is_ident("error")) {
+        if found.is_some() {
+            return Err(syn::Error::new_spanned(
+                attr,
+                "duplicate #[error] attribute",
+            ));
+        }
+        found = Some(attr.parse_args_with(|input: syn::parse::ParseStream| {
+            if input.peek(Ident) {
+                let ident: Ident = input.parse()?;
+                if ident == "transparent" {
+                    return Ok(Display::Transparent(ident.span()));
+                }
+                return Err(syn::Error::new_spanned(
+                    ident,
+                    "expected a format string or `transparent`",
+                ));
+            }
+            Ok(Display::Format(input.parse()?))
+        })?);
+    }
+    found.ok_or_else(|| syn::Error::new_spanned(owner, "missing #[error(\"...\")] attribute"))
+}
+
+fn shape<'a>(display: Display, fields: &'a Fields, path: TokenStream2) -> Result<Shape<'a>> {
+    let named = matches!(fields, Fields::Named(_));
+    let mut out = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let (member, binding) = match &field.ident {
+            Some(ident) => (Member::Named(ident.clone()), ident.clone()),
+            None => (Member::Unnamed(i.into()), format_ident!("_{}", i)),
+        };
+        let from_attr = field.attrs.iter().find(|a| a.path().is_ident("from"));
+        let source_attr = field.attrs.iter().find(|a| a.path().is_ident("source"));
+        let from = from_attr.is_some();
+        let source = from
+            || source_attr.is_some()
+            || field.ident.as_ref().is_some_and(|ident| ident == "source");
+        let span = match from_attr.or(source_attr) {
+            Some(attr) => quote!(#attr),
+            None => quote!(#field),
+        };
+        out.push(Field {
+            member,
+            binding,
+            ty: &field.ty,
+            source,
+            from,
+            span,
+        });
+    }
+
+    if let Some(field) = out.iter().find(|f| f.from)
+        && out.len() != 1
+    {
+        return Err(syn::Error::new_spanned(
+            &field.span,
+            "#[from] requires the field to be the only one in its variant",
+        ));
+    }
+    if let Some(field) = out.iter().filter(|f| f.source).nth(1) {
+        return Err(syn::Error::new_spanned(
+            &field.span,
+            "only one field can be the error source",
+        ));
+    }
+    if let Display::Transparent(span) = display
+        && out.len() != 1
+    {
+        return Err(syn::Error::new(
+            span,
+            "#[error(transparent)] requires exactly one field",
+        ));
+    }
+
+    Ok(Shape {
+        display,
+        fields: out,
+        named,
+        path,
+    })
+}
+
+/// `Self::Variant { a, b }` or `Self::Variant(_0, _1)`, binding every field.
+fn pattern(shape: &Shape) -> TokenStream2 {
+    let path = &shape.path;
+    let bindings = shape.fields.iter().map(|f| &f.binding);
+    if shape.named {
+        quote!(#path { #(#bindings),* })
+    } else if shape.fields.is_empty() {
+        quote!(#path)
+    } else {
+        quote!(#path(#(#bindings),*))
+    }
+}
+
+fn display_arm(shape: &Shape) -> TokenStream2 {
+    let pat = pattern(shape);
+    match &shape.display {
+        Display::Transparent(_) => {
+            let binding = &shape.fields[0].binding;
+            quote!(#pat => ::core::fmt::Display::fmt(#binding, __formatter),)
+        }
+        Display::Format(lit) => {
+            let text = LitStr::new(&rewrite_positional(&lit.value()), lit.span());
+            quote!(#pat => ::core::write!(__formatter, #text),)
+        }
+    }
+}
+
+/// A trait local to the generated `source` that turns both sized errors and
+/// `dyn Error` trait objects behind a `Box` into `&dyn Error`. Method-call
+/// auto-deref finds the trait-object impls through the box.
+fn as_dyn_error() -> TokenStream2 {
+    let objects = [
+        quote!(dyn ::std::error::Error + 'static),
+        quote!(dyn ::std::error::Error + ::core::marker::Send + 'static),
+        quote!(dyn ::std::error::Error + ::core::marker::Send + ::core::marker::Sync + 'static),
+    ];
+    quote! {
+        trait __AsDynError {
+            fn __as_dyn_error(&self) -> &(dyn ::std::error::Error + 'static);
+        }
+        impl<T: ::std::error::Error + 'static> __AsDynError for T {
+            fn __as_dyn_error(&self) -> &(dyn ::std::error::Error + 'static) {
+                self
+            }
+        }
+        #(
+            impl __AsDynError for #objects {
+                fn __as_dyn_error(&self) -> &(dyn ::std::error::Error + 'static) {
+                    self
+                }
+            }
+        )*
+    }
+}
+
+/// True for `Option<T>`, however the path to it is spelled.
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) if path.qself.is_none() => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Option"),
+        _ => false,
+    }
+}
+
+fn source_arm(shape: &Shape) -> TokenStream2 {
+    let pat = pattern(shape);
+    let field = match shape.display {
+        Display::Transparent(_) => shape.fields.first(),
+        Display::Format(_) => shape.fields.iter().find(|f| f.source),
+    };
+    match field {
+        Some(field) if matches!(shape.display, Display::Transparent(_)) => {
+            let binding = &field.binding;
+            quote!(#pat => ::std::error::Error::source(#binding.__as_dyn_error()),)
+        }
+        Some(field) if is_option(field.ty) => {
+            let binding = &field.binding;
+            quote! {
+                #pat => ::core::option::Option::as_ref(#binding)
+                    .map(|__source| __source.__as_dyn_error()),
+            }
+        }
+        Some(field) => {
+            let binding = &field.binding;
+            quote!(#pat => ::core::option::Option::Some(#binding.__as_dyn_error()),)
+        }
+        None => quote!(#pat => ::core::option::Option::None,),
+    }
+}
+
+/// Turns `{0}` / `{1:?}` into `{_0}` / `{_1:?}` so tuple fields can be
+/// captured by name like named fields. `{{` escapes are left alone.
+fn rewrite_positional(format: &str) -> String {
+    let mut out = String::with_capacity(format.len() + 4);
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        out.push(c);
+        if c != '{' {
+            continue;
+        }
+        if chars.peek() == Some(&'{') {
+            out.push(chars.next().unwrap());
+            continue;
+        }
+        if chars.peek().is_some_and(char::is_ascii_digit) {
+            out.push('_');
+        }
+    }
+    out
+}
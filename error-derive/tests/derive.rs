@@ -0,0 +1,214 @@
+//! The derived impls must behave like the hand-written ones the
+//! error-handling exercises ask for (exercises 09, 21 and 28).
+
+use error_derive::Error;
+use std::error::Error as _;
+use std::num::ParseIntError;
+
+#[derive(Debug, PartialEq, Error)]
+pub enum MathError {
+    #[error("Cannot divide by zero")]
+    DivisionByZero,
+    #[error("Cannot take square root of negative number")]
+    NegativeSquareRoot,
+    #[error("Arithmetic overflow")]
+    Overflow,
+}
+
+#[derive(Debug, Error)]
+pub enum DatabaseError {
+    #[error("Connection error: {0}")]
+    ConnectionError(String),
+    #[error("Query error: {0}")]
+    QueryError(String),
+}
+
+#[derive(Debug, Error)]
+pub enum ServiceError {
+    #[error("Service database error: {0}")]
+    Database(#[from] DatabaseError),
+    #[error("Validation error: {0}")]
+    Validation(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum ValidationError {
+    #[error("input too short: {0} < {1}")]
+    TooShort(usize, usize),
+    #[error("input too long: {len} > {max}")]
+    TooLong { len: usize, max: usize },
+}
+
+#[derive(Debug, Error)]
+pub enum ApplicationError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Validation failed: {0}")]
+    Validation(#[from] ValidationError),
+    #[error("Parse error: {0}")]
+    Parse(#[from] ParseIntError),
+    #[error("{0}")]
+    Other(String),
+}
+
+#[derive(Debug, Error)]
+#[error("config key {key:?} is invalid ({{raw}} = {raw})")]
+pub struct ConfigError {
+    key: String,
+    raw: String,
+    source: ParseIntError,
+}
+
+#[derive(Debug, Error)]
+#[error("wrapped: {inner}")]
+pub struct Wrapper<E: std::error::Error + 'static> {
+    #[source]
+    inner: E,
+}
+
+#[derive(Debug, Error)]
+pub enum LoadError {
+    #[error("plugin failed")]
+    Plugin(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("cache miss")]
+    Cache { source: Option<std::io::Error> },
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+#[derive(Debug, Error)]
+pub enum PlotError {
+    #[error("bad point ({x}, {f})")]
+    Point { x: i32, f: i32 },
+}
+
+fn fetch_user(db_success: bool) -> Result<String, ServiceError> {
+    if !db_success {
+        Err(DatabaseError::ConnectionError("timeout".into()))?;
+    }
+    Ok("alice".into())
+}
+
+fn process_input(input: &str) -> Result<i32, ApplicationError> {
+    if input.len() < 3 {
+        Err(ValidationError::TooShort(input.len(), 3))?;
+    }
+    Ok(input.parse()?)
+}
+
+fn depth(err: &dyn std::error::Error) -> usize {
+    std::iter::successors(Some(err), |&e| e.source()).count()
+}
+
+#[test]
+fn unit_variants_display() {
+    assert_eq!(
+        MathError::DivisionByZero.to_string(),
+        "Cannot divide by zero"
+    );
+    assert_eq!(
+        MathError::NegativeSquareRoot.to_string(),
+        "Cannot take square root of negative number"
+    );
+    assert!(MathError::Overflow.source().is_none());
+}
+
+#[test]
+fn from_and_source_chain() {
+    let err = fetch_user(false).unwrap_err();
+    assert!(matches!(err, ServiceError::Database(_)));
+    assert_eq!(
+        err.to_string(),
+        "Service database error: Connection error: timeout"
+    );
+    assert_eq!(
+        err.source().unwrap().to_string(),
+        "Connection error: timeout"
+    );
+    assert_eq!(depth(&err), 2);
+
+    let err = ServiceError::Validation("name".into());
+    assert!(err.to_string().contains("Validation error"));
+    assert!(err.source().is_none());
+    assert_eq!(fetch_user(true).unwrap(), "alice");
+}
+
+#[test]
+fn multiple_from_impls() {
+    assert!(matches!(
+        process_input("12"),
+        Err(ApplicationError::Validation(ValidationError::TooShort(
+            2, 3
+        )))
+    ));
+    let err = process_input("abc").unwrap_err();
+    assert!(matches!(err, ApplicationError::Parse(_)));
+    assert!(err.source().unwrap().is::<ParseIntError>());
+    assert_eq!(process_input("123").unwrap(), 123);
+
+    let err = ApplicationError::from(ValidationError::TooLong { len: 11, max: 10 });
+    assert_eq!(
+        err.to_string(),
+        "Validation failed: input too long: 11 > 10"
+    );
+}
+
+#[test]
+fn transparent_forwards_display_and_source() {
+    let io = std::io::Error::new(std::io::ErrorKind::NotFound, "missing file");
+    let err = ApplicationError::from(io);
+    assert_eq!(err.to_string(), "missing file");
+    assert!(err.source().is_none());
+    assert_eq!(ApplicationError::Other("x".into()).to_string(), "x");
+}
+
+#[test]
+fn struct_with_implicit_source_and_format_specs() {
+    let err = ConfigError {
+        key: "port".into(),
+        raw: "http".into(),
+        source: "http".parse::<u16>().unwrap_err(),
+    };
+    assert_eq!(
+        err.to_string(),
+        "config key \"port\" is invalid ({raw} = http)"
+    );
+    assert!(err.source().unwrap().is::<ParseIntError>());
+}
+
+#[test]
+fn generic_struct_with_source_attribute() {
+    let err = Wrapper {
+        inner: MathError::Overflow,
+    };
+    assert_eq!(err.to_string(), "wrapped: Arithmetic overflow");
+    assert_eq!(depth(&err), 2);
+}
+
+#[test]
+fn field_named_f_does_not_shadow_formatter() {
+    let err = PlotError::Point { x: 1, f: 2 };
+    assert_eq!(err.to_string(), "bad point (1, 2)");
+}
+
+#[test]
+fn boxed_and_optional_sources() {
+    let err = LoadError::Plugin(Box::new(MathError::Overflow));
+    assert_eq!(err.source().unwrap().to_string(), "Arithmetic overflow");
+
+    let err = LoadError::Cache { source: None };
+    assert!(err.source().is_none());
+    let err = LoadError::Cache {
+        source: Some(std::io::Error::other("disk")),
+    };
+    assert_eq!(err.source().unwrap().to_string(), "disk");
+
+    let inner = Wrapper {
+        inner: MathError::DivisionByZero,
+    };
+    let err = LoadError::Other(Box::new(inner));
+    assert_eq!(err.to_string(), "wrapped: Cannot divide by zero");
+    assert_eq!(err.source().unwrap().to_string(), "Cannot divide by zero");
+}
@@ -0,0 +1,573 @@
+//! Exercise 31: Async Event Bus - Topic-based publish/subscribe
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Route typed events by topic with wildcard patterns
+//! - Give every subscriber its own bounded buffer
+//! - Choose what happens when a subscriber lags behind
+//! - Wake publishers and subscribers with tokio::sync::Notify
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// What the bus does when a subscriber's buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Discard the oldest buffered event to make room for the new one.
+    DropOldest,
+    /// Disconnect the subscriber; its next `recv` reports `RecvError::Lagged`.
+    Disconnect,
+    /// Make the publisher wait until the subscriber frees a slot.
+    Block,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BusError {
+    InvalidPattern(String),
+    InvalidTopic(String),
+    ZeroCapacity,
+    Closed,
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusError::InvalidPattern(p) => write!(f, "invalid topic pattern: {}", p),
+            BusError::InvalidTopic(t) => write!(f, "invalid topic: {}", t),
+            BusError::ZeroCapacity => write!(f, "subscription capacity must be at least 1"),
+            BusError::Closed => write!(f, "event bus is closed"),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// The subscriber fell behind under `LagPolicy::Disconnect`.
+    Lagged,
+    /// The bus was closed and the buffer is drained.
+    Closed,
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecvError::Lagged => write!(f, "subscriber lagged and was disconnected"),
+            RecvError::Closed => write!(f, "event bus is closed"),
+        }
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+/// A topic pattern made of `.`-separated segments.
+///
+/// `*` matches exactly one segment and a trailing `#` matches zero or more
+/// segments, so `orders.*.created` matches `orders.eu.created` and `orders.#`
+/// matches every topic below `orders`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicPattern {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    AnyOne,
+    AnyRest,
+}
+
+impl TopicPattern {
+    pub fn parse(pattern: &str) -> Result<Self, BusError> {
+        let parts: Vec<&str> = pattern.split('.').collect();
+        let mut segments = Vec::with_capacity(parts.len());
+        for (i, part) in parts.iter().enumerate() {
+            let segment = match *part {
+                "" => return Err(BusError::InvalidPattern(pattern.to_string())),
+                "*" => Segment::AnyOne,
+                "#" if i == parts.len() - 1 => Segment::AnyRest,
+                p if p.contains('*') || p.contains('#') => {
+                    return Err(BusError::InvalidPattern(pattern.to_string()));
+                }
+                p => Segment::Literal(p.to_string()),
+            };
+            segments.push(segment);
+        }
+        Ok(TopicPattern { segments })
+    }
+
+    pub fn matches(&self, topic: &str) -> bool {
+        let parts: Vec<&str> = topic.split('.').collect();
+        let mut i = 0;
+        for segment in &self.segments {
+            match segment {
+                Segment::AnyRest => return true,
+                Segment::AnyOne if i < parts.len() => {}
+                Segment::Literal(lit) if i < parts.len() && parts[i] == lit => {}
+                _ => return false,
+            }
+            i += 1;
+        }
+        i == parts.len()
+    }
+}
+
+fn validate_topic(topic: &str) -> Result<(), BusError> {
+    if topic.split('.').any(|p| p.is_empty() || p.contains('*') || p.contains('#')) {
+        return Err(BusError::InvalidTopic(topic.to_string()));
+    }
+    Ok(())
+}
+
+/// An event as seen by a subscriber.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event<T> {
+    pub topic: String,
+    pub payload: T,
+}
+
+/// What happened to a single `publish` call.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PublishReport {
+    pub delivered: usize,
+    pub dropped_oldest: usize,
+    pub disconnected: usize,
+}
+
+struct Buffer<T> {
+    queue: VecDeque<Event<T>>,
+    lagged: bool,
+}
+
+struct SubscriberShared<T> {
+    id: u64,
+    pattern: TopicPattern,
+    capacity: usize,
+    policy: LagPolicy,
+    buffer: Mutex<Buffer<T>>,
+    dropped: AtomicU64,
+    detached: AtomicBool,
+    items: Notify,
+    space: Notify,
+}
+
+struct BusShared<T> {
+    subscribers: Mutex<Vec<Arc<SubscriberShared<T>>>>,
+    next_id: AtomicU64,
+    closed: AtomicBool,
+}
+
+/// An in-process async event bus.
+///
+/// Cloning the bus is cheap; every clone publishes to the same subscribers.
+pub struct EventBus<T> {
+    shared: Arc<BusShared<T>>,
+}
+
+impl<T> Clone for EventBus<T> {
+    fn clone(&self) -> Self {
+        EventBus {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T: Clone + Send> EventBus<T> {
+    pub fn new() -> Self {
+        EventBus {
+            shared: Arc::new(BusShared {
+                subscribers: Mutex::new(Vec::new()),
+                next_id: AtomicU64::new(0),
+                closed: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Subscribes to every topic matching `pattern`.
+    ///
+    /// `capacity` is the per-subscriber buffer size and must be non-zero.
+    pub fn subscribe(
+        &self,
+        pattern: &str,
+        capacity: usize,
+        policy: LagPolicy,
+    ) -> Result<Subscription<T>, BusError> {
+        if self.is_closed() {
+            return Err(BusError::Closed);
+        }
+        if capacity == 0 {
+            return Err(BusError::ZeroCapacity);
+        }
+        let pattern = TopicPattern::parse(pattern)?;
+        let shared = Arc::new(SubscriberShared {
+            id: self.shared.next_id.fetch_add(1, Ordering::Relaxed),
+            pattern,
+            capacity,
+            policy,
+            buffer: Mutex::new(Buffer {
+                queue: VecDeque::new(),
+                lagged: false,
+            }),
+            dropped: AtomicU64::new(0),
+            detached: AtomicBool::new(false),
+            items: Notify::new(),
+            space: Notify::new(),
+        });
+        self.shared
+            .subscribers
+            .lock()
+            .unwrap()
+            .push(Arc::clone(&shared));
+        Ok(Subscription {
+            shared,
+            bus: Arc::clone(&self.shared),
+        })
+    }
+
+    /// Publishes `payload` to every subscriber whose pattern matches `topic`.
+    ///
+    /// Under `LagPolicy::Block` this waits for slow subscribers to make room.
+    pub async fn publish(&self, topic: &str, payload: T) -> Result<PublishReport, BusError> {
+        validate_topic(topic)?;
+        if self.is_closed() {
+            return Err(BusError::Closed);
+        }
+        let targets: Vec<Arc<SubscriberShared<T>>> = {
+            let mut subscribers = self.shared.subscribers.lock().unwrap();
+            subscribers.retain(|s| !s.detached.load(Ordering::Acquire));
+            subscribers
+                .iter()
+                .filter(|s| s.pattern.matches(topic))
+                .cloned()
+                .collect()
+        };
+
+        let mut report = PublishReport::default();
+        for sub in targets {
+            let event = Event {
+                topic: topic.to_string(),
+                payload: payload.clone(),
+            };
+            match deliver(&sub, event, &self.shared.closed).await {
+                Delivery::Delivered => report.delivered += 1,
+                Delivery::DroppedOldest => {
+                    report.delivered += 1;
+                    report.dropped_oldest += 1;
+                }
+                Delivery::Disconnected => report.disconnected += 1,
+                Delivery::Skipped => {}
+            }
+        }
+        Ok(report)
+    }
+
+    /// Number of live subscriptions.
+    pub fn subscriber_count(&self) -> usize {
+        let mut subscribers = self.shared.subscribers.lock().unwrap();
+        subscribers.retain(|s| !s.detached.load(Ordering::Acquire));
+        subscribers.len()
+    }
+
+    /// Closes the bus. Subscribers drain what is buffered, then get `RecvError::Closed`.
+    pub fn close(&self) {
+        self.shared.closed.store(true, Ordering::Release);
+        for sub in self.shared.subscribers.lock().unwrap().drain(..) {
+            sub.items.notify_one();
+            sub.space.notify_waiters();
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T: Clone + Send> Default for EventBus<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+enum Delivery {
+    Delivered,
+    DroppedOldest,
+    Disconnected,
+    Skipped,
+}
+
+async fn deliver<T>(sub: &SubscriberShared<T>, event: Event<T>, closed: &AtomicBool) -> Delivery {
+    loop {
+        // Register interest in freed space before checking, so a `recv`
+        // between the check and the await cannot be missed.
+        let space = sub.space.notified();
+        tokio::pin!(space);
+        space.as_mut().enable();
+        {
+            if sub.detached.load(Ordering::Acquire) || closed.load(Ordering::Acquire) {
+                return Delivery::Skipped;
+            }
+            let mut buffer = sub.buffer.lock().unwrap();
+            if buffer.lagged {
+                return Delivery::Skipped;
+            }
+            if buffer.queue.len() < sub.capacity {
+                buffer.queue.push_back(event);
+                drop(buffer);
+                sub.items.notify_one();
+                return Delivery::Delivered;
+            }
+            match sub.policy {
+                LagPolicy::DropOldest => {
+                    buffer.queue.pop_front();
+                    buffer.queue.push_back(event);
+                    drop(buffer);
+                    sub.dropped.fetch_add(1, Ordering::Relaxed);
+                    sub.items.notify_one();
+                    return Delivery::DroppedOldest;
+                }
+                LagPolicy::Disconnect => {
+                    buffer.queue.clear();
+                    buffer.lagged = true;
+                    drop(buffer);
+                    sub.detached.store(true, Ordering::Release);
+                    sub.items.notify_one();
+                    return Delivery::Disconnected;
+                }
+                LagPolicy::Block => {}
+            }
+        }
+        space.await;
+    }
+}
+
+/// The receiving half of a subscription. Dropping it unsubscribes.
+pub struct Subscription<T> {
+    shared: Arc<SubscriberShared<T>>,
+    bus: Arc<BusShared<T>>,
+}
+
+impl<T> Subscription<T> {
+    /// Waits for the next event.
+    pub async fn recv(&mut self) -> Result<Event<T>, RecvError> {
+        loop {
+            let items = self.shared.items.notified();
+            tokio::pin!(items);
+            items.as_mut().enable();
+            if let Some(result) = self.poll_buffer() {
+                return result;
+            }
+            items.await;
+        }
+    }
+
+    /// Returns the next buffered event without waiting.
+    pub fn try_recv(&mut self) -> Option<Result<Event<T>, RecvError>> {
+        self.poll_buffer()
+    }
+
+    fn poll_buffer(&self) -> Option<Result<Event<T>, RecvError>> {
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        if let Some(event) = buffer.queue.pop_front() {
+            drop(buffer);
+            self.shared.space.notify_one();
+            return Some(Ok(event));
+        }
+        if buffer.lagged {
+            return Some(Err(RecvError::Lagged));
+        }
+        if self.bus.closed.load(Ordering::Acquire) {
+            return Some(Err(RecvError::Closed));
+        }
+        None
+    }
+
+    /// Events discarded for this subscriber under `LagPolicy::DropOldest`.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.shared.id
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        self.shared.detached.store(true, Ordering::Release);
+        self.shared.space.notify_waiters();
+        self.bus
+            .subscribers
+            .lock()
+            .unwrap()
+            .retain(|s| s.id != self.shared.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{timeout, Duration};
+
+    #[test]
+    fn test_topic_patterns() {
+        let p = TopicPattern::parse("orders.*.created").unwrap();
+        assert!(p.matches("orders.eu.created"));
+        assert!(!p.matches("orders.eu.deleted"));
+        assert!(!p.matches("orders.created"));
+
+        let rest = TopicPattern::parse("orders.#").unwrap();
+        assert!(rest.matches("orders"));
+        assert!(rest.matches("orders.eu.created"));
+        assert!(!rest.matches("users.new"));
+
+        assert!(TopicPattern::parse("orders.#.created").is_err());
+        assert!(TopicPattern::parse("orders..x").is_err());
+        assert!(TopicPattern::parse("ord*").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_publish_routes_by_topic() {
+        let bus = EventBus::new();
+        let mut orders = bus.subscribe("orders.#", 8, LagPolicy::DropOldest).unwrap();
+        let mut created = bus.subscribe("*.created", 8, LagPolicy::DropOldest).unwrap();
+
+        let report = bus.publish("orders.created", 1).await.unwrap();
+        assert_eq!(report.delivered, 2);
+        bus.publish("users.created", 2).await.unwrap();
+        bus.publish("orders.shipped", 3).await.unwrap();
+
+        assert_eq!(orders.recv().await.unwrap().payload, 1);
+        assert_eq!(orders.recv().await.unwrap().payload, 3);
+        assert_eq!(created.recv().await.unwrap().payload, 1);
+        let ev = created.recv().await.unwrap();
+        assert_eq!((ev.topic.as_str(), ev.payload), ("users.created", 2));
+        assert!(created.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_publish_rejects_wildcard_topic() {
+        let bus: EventBus<i32> = EventBus::new();
+        assert_eq!(
+            bus.publish("orders.*", 1).await,
+            Err(BusError::InvalidTopic("orders.*".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_subscribe_rejects_zero_capacity() {
+        let bus: EventBus<i32> = EventBus::new();
+        assert!(matches!(
+            bus.subscribe("orders.#", 0, LagPolicy::Block),
+            Err(BusError::ZeroCapacity)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_policy() {
+        let bus = EventBus::new();
+        let mut sub = bus.subscribe("t", 2, LagPolicy::DropOldest).unwrap();
+        for i in 0..5 {
+            bus.publish("t", i).await.unwrap();
+        }
+        assert_eq!(sub.dropped(), 3);
+        assert_eq!(sub.recv().await.unwrap().payload, 3);
+        assert_eq!(sub.recv().await.unwrap().payload, 4);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_policy() {
+        let bus = EventBus::new();
+        let mut slow = bus.subscribe("t", 1, LagPolicy::Disconnect).unwrap();
+        let mut fast = bus.subscribe("t", 8, LagPolicy::Disconnect).unwrap();
+        bus.publish("t", 1).await.unwrap();
+        let report = bus.publish("t", 2).await.unwrap();
+        assert_eq!(report.disconnected, 1);
+        assert_eq!(report.delivered, 1);
+
+        assert_eq!(slow.recv().await, Err(RecvError::Lagged));
+        assert_eq!(fast.recv().await.unwrap().payload, 1);
+        assert_eq!(bus.subscriber_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_block_policy_applies_backpressure() {
+        let bus = EventBus::new();
+        let mut sub = bus.subscribe("t", 1, LagPolicy::Block).unwrap();
+        bus.publish("t", 1).await.unwrap();
+
+        let publisher = {
+            let bus = bus.clone();
+            tokio::spawn(async move { bus.publish("t", 2).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!publisher.is_finished());
+
+        assert_eq!(sub.recv().await.unwrap().payload, 1);
+        let report = timeout(Duration::from_secs(1), publisher)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.delivered, 1);
+        assert_eq!(sub.recv().await.unwrap().payload, 2);
+    }
+
+    #[tokio::test]
+    async fn test_dropping_subscription_unblocks_publisher() {
+        let bus = EventBus::new();
+        let sub = bus.subscribe("t", 1, LagPolicy::Block).unwrap();
+        bus.publish("t", 1).await.unwrap();
+        let publisher = {
+            let bus = bus.clone();
+            tokio::spawn(async move { bus.publish("t", 2).await })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(sub);
+        let report = timeout(Duration::from_secs(1), publisher)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.delivered, 0);
+        assert_eq!(bus.subscriber_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_close_drains_then_reports_closed() {
+        let bus = EventBus::new();
+        let mut sub = bus.subscribe("#", 4, LagPolicy::DropOldest).unwrap();
+        bus.publish("a", "x".to_string()).await.unwrap();
+        bus.close();
+        assert_eq!(bus.publish("a", "y".to_string()).await, Err(BusError::Closed));
+        assert_eq!(sub.recv().await.unwrap().payload, "x");
+        assert_eq!(sub.recv().await, Err(RecvError::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_publishers() {
+        let bus = EventBus::new();
+        let mut sub = bus.subscribe("jobs.*", 16, LagPolicy::Block).unwrap();
+        let mut handles = Vec::new();
+        for worker in 0..4 {
+            let bus = bus.clone();
+            handles.push(tokio::spawn(async move {
+                for i in 0..25 {
+                    bus.publish(&format!("jobs.w{}", worker), i).await.unwrap();
+                }
+            }));
+        }
+        let mut received = 0;
+        while received < 100 {
+            sub.recv().await.unwrap();
+            received += 1;
+        }
+        for h in handles {
+            h.await.unwrap();
+        }
+        assert!(sub.try_recv().is_none());
+    }
+}
@@ -0,0 +1,572 @@
+//! Exercise 32: Async Circuit Breaker - Failing fast when a dependency is down
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Model closed, open and half-open breaker states
+//! - Compute failure rates over a rolling time window
+//! - Probe a recovering dependency after a cool-down
+//! - Publish state changes with tokio::sync::broadcast
+//!
+//! The breaker wraps any `FnOnce() -> impl Future<Output = Result<T, E>>`, so
+//! it composes with the other helpers in this module: wrap a call to
+//! `exercise_14::retry` to retry inside one breaker call, or use
+//! `call_with_timeout` for the `exercise_06::with_timeout` behaviour with the
+//! timeout counted as a failure.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio::time::{timeout, Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Length of the rolling window used for the failure rate.
+    pub window: Duration,
+    /// Calls needed in the window before the failure rate is evaluated.
+    pub min_calls: usize,
+    /// Failure rate in `0.0..=1.0` at or above which the breaker opens.
+    pub failure_rate_threshold: f64,
+    /// Time spent open before probes are allowed.
+    pub cool_down: Duration,
+    /// Successful probes needed in half-open state to close again.
+    pub half_open_probes: usize,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            window: Duration::from_secs(10),
+            min_calls: 5,
+            failure_rate_threshold: 0.5,
+            cool_down: Duration::from_secs(5),
+            half_open_probes: 1,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CallError<E> {
+    /// The breaker rejected the call without running it.
+    Open,
+    /// The call did not finish within the given timeout.
+    Timeout,
+    /// The call ran and failed.
+    Inner(E),
+}
+
+impl<E: fmt::Display> fmt::Display for CallError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CallError::Open => write!(f, "circuit breaker is open"),
+            CallError::Timeout => write!(f, "call timed out"),
+            CallError::Inner(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for CallError<E> {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateChange {
+    pub from: CircuitState,
+    pub to: CircuitState,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitMetrics {
+    pub state: CircuitState,
+    pub total_calls: u64,
+    pub successes: u64,
+    pub failures: u64,
+    pub rejected: u64,
+    pub window_calls: usize,
+    pub window_failure_rate: f64,
+    pub state_changes: u64,
+}
+
+struct Inner {
+    state: CircuitState,
+    outcomes: VecDeque<(Instant, bool)>,
+    opened_at: Option<Instant>,
+    probes_in_flight: usize,
+    probe_successes: usize,
+    total_calls: u64,
+    successes: u64,
+    failures: u64,
+    rejected: u64,
+    state_changes: u64,
+}
+
+/// A circuit breaker shared between tasks. Cloning shares the same state.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    config: Arc<CircuitBreakerConfig>,
+    inner: Arc<Mutex<Inner>>,
+    events: broadcast::Sender<StateChange>,
+}
+
+/// Permission to run one call. Records the outcome exactly once.
+///
+/// If the call is cancelled before it finishes (for example by an outer
+/// `tokio::time::timeout`), dropping the permit frees a half-open probe slot
+/// and counts the probe as failed, so the breaker cannot get stuck half-open.
+struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+    finished: bool,
+}
+
+impl Permit<'_> {
+    fn finish(mut self, success: bool) {
+        self.finished = true;
+        self.breaker.record(success, self.probe);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.finished && self.probe {
+            self.breaker.record(false, true);
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        let (events, _) = broadcast::channel(64);
+        CircuitBreaker {
+            config: Arc::new(config),
+            inner: Arc::new(Mutex::new(Inner {
+                state: CircuitState::Closed,
+                outcomes: VecDeque::new(),
+                opened_at: None,
+                probes_in_flight: 0,
+                probe_successes: 0,
+                total_calls: 0,
+                successes: 0,
+                failures: 0,
+                rejected: 0,
+                state_changes: 0,
+            })),
+            events,
+        }
+    }
+
+    /// Runs `operation` if the breaker allows it and records the outcome.
+    pub async fn call<F, Fut, T, E>(&self, operation: F) -> Result<T, CallError<E>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let permit = self.acquire()?;
+        let result = operation().await;
+        permit.finish(result.is_ok());
+        result.map_err(CallError::Inner)
+    }
+
+    /// Like `call`, but a call that exceeds `limit` counts as a failure.
+    pub async fn call_with_timeout<F, Fut, T, E>(
+        &self,
+        limit: Duration,
+        operation: F,
+    ) -> Result<T, CallError<E>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let permit = self.acquire()?;
+        match timeout(limit, operation()).await {
+            Ok(result) => {
+                permit.finish(result.is_ok());
+                result.map_err(CallError::Inner)
+            }
+            Err(_) => {
+                permit.finish(false);
+                Err(CallError::Timeout)
+            }
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner, Instant::now());
+        inner.state
+    }
+
+    /// Subscribes to state-change events.
+    pub fn subscribe(&self) -> broadcast::Receiver<StateChange> {
+        self.events.subscribe()
+    }
+
+    pub fn metrics(&self) -> CircuitMetrics {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        self.refresh(&mut inner, now);
+        self.prune(&mut inner, now);
+        let window_calls = inner.outcomes.len();
+        let window_failures = inner.outcomes.iter().filter(|(_, ok)| !ok).count();
+        CircuitMetrics {
+            state: inner.state,
+            total_calls: inner.total_calls,
+            successes: inner.successes,
+            failures: inner.failures,
+            rejected: inner.rejected,
+            window_calls,
+            window_failure_rate: if window_calls == 0 {
+                0.0
+            } else {
+                window_failures as f64 / window_calls as f64
+            },
+            state_changes: inner.state_changes,
+        }
+    }
+
+    /// Forces the breaker back to closed and clears the window.
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.outcomes.clear();
+        self.transition(&mut inner, CircuitState::Closed);
+    }
+
+    /// Decides whether a call may run.
+    fn acquire<E>(&self) -> Result<Permit<'_>, CallError<E>> {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner, Instant::now());
+        let permit = |probe| Permit {
+            breaker: self,
+            probe,
+            finished: false,
+        };
+        match inner.state {
+            CircuitState::Closed => Ok(permit(false)),
+            CircuitState::HalfOpen
+                if inner.probes_in_flight + inner.probe_successes < self.config.half_open_probes =>
+            {
+                inner.probes_in_flight += 1;
+                Ok(permit(true))
+            }
+            _ => {
+                inner.rejected += 1;
+                Err(CallError::Open)
+            }
+        }
+    }
+
+    fn record(&self, success: bool, probe: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner.total_calls += 1;
+        if success {
+            inner.successes += 1;
+        } else {
+            inner.failures += 1;
+        }
+
+        if probe {
+            inner.probes_in_flight -= 1;
+            if inner.state != CircuitState::HalfOpen {
+                return;
+            }
+            if success {
+                inner.probe_successes += 1;
+                if inner.probe_successes >= self.config.half_open_probes {
+                    inner.outcomes.clear();
+                    self.transition(&mut inner, CircuitState::Closed);
+                }
+            } else {
+                self.transition(&mut inner, CircuitState::Open);
+            }
+            return;
+        }
+
+        if inner.state != CircuitState::Closed {
+            return;
+        }
+        inner.outcomes.push_back((now, success));
+        self.prune(&mut inner, now);
+        let calls = inner.outcomes.len();
+        if calls >= self.config.min_calls.max(1) {
+            let failures = inner.outcomes.iter().filter(|(_, ok)| !ok).count();
+            if failures as f64 / calls as f64 >= self.config.failure_rate_threshold {
+                self.transition(&mut inner, CircuitState::Open);
+            }
+        }
+    }
+
+    /// Moves an open breaker to half-open once the cool-down has elapsed.
+    fn refresh(&self, inner: &mut Inner, now: Instant) {
+        if inner.state == CircuitState::Open
+            && let Some(opened_at) = inner.opened_at
+            && now.duration_since(opened_at) >= self.config.cool_down
+        {
+            self.transition(inner, CircuitState::HalfOpen);
+        }
+    }
+
+    fn prune(&self, inner: &mut Inner, now: Instant) {
+        while let Some(&(at, _)) = inner.outcomes.front() {
+            if now.duration_since(at) > self.config.window {
+                inner.outcomes.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, to: CircuitState) {
+        let from = inner.state;
+        if from == to {
+            return;
+        }
+        inner.state = to;
+        inner.state_changes += 1;
+        inner.probe_successes = 0;
+        inner.opened_at = if to == CircuitState::Open {
+            Some(Instant::now())
+        } else {
+            None
+        };
+        // Nobody listening is fine.
+        let _ = self.events.send(StateChange { from, to });
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(CircuitBreakerConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::sleep;
+
+    fn config() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            window: Duration::from_secs(60),
+            min_calls: 4,
+            failure_rate_threshold: 0.5,
+            cool_down: Duration::from_millis(50),
+            half_open_probes: 2,
+        }
+    }
+
+    async fn fail(breaker: &CircuitBreaker) -> Result<i32, CallError<String>> {
+        breaker.call(|| async { Err("boom".to_string()) }).await
+    }
+
+    async fn succeed(breaker: &CircuitBreaker) -> Result<i32, CallError<String>> {
+        breaker.call(|| async { Ok(1) }).await
+    }
+
+    #[tokio::test]
+    async fn test_stays_closed_below_threshold() {
+        let breaker = CircuitBreaker::new(config());
+        succeed(&breaker).await.unwrap();
+        succeed(&breaker).await.unwrap();
+        succeed(&breaker).await.unwrap();
+        assert_eq!(fail(&breaker).await, Err(CallError::Inner("boom".to_string())));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(breaker.metrics().window_failure_rate, 0.25);
+    }
+
+    #[tokio::test]
+    async fn test_min_calls_before_opening() {
+        let breaker = CircuitBreaker::new(config());
+        for _ in 0..3 {
+            let _ = fail(&breaker).await;
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        let _ = fail(&breaker).await;
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn test_open_rejects_without_running() {
+        let breaker = CircuitBreaker::new(config());
+        for _ in 0..4 {
+            let _ = fail(&breaker).await;
+        }
+        let ran = Arc::new(Mutex::new(false));
+        let flag = Arc::clone(&ran);
+        let result: Result<(), CallError<String>> = breaker
+            .call(|| async move {
+                *flag.lock().unwrap() = true;
+                Ok(())
+            })
+            .await;
+        assert_eq!(result, Err(CallError::Open));
+        assert!(!*ran.lock().unwrap());
+        assert_eq!(breaker.metrics().rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn test_half_open_probes_close_breaker() {
+        let breaker = CircuitBreaker::new(config());
+        let mut events = breaker.subscribe();
+        for _ in 0..4 {
+            let _ = fail(&breaker).await;
+        }
+        sleep(Duration::from_millis(60)).await;
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+
+        succeed(&breaker).await.unwrap();
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        succeed(&breaker).await.unwrap();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        let mut seen = Vec::new();
+        while let Ok(change) = events.try_recv() {
+            seen.push((change.from, change.to));
+        }
+        assert_eq!(
+            seen,
+            vec![
+                (CircuitState::Closed, CircuitState::Open),
+                (CircuitState::Open, CircuitState::HalfOpen),
+                (CircuitState::HalfOpen, CircuitState::Closed),
+            ]
+        );
+        assert_eq!(breaker.metrics().state_changes, 3);
+    }
+
+    #[tokio::test]
+    async fn test_failed_probe_reopens() {
+        let breaker = CircuitBreaker::new(config());
+        for _ in 0..4 {
+            let _ = fail(&breaker).await;
+        }
+        sleep(Duration::from_millis(60)).await;
+        let _ = fail(&breaker).await;
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(succeed(&breaker).await, Err(CallError::Open));
+    }
+
+    #[tokio::test]
+    async fn test_half_open_limits_concurrent_probes() {
+        let mut cfg = config();
+        cfg.half_open_probes = 1;
+        let breaker = CircuitBreaker::new(cfg);
+        for _ in 0..4 {
+            let _ = fail(&breaker).await;
+        }
+        sleep(Duration::from_millis(60)).await;
+
+        let slow = {
+            let breaker = breaker.clone();
+            tokio::spawn(async move {
+                breaker
+                    .call(|| async {
+                        sleep(Duration::from_millis(30)).await;
+                        Ok::<_, String>(1)
+                    })
+                    .await
+            })
+        };
+        sleep(Duration::from_millis(5)).await;
+        assert_eq!(succeed(&breaker).await, Err(CallError::Open));
+        assert_eq!(slow.await.unwrap(), Ok(1));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_probe_releases_slot() {
+        let mut cfg = config();
+        cfg.half_open_probes = 1;
+        let breaker = CircuitBreaker::new(cfg);
+        for _ in 0..4 {
+            let _ = fail(&breaker).await;
+        }
+        sleep(Duration::from_millis(60)).await;
+
+        let cancelled = timeout(
+            Duration::from_millis(5),
+            breaker.call(|| async {
+                sleep(Duration::from_secs(60)).await;
+                Ok::<_, String>(1)
+            }),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        // The dropped probe counts as a failure and the breaker reopens.
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        sleep(Duration::from_millis(60)).await;
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        succeed(&breaker).await.unwrap();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_rolling_window_forgets_old_failures() {
+        let mut cfg = config();
+        cfg.window = Duration::from_millis(40);
+        let breaker = CircuitBreaker::new(cfg);
+        for _ in 0..3 {
+            let _ = fail(&breaker).await;
+        }
+        sleep(Duration::from_millis(50)).await;
+        let _ = fail(&breaker).await;
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(breaker.metrics().window_calls, 1);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_counts_as_failure() {
+        let breaker = CircuitBreaker::new(config());
+        for _ in 0..4 {
+            let result: Result<(), CallError<String>> = breaker
+                .call_with_timeout(Duration::from_millis(5), || async {
+                    sleep(Duration::from_millis(50)).await;
+                    Ok(())
+                })
+                .await;
+            assert!(matches!(result, Err(CallError::Timeout) | Err(CallError::Open)));
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(breaker.metrics().failures, 4);
+    }
+
+    #[tokio::test]
+    async fn test_composes_with_retry() {
+        let breaker = CircuitBreaker::new(config());
+        let attempts = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&attempts);
+        let result = breaker
+            .call(|| async move {
+                // A retry loop inside a single breaker call counts once.
+                for _ in 0..3 {
+                    let mut n = counter.lock().unwrap();
+                    *n += 1;
+                    if *n == 3 {
+                        return Ok(*n);
+                    }
+                }
+                Err("gave up".to_string())
+            })
+            .await;
+        assert_eq!(result, Ok(3));
+        assert_eq!(breaker.metrics().total_calls, 1);
+    }
+
+    #[tokio::test]
+    async fn test_reset() {
+        let breaker = CircuitBreaker::new(config());
+        for _ in 0..4 {
+            let _ = fail(&breaker).await;
+        }
+        breaker.reset();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(breaker.metrics().window_calls, 0);
+    }
+}
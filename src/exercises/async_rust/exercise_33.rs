@@ -0,0 +1,618 @@
+//! Exercise 33: Async TCP Key-Value Server - Real I/O with tokio::net
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Serve a line-oriented protocol over tokio::net::TcpListener
+//! - Own shared state in a single actor task fed by mpsc + oneshot
+//! - Shut the server down gracefully with CancellationToken
+//! - Write a matching client with buffered line I/O
+//!
+//! # Protocol
+//! Every request and response is one `\n`-terminated line:
+//!
+//! | Request           | Response                        |
+//! |-------------------|---------------------------------|
+//! | `GET <key>`       | `VALUE <value>` or `NIL`        |
+//! | `SET <key> <val>` | `OK` (the value may hold spaces)|
+//! | `DEL <key>`       | `DELETED` or `NIL`              |
+//! | anything else     | `ERR <reason>`                  |
+//!
+//! A value is every byte after the space that follows the key, so leading
+//! and trailing spaces survive and `SET <key> ` stores an empty value.
+//! Request lines longer than `MAX_LINE` bytes are answered with
+//! `ERR line too long`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Get(String),
+    Set(String, String),
+    Del(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Value(String),
+    Nil,
+    Ok,
+    Deleted,
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    Empty,
+    UnknownCommand(String),
+    MissingArgument(&'static str),
+    TooManyArguments,
+    BadResponse(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Empty => write!(f, "empty command"),
+            ProtocolError::UnknownCommand(c) => write!(f, "unknown command '{}'", c),
+            ProtocolError::MissingArgument(a) => write!(f, "missing {}", a),
+            ProtocolError::TooManyArguments => write!(f, "too many arguments"),
+            ProtocolError::BadResponse(r) => write!(f, "malformed response '{}'", r),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl Command {
+    /// Parses one request line (without the trailing newline).
+    pub fn parse(line: &str) -> Result<Command, ProtocolError> {
+        let line = line.trim_end_matches(['\r', '\n']).trim_start();
+        let (verb, rest) = line.split_once(' ').unwrap_or((line, ""));
+        if verb.is_empty() {
+            return Err(ProtocolError::Empty);
+        }
+        let single_key = |rest: &str| -> Result<String, ProtocolError> {
+            let mut words = rest.split_whitespace();
+            match (words.next(), words.next()) {
+                (None, _) => Err(ProtocolError::MissingArgument("key")),
+                (Some(key), None) => Ok(key.to_string()),
+                (Some(_), Some(_)) => Err(ProtocolError::TooManyArguments),
+            }
+        };
+        match verb.to_ascii_uppercase().as_str() {
+            "GET" => Ok(Command::Get(single_key(rest)?)),
+            "DEL" => Ok(Command::Del(single_key(rest)?)),
+            "SET" => {
+                let rest = rest.trim_start();
+                if rest.is_empty() {
+                    return Err(ProtocolError::MissingArgument("key"));
+                }
+                // The value is kept byte for byte, spaces included.
+                let (key, value) = rest
+                    .split_once(' ')
+                    .ok_or(ProtocolError::MissingArgument("value"))?;
+                Ok(Command::Set(key.to_string(), value.to_string()))
+            }
+            _ => Err(ProtocolError::UnknownCommand(verb.to_string())),
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        match self {
+            Command::Get(k) => format!("GET {}\n", k),
+            Command::Set(k, v) => format!("SET {} {}\n", k, v),
+            Command::Del(k) => format!("DEL {}\n", k),
+        }
+    }
+}
+
+impl Response {
+    pub fn parse(line: &str) -> Result<Response, ProtocolError> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        match line {
+            "NIL" => Ok(Response::Nil),
+            "OK" => Ok(Response::Ok),
+            "DELETED" => Ok(Response::Deleted),
+            _ => {
+                if let Some(v) = line.strip_prefix("VALUE ") {
+                    Ok(Response::Value(v.to_string()))
+                } else if let Some(e) = line.strip_prefix("ERR ") {
+                    Ok(Response::Error(e.to_string()))
+                } else {
+                    Err(ProtocolError::BadResponse(line.to_string()))
+                }
+            }
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        match self {
+            Response::Value(v) => format!("VALUE {}\n", v),
+            Response::Nil => "NIL\n".to_string(),
+            Response::Ok => "OK\n".to_string(),
+            Response::Deleted => "DELETED\n".to_string(),
+            Response::Error(e) => format!("ERR {}\n", e),
+        }
+    }
+}
+
+struct StoreRequest {
+    command: Command,
+    reply: oneshot::Sender<Response>,
+}
+
+/// Handle to the store actor. The actor owns the map; every clone sends to it.
+#[derive(Clone)]
+pub struct StoreHandle {
+    sender: mpsc::Sender<StoreRequest>,
+}
+
+impl StoreHandle {
+    /// Spawns the store actor on the current runtime.
+    pub fn spawn() -> StoreHandle {
+        let (sender, mut receiver) = mpsc::channel::<StoreRequest>(64);
+        tokio::spawn(async move {
+            let mut data: HashMap<String, String> = HashMap::new();
+            while let Some(StoreRequest { command, reply }) = receiver.recv().await {
+                let response = match command {
+                    Command::Get(k) => data.get(&k).cloned().map_or(Response::Nil, Response::Value),
+                    Command::Set(k, v) => {
+                        data.insert(k, v);
+                        Response::Ok
+                    }
+                    Command::Del(k) => data.remove(&k).map_or(Response::Nil, |_| Response::Deleted),
+                };
+                let _ = reply.send(response);
+            }
+        });
+        StoreHandle { sender }
+    }
+
+    pub async fn execute(&self, command: Command) -> Response {
+        let (reply, rx) = oneshot::channel();
+        if self.sender.send(StoreRequest { command, reply }).await.is_err() {
+            return Response::Error("store unavailable".to_string());
+        }
+        rx.await
+            .unwrap_or_else(|_| Response::Error("store unavailable".to_string()))
+    }
+}
+
+/// A bound, not yet running key-value server.
+pub struct KvServer {
+    listener: TcpListener,
+    store: StoreHandle,
+}
+
+/// Pause after a failed `accept` before trying again.
+const ACCEPT_BACKOFF: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Longest request line the server reads, newline included.
+pub const MAX_LINE: usize = 8 * 1024;
+
+impl KvServer {
+    /// Binds the listener. Use `127.0.0.1:0` to let the OS pick a port.
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<KvServer> {
+        Ok(KvServer {
+            listener: TcpListener::bind(addr).await?,
+            store: StoreHandle::spawn(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until `shutdown` is cancelled, then waits for open
+    /// connections to finish their current request and close.
+    ///
+    /// A failed `accept` (for example `EMFILE` when out of file descriptors)
+    /// only affects that connection attempt: the server pauses briefly and
+    /// keeps accepting.
+    pub async fn run(self, shutdown: CancellationToken) -> io::Result<()> {
+        let mut connections = JoinSet::new();
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                accepted = self.listener.accept() => {
+                    let stream = match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(_) => {
+                            tokio::select! {
+                                _ = shutdown.cancelled() => break,
+                                _ = tokio::time::sleep(ACCEPT_BACKOFF) => continue,
+                            }
+                        }
+                    };
+                    let store = self.store.clone();
+                    let token = shutdown.clone();
+                    connections.spawn(async move {
+                        // A broken client connection only affects that client.
+                        let _ = handle_connection(stream, store, token).await;
+                    });
+                }
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            }
+        }
+        while connections.join_next().await.is_some() {}
+        Ok(())
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    store: StoreHandle,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        let read = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            read = read_line_bounded(&mut reader, &mut buf) => read?,
+        };
+        let response = match read {
+            LineRead::Eof => break,
+            LineRead::TooLong => Response::Error("line too long".to_string()),
+            LineRead::Line => match std::str::from_utf8(&buf) {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => match Command::parse(line) {
+                    Ok(command) => store.execute(command).await,
+                    Err(e) => Response::Error(e.to_string()),
+                },
+                Err(_) => Response::Error("request is not UTF-8".to_string()),
+            },
+        };
+        writer.write_all(response.to_line().as_bytes()).await?;
+    }
+    writer.shutdown().await
+}
+
+enum LineRead {
+    Line,
+    TooLong,
+    Eof,
+}
+
+/// Reads one line into `buf`, holding at most `MAX_LINE` bytes. The rest of
+/// a longer line is skipped up to its newline so the next request is read
+/// in sync.
+async fn read_line_bounded<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> io::Result<LineRead> {
+    let n = (&mut *reader).take(MAX_LINE as u64).read_until(b'\n', buf).await?;
+    if n == 0 {
+        return Ok(LineRead::Eof);
+    }
+    if buf.ends_with(b"\n") || n < MAX_LINE {
+        return Ok(LineRead::Line);
+    }
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(LineRead::TooLong);
+        }
+        match available.iter().position(|&b| b == b'\n') {
+            Some(i) => {
+                reader.consume(i + 1);
+                return Ok(LineRead::TooLong);
+            }
+            None => {
+                let len = available.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    Protocol(ProtocolError),
+    Server(String),
+    /// A key or value that cannot be sent on the line protocol.
+    InvalidInput(String),
+    Disconnected,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "I/O error: {}", e),
+            ClientError::Protocol(e) => write!(f, "protocol error: {}", e),
+            ClientError::Server(e) => write!(f, "server error: {}", e),
+            ClientError::InvalidInput(e) => write!(f, "invalid input: {}", e),
+            ClientError::Disconnected => write!(f, "server closed the connection"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl From<ProtocolError> for ClientError {
+    fn from(e: ProtocolError) -> Self {
+        ClientError::Protocol(e)
+    }
+}
+
+/// A client speaking the same line protocol as `KvServer`.
+pub struct KvClient {
+    reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
+    writer: tokio::net::tcp::OwnedWriteHalf,
+}
+
+impl KvClient {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<KvClient, ClientError> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        Ok(KvClient {
+            reader: BufReader::new(reader),
+            writer,
+        })
+    }
+
+    pub async fn get(&mut self, key: &str) -> Result<Option<String>, ClientError> {
+        match self.request(&Command::Get(key.to_string())).await? {
+            Response::Value(v) => Ok(Some(v)),
+            Response::Nil => Ok(None),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub async fn set(&mut self, key: &str, value: &str) -> Result<(), ClientError> {
+        match self
+            .request(&Command::Set(key.to_string(), value.to_string()))
+            .await?
+        {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Deletes `key`, returning whether it existed.
+    pub async fn del(&mut self, key: &str) -> Result<bool, ClientError> {
+        match self.request(&Command::Del(key.to_string())).await? {
+            Response::Deleted => Ok(true),
+            Response::Nil => Ok(false),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Sends `command`, rejecting keys and values that would break framing.
+    pub async fn request(&mut self, command: &Command) -> Result<Response, ClientError> {
+        match command {
+            Command::Get(key) | Command::Del(key) => check_key(key)?,
+            Command::Set(key, value) => {
+                check_key(key)?;
+                check_value(value)?;
+            }
+        }
+        self.writer.write_all(command.to_line().as_bytes()).await?;
+        self.read_reply().await
+    }
+
+    /// Sends an arbitrary line and parses the reply.
+    pub async fn send_raw(&mut self, line: &str) -> Result<Response, ClientError> {
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.write_all(b"\n").await?;
+        self.read_reply().await
+    }
+
+    async fn read_reply(&mut self) -> Result<Response, ClientError> {
+        let mut reply = String::new();
+        if self.reader.read_line(&mut reply).await? == 0 {
+            return Err(ClientError::Disconnected);
+        }
+        Ok(Response::parse(&reply)?)
+    }
+}
+
+/// Keys are single tokens: non-empty and free of whitespace.
+fn check_key(key: &str) -> Result<(), ClientError> {
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return Err(ClientError::InvalidInput(format!("key {:?}", key)));
+    }
+    Ok(())
+}
+
+/// Values may hold spaces but not line breaks.
+fn check_value(value: &str) -> Result<(), ClientError> {
+    if value.contains(['\n', '\r']) {
+        return Err(ClientError::InvalidInput(format!("value {:?}", value)));
+    }
+    Ok(())
+}
+
+fn unexpected(response: Response) -> ClientError {
+    match response {
+        Response::Error(e) => ClientError::Server(e),
+        other => ClientError::Protocol(ProtocolError::BadResponse(
+            other.to_line().trim_end().to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::task::JoinHandle;
+    use tokio::time::{timeout, Duration};
+
+    async fn start() -> (SocketAddr, CancellationToken, JoinHandle<io::Result<()>>) {
+        let server = KvServer::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let token = CancellationToken::new();
+        let handle = tokio::spawn(server.run(token.clone()));
+        (addr, token, handle)
+    }
+
+    #[test]
+    fn test_command_parsing() {
+        assert_eq!(Command::parse("GET a"), Ok(Command::Get("a".to_string())));
+        assert_eq!(Command::parse("get a"), Ok(Command::Get("a".to_string())));
+        assert_eq!(
+            Command::parse("SET a hello world"),
+            Ok(Command::Set("a".to_string(), "hello world".to_string()))
+        );
+        assert_eq!(Command::parse("DEL a"), Ok(Command::Del("a".to_string())));
+        assert_eq!(Command::parse(""), Err(ProtocolError::Empty));
+        assert_eq!(Command::parse("GET"), Err(ProtocolError::MissingArgument("key")));
+        assert_eq!(Command::parse("SET a"), Err(ProtocolError::MissingArgument("value")));
+        assert_eq!(Command::parse("SET"), Err(ProtocolError::MissingArgument("key")));
+        assert_eq!(
+            Command::parse("SET a  x \r"),
+            Ok(Command::Set("a".to_string(), " x ".to_string()))
+        );
+        assert_eq!(Command::parse("GET a b"), Err(ProtocolError::TooManyArguments));
+        assert_eq!(
+            Command::parse("PING"),
+            Err(ProtocolError::UnknownCommand("PING".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        for r in [
+            Response::Value("x y".to_string()),
+            Response::Nil,
+            Response::Ok,
+            Response::Deleted,
+            Response::Error("bad".to_string()),
+        ] {
+            assert_eq!(Response::parse(&r.to_line()), Ok(r));
+        }
+        assert!(Response::parse("HUH").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_set_del_over_tcp() {
+        let (addr, token, handle) = start().await;
+        let mut client = KvClient::connect(addr).await.unwrap();
+
+        assert_eq!(client.get("k").await.unwrap(), None);
+        client.set("k", "some value").await.unwrap();
+        assert_eq!(client.get("k").await.unwrap(), Some("some value".to_string()));
+        assert!(client.del("k").await.unwrap());
+        assert!(!client.del("k").await.unwrap());
+        assert_eq!(client.get("k").await.unwrap(), None);
+
+        token.cancel();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_errors_keep_connection_open() {
+        let (addr, token, handle) = start().await;
+        let mut client = KvClient::connect(addr).await.unwrap();
+        assert_eq!(
+            client.send_raw("FLY away").await.unwrap(),
+            Response::Error("unknown command 'FLY'".to_string())
+        );
+        client.set("a", "1").await.unwrap();
+        assert_eq!(client.get("a").await.unwrap(), Some("1".to_string()));
+        token.cancel();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_rejects_framing_characters() {
+        let (addr, token, handle) = start().await;
+        let mut client = KvClient::connect(addr).await.unwrap();
+        let invalid = |r: Result<(), ClientError>| matches!(r, Err(ClientError::InvalidInput(_)));
+        assert!(invalid(client.set("a", "1\nDEL b").await));
+        assert!(invalid(client.set("a b", "1").await));
+        assert!(invalid(client.get("a\nDEL b").await.map(|_| ())));
+        assert!(invalid(client.del("").await.map(|_| ())));
+
+        // Nothing was sent, so the connection is still in sync.
+        client.set("b", "two words").await.unwrap();
+        assert_eq!(client.get("b").await.unwrap(), Some("two words".to_string()));
+        assert_eq!(client.get("a").await.unwrap(), None);
+        token.cancel();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_values_round_trip_unchanged() {
+        let (addr, token, handle) = start().await;
+        let mut client = KvClient::connect(addr).await.unwrap();
+        for value in ["  padded ", "", " ", "tab\tinside", "ünïcode"] {
+            client.set("k", value).await.unwrap();
+            assert_eq!(client.get("k").await.unwrap().as_deref(), Some(value));
+        }
+        token.cancel();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_overlong_line_is_rejected() {
+        let (addr, token, handle) = start().await;
+        let mut client = KvClient::connect(addr).await.unwrap();
+        let line = format!("SET k {}", "x".repeat(3 * MAX_LINE));
+        assert_eq!(
+            client.send_raw(&line).await.unwrap(),
+            Response::Error("line too long".to_string())
+        );
+        // The rest of the long line was skipped, so the next request works.
+        client.set("k", "short").await.unwrap();
+        assert_eq!(client.get("k").await.unwrap(), Some("short".to_string()));
+        token.cancel();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_clients_share_state() {
+        let (addr, token, handle) = start().await;
+        let mut writers = Vec::new();
+        for i in 0..8 {
+            writers.push(tokio::spawn(async move {
+                let mut client = KvClient::connect(addr).await.unwrap();
+                client.set(&format!("key{}", i), &i.to_string()).await.unwrap();
+            }));
+        }
+        for w in writers {
+            w.await.unwrap();
+        }
+        let mut reader = KvClient::connect(addr).await.unwrap();
+        for i in 0..8 {
+            assert_eq!(
+                reader.get(&format!("key{}", i)).await.unwrap(),
+                Some(i.to_string())
+            );
+        }
+        token.cancel();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown_closes_idle_clients() {
+        let (addr, token, handle) = start().await;
+        let mut client = KvClient::connect(addr).await.unwrap();
+        client.set("a", "1").await.unwrap();
+
+        token.cancel();
+        timeout(Duration::from_secs(1), handle)
+            .await
+            .expect("server did not stop")
+            .unwrap()
+            .unwrap();
+        assert!(client.get("a").await.is_err());
+        assert!(KvClient::connect(addr).await.is_err());
+    }
+}
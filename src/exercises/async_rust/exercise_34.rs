@@ -0,0 +1,533 @@
+//! Exercise 34: Actor Framework - Mailboxes built from mpsc and oneshot
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Own actor state inside a single task fed by a typed mailbox
+//! - Implement fire-and-forget `tell` and request/response `ask`
+//! - Run `started`/`stopped` lifecycle hooks around the message loop
+//! - Look actors up by name in a type-checked registry
+//!
+//! Requests that need an answer carry a `Reply<R>`; `ActorRef::ask` creates the
+//! oneshot channel, builds the message with it and waits for the answer.
+//!
+//! An actor only holds a `WeakActorRef` to itself, so once every `ActorRef`
+//! is dropped its mailbox closes and it stops after the queued messages.
+
+use async_trait::async_trait;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout, Duration};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ActorError {
+    /// The actor has stopped and no longer accepts messages.
+    MailboxClosed,
+    /// The mailbox is full (only from `try_tell`).
+    MailboxFull,
+    /// No reply arrived within the `ask` timeout.
+    Timeout,
+    /// The actor dropped the reply without answering.
+    NoReply,
+    /// Another live actor is registered under this name.
+    NameTaken(String),
+}
+
+impl fmt::Display for ActorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ActorError::MailboxClosed => write!(f, "actor mailbox is closed"),
+            ActorError::MailboxFull => write!(f, "actor mailbox is full"),
+            ActorError::Timeout => write!(f, "timed out waiting for reply"),
+            ActorError::NoReply => write!(f, "actor dropped the reply"),
+            ActorError::NameTaken(name) => write!(f, "actor name '{}' is already taken", name),
+        }
+    }
+}
+
+impl std::error::Error for ActorError {}
+
+#[async_trait]
+pub trait Actor: Send + Sized + 'static {
+    type Message: Send + 'static;
+
+    /// Runs once before the first message is handled.
+    async fn started(&mut self, _ctx: &mut Context<Self>) {}
+
+    async fn handle(&mut self, message: Self::Message, ctx: &mut Context<Self>);
+
+    /// Runs once after the mailbox loop ends.
+    async fn stopped(&mut self) {}
+}
+
+/// The answering half of an `ask`.
+pub struct Reply<R> {
+    sender: oneshot::Sender<R>,
+}
+
+impl<R> Reply<R> {
+    /// Sends the answer. Returns false if the asker gave up waiting.
+    pub fn send(self, value: R) -> bool {
+        self.sender.send(value).is_ok()
+    }
+}
+
+impl<R> fmt::Debug for Reply<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Reply")
+    }
+}
+
+enum Envelope<M> {
+    Message(M),
+    Stop,
+}
+
+/// A cloneable address for sending messages to an actor.
+pub struct ActorRef<M> {
+    name: Arc<str>,
+    sender: mpsc::Sender<Envelope<M>>,
+    terminated: CancellationToken,
+}
+
+impl<M> Clone for ActorRef<M> {
+    fn clone(&self) -> Self {
+        ActorRef {
+            name: Arc::clone(&self.name),
+            sender: self.sender.clone(),
+            terminated: self.terminated.clone(),
+        }
+    }
+}
+
+impl<M: Send + 'static> ActorRef<M> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sends a message, waiting for mailbox space.
+    pub async fn tell(&self, message: M) -> Result<(), ActorError> {
+        self.sender
+            .send(Envelope::Message(message))
+            .await
+            .map_err(|_| ActorError::MailboxClosed)
+    }
+
+    /// Sends a message without waiting.
+    pub fn try_tell(&self, message: M) -> Result<(), ActorError> {
+        self.sender
+            .try_send(Envelope::Message(message))
+            .map_err(|e| match e {
+                mpsc::error::TrySendError::Full(_) => ActorError::MailboxFull,
+                mpsc::error::TrySendError::Closed(_) => ActorError::MailboxClosed,
+            })
+    }
+
+    /// Sends the message built by `make` and waits up to `limit` for its reply.
+    pub async fn ask<R, F>(&self, make: F, limit: Duration) -> Result<R, ActorError>
+    where
+        F: FnOnce(Reply<R>) -> M,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let send_and_wait = async {
+            self.tell(make(Reply { sender })).await?;
+            receiver.await.map_err(|_| ActorError::NoReply)
+        };
+        timeout(limit, send_and_wait)
+            .await
+            .unwrap_or(Err(ActorError::Timeout))
+    }
+
+    /// Asks the actor to stop after the messages already in its mailbox.
+    pub async fn stop(&self) {
+        let _ = self.sender.send(Envelope::Stop).await;
+    }
+
+    pub fn is_alive(&self) -> bool {
+        !self.terminated.is_cancelled()
+    }
+
+    /// Waits until the actor's `stopped` hook has run.
+    pub async fn wait_stopped(&self) {
+        self.terminated.cancelled().await
+    }
+
+    /// An address that does not keep the actor's mailbox open.
+    pub fn downgrade(&self) -> WeakActorRef<M> {
+        WeakActorRef {
+            name: Arc::clone(&self.name),
+            sender: self.sender.downgrade(),
+            terminated: self.terminated.clone(),
+        }
+    }
+}
+
+/// An `ActorRef` that does not count towards keeping the actor running.
+pub struct WeakActorRef<M> {
+    name: Arc<str>,
+    sender: mpsc::WeakSender<Envelope<M>>,
+    terminated: CancellationToken,
+}
+
+impl<M> Clone for WeakActorRef<M> {
+    fn clone(&self) -> Self {
+        WeakActorRef {
+            name: Arc::clone(&self.name),
+            sender: self.sender.clone(),
+            terminated: self.terminated.clone(),
+        }
+    }
+}
+
+impl<M> WeakActorRef<M> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// A full address, or `None` once no `ActorRef` is left.
+    pub fn upgrade(&self) -> Option<ActorRef<M>> {
+        Some(ActorRef {
+            name: Arc::clone(&self.name),
+            sender: self.sender.upgrade()?,
+            terminated: self.terminated.clone(),
+        })
+    }
+
+    /// Waits until the actor's `stopped` hook has run.
+    pub async fn wait_stopped(&self) {
+        self.terminated.cancelled().await
+    }
+}
+
+/// Per-actor context passed to every hook and handler.
+pub struct Context<A: Actor> {
+    myself: WeakActorRef<A::Message>,
+    stopping: bool,
+}
+
+impl<A: Actor> Context<A> {
+    pub fn name(&self) -> &str {
+        self.myself.name()
+    }
+
+    /// An address for this actor, e.g. to hand to other actors. `None` once
+    /// every outside `ActorRef` is gone and the actor is draining its mailbox.
+    pub fn myself(&self) -> Option<ActorRef<A::Message>> {
+        self.myself.upgrade()
+    }
+
+    /// Stops the actor once the current message has been handled.
+    pub fn stop(&mut self) {
+        self.stopping = true;
+    }
+}
+
+/// Spawns `actor` on the current runtime with a bounded mailbox.
+pub fn spawn<A: Actor>(name: &str, actor: A, mailbox: usize) -> ActorRef<A::Message> {
+    spawn_with_exit(name, actor, mailbox, || {})
+}
+
+fn spawn_with_exit<A, F>(name: &str, mut actor: A, mailbox: usize, on_exit: F) -> ActorRef<A::Message>
+where
+    A: Actor,
+    F: FnOnce() + Send + 'static,
+{
+    let (sender, mut receiver) = mpsc::channel(mailbox.max(1));
+    let myself = ActorRef {
+        name: Arc::from(name),
+        sender,
+        terminated: CancellationToken::new(),
+    };
+    let terminated = myself.terminated.clone();
+    let mut ctx = Context {
+        myself: myself.downgrade(),
+        stopping: false,
+    };
+    tokio::spawn(async move {
+        // Cancelled on drop, so a panicking handler still marks the actor dead.
+        let _guard = terminated.drop_guard();
+        actor.started(&mut ctx).await;
+        while !ctx.stopping {
+            match receiver.recv().await {
+                Some(Envelope::Message(message)) => actor.handle(message, &mut ctx).await,
+                Some(Envelope::Stop) | None => break,
+            }
+        }
+        receiver.close();
+        actor.stopped().await;
+        on_exit();
+    });
+    myself
+}
+
+struct Entry {
+    actor_ref: Box<dyn Any + Send>,
+    terminated: CancellationToken,
+}
+
+/// Name-based lookup of running actors. Cloning shares the same registry.
+#[derive(Clone, Default)]
+pub struct Registry {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spawns an actor and registers it under `name`; the entry is removed
+    /// when the actor stops. The entry holds an `ActorRef`, so a registered
+    /// actor runs until it is stopped explicitly.
+    pub fn spawn<A: Actor>(
+        &self,
+        name: &str,
+        actor: A,
+        mailbox: usize,
+    ) -> Result<ActorRef<A::Message>, ActorError> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.get(name).is_some_and(|e| !e.terminated.is_cancelled()) {
+            return Err(ActorError::NameTaken(name.to_string()));
+        }
+        let registry = self.clone();
+        let key = name.to_string();
+        let actor_ref = spawn_with_exit(name, actor, mailbox, move || {
+            registry.entries.lock().unwrap().remove(&key);
+        });
+        entries.insert(
+            name.to_string(),
+            Entry {
+                actor_ref: Box::new(actor_ref.clone()),
+                terminated: actor_ref.terminated.clone(),
+            },
+        );
+        Ok(actor_ref)
+    }
+
+    /// Returns the actor registered as `name` if it accepts messages of type `M`.
+    pub fn lookup<M: Send + 'static>(&self, name: &str) -> Option<ActorRef<M>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(name)?
+            .actor_ref
+            .downcast_ref::<ActorRef<M>>()
+            .filter(|r| r.is_alive())
+            .cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, e)| !e.terminated.is_cancelled())
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    enum CounterMsg {
+        Add(i64),
+        Get(Reply<i64>),
+        Ignore(Reply<i64>),
+        Slow(Reply<i64>),
+        /// Signals `started`, then holds the actor until `release` fires.
+        Block {
+            started: oneshot::Sender<()>,
+            release: oneshot::Receiver<()>,
+        },
+        StopNow,
+    }
+
+    struct Counter {
+        total: i64,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Actor for Counter {
+        type Message = CounterMsg;
+
+        async fn started(&mut self, ctx: &mut Context<Self>) {
+            self.log.lock().unwrap().push(format!("started {}", ctx.name()));
+        }
+
+        async fn handle(&mut self, message: CounterMsg, ctx: &mut Context<Self>) {
+            match message {
+                CounterMsg::Add(n) => self.total += n,
+                CounterMsg::Get(reply) => {
+                    reply.send(self.total);
+                }
+                CounterMsg::Ignore(reply) => drop(reply),
+                CounterMsg::Slow(reply) => {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    reply.send(self.total);
+                }
+                CounterMsg::Block { started, release } => {
+                    let _ = started.send(());
+                    let _ = release.await;
+                }
+                CounterMsg::StopNow => ctx.stop(),
+            }
+        }
+
+        async fn stopped(&mut self) {
+            self.log.lock().unwrap().push(format!("stopped at {}", self.total));
+        }
+    }
+
+    fn counter() -> (Counter, Arc<Mutex<Vec<String>>>) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        (
+            Counter {
+                total: 0,
+                log: Arc::clone(&log),
+            },
+            log,
+        )
+    }
+
+    const LIMIT: Duration = Duration::from_secs(1);
+
+    #[tokio::test]
+    async fn test_tell_and_ask() {
+        let (actor, _) = counter();
+        let counter = spawn("counter", actor, 8);
+        for i in 1..=10 {
+            counter.tell(CounterMsg::Add(i)).await.unwrap();
+        }
+        assert_eq!(counter.ask(CounterMsg::Get, LIMIT).await, Ok(55));
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_hooks() {
+        let (actor, log) = counter();
+        let counter = spawn("c1", actor, 8);
+        counter.tell(CounterMsg::Add(3)).await.unwrap();
+        counter.stop().await;
+        counter.wait_stopped().await;
+        assert!(!counter.is_alive());
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["started c1".to_string(), "stopped at 3".to_string()]
+        );
+        assert_eq!(counter.tell(CounterMsg::Add(1)).await, Err(ActorError::MailboxClosed));
+    }
+
+    #[tokio::test]
+    async fn test_ctx_stop_from_handler() {
+        let (actor, _) = counter();
+        let counter = spawn("c", actor, 8);
+        counter.tell(CounterMsg::StopNow).await.unwrap();
+        counter.wait_stopped().await;
+        assert_eq!(counter.ask(CounterMsg::Get, LIMIT).await, Err(ActorError::MailboxClosed));
+    }
+
+    #[tokio::test]
+    async fn test_actor_stops_when_all_refs_dropped() {
+        let (actor, log) = counter();
+        let counter = spawn("c", actor, 8);
+        let copy = counter.clone();
+        counter.tell(CounterMsg::Add(2)).await.unwrap();
+        let weak = counter.downgrade();
+        drop(counter);
+        assert!(weak.upgrade().is_some());
+        drop(copy);
+        timeout(LIMIT, weak.wait_stopped()).await.expect("actor did not stop");
+        assert!(weak.upgrade().is_none());
+        assert_eq!(log.lock().unwrap().last().unwrap(), "stopped at 2");
+    }
+
+    #[tokio::test]
+    async fn test_ask_timeout_and_no_reply() {
+        let (actor, _) = counter();
+        let counter = spawn("c", actor, 8);
+        assert_eq!(
+            counter.ask(CounterMsg::Slow, Duration::from_millis(10)).await,
+            Err(ActorError::Timeout)
+        );
+        assert_eq!(counter.ask(CounterMsg::Ignore, LIMIT).await, Err(ActorError::NoReply));
+    }
+
+    #[tokio::test]
+    async fn test_try_tell_full_mailbox() {
+        let (actor, _) = counter();
+        let counter = spawn("c", actor, 1);
+        let (started, started_rx) = oneshot::channel();
+        let (release_tx, release) = oneshot::channel();
+        counter.try_tell(CounterMsg::Block { started, release }).unwrap();
+        // Once the actor is busy in the handler the mailbox holds one message.
+        started_rx.await.unwrap();
+        counter.try_tell(CounterMsg::Add(1)).unwrap();
+        assert_eq!(counter.try_tell(CounterMsg::Add(1)), Err(ActorError::MailboxFull));
+        release_tx.send(()).unwrap();
+        assert_eq!(counter.ask(CounterMsg::Get, LIMIT).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn test_registry_lookup() {
+        let registry = Registry::new();
+        let (actor, _) = counter();
+        let spawned = registry.spawn("counter", actor, 8).unwrap();
+        spawned.tell(CounterMsg::Add(7)).await.unwrap();
+
+        let found = registry.lookup::<CounterMsg>("counter").unwrap();
+        assert_eq!(found.ask(CounterMsg::Get, LIMIT).await, Ok(7));
+        assert!(registry.lookup::<String>("counter").is_none());
+        assert!(registry.lookup::<CounterMsg>("missing").is_none());
+
+        let (other, _) = counter();
+        assert_eq!(
+            registry.spawn("counter", other, 8).err(),
+            Some(ActorError::NameTaken("counter".to_string()))
+        );
+        assert_eq!(registry.names(), vec!["counter".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_registry_forgets_stopped_actors() {
+        let registry = Registry::new();
+        let (actor, _) = counter();
+        let actor_ref = registry.spawn("counter", actor, 8).unwrap();
+        actor_ref.stop().await;
+        actor_ref.wait_stopped().await;
+        assert!(registry.lookup::<CounterMsg>("counter").is_none());
+        assert!(registry.names().is_empty());
+
+        let (again, _) = counter();
+        assert!(registry.spawn("counter", again, 8).is_ok());
+    }
+
+    struct Panicker;
+
+    #[async_trait]
+    impl Actor for Panicker {
+        type Message = ();
+
+        async fn handle(&mut self, _message: (), _ctx: &mut Context<Self>) {
+            panic!("handler failure");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_panicking_actor_is_marked_dead() {
+        let registry = Registry::new();
+        let actor = registry.spawn("p", Panicker, 4).unwrap();
+        actor.tell(()).await.unwrap();
+        tokio::time::timeout(LIMIT, actor.wait_stopped()).await.unwrap();
+        assert!(!actor.is_alive());
+        assert!(registry.lookup::<()>("p").is_none());
+        assert!(registry.spawn("p", Panicker, 4).is_ok());
+    }
+}
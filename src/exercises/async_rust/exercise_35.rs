@@ -0,0 +1,461 @@
+//! Exercise 35: Async Traversal - Recursion without recursion
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Walk trees and graphs breadth-first and depth-first with an explicit work list
+//! - Bound how many node expansions run concurrently
+//! - Share memoised results between concurrent computations
+//! - Stop a long walk early with CancellationToken
+//!
+//! Exercise 26 recurses through `Pin<Box<dyn Future>>`, which allocates per
+//! call and grows the poll stack with the depth of the tree. The `Walker` here
+//! keeps pending nodes in a queue or stack instead, so a chain of a million
+//! nodes is as safe to walk as a balanced tree.
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkOrder {
+    BreadthFirst,
+    DepthFirst,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraversalError {
+    Cancelled,
+    /// A node was found deeper than the configured limit.
+    DepthExceeded { limit: usize },
+}
+
+impl fmt::Display for TraversalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraversalError::Cancelled => write!(f, "traversal cancelled"),
+            TraversalError::DepthExceeded { limit } => {
+                write!(f, "traversal exceeded maximum depth {}", limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TraversalError {}
+
+/// Configuration for an async walk.
+///
+/// `expand` turns a node into its output and its children, so nodes never
+/// need to be cloned for trees. Up to `max_parallel` expansions run at once and
+/// a new one starts as soon as any finishes. Outputs are returned in the order
+/// nodes were started, which is exact breadth- or depth-first order when the
+/// parallelism is 1.
+#[derive(Debug, Clone)]
+pub struct Walker {
+    order: WalkOrder,
+    max_parallel: usize,
+    max_depth: Option<usize>,
+    cancel: CancellationToken,
+}
+
+impl Walker {
+    pub fn new(order: WalkOrder) -> Self {
+        Walker {
+            order,
+            max_parallel: 1,
+            max_depth: None,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Maximum number of `expand` futures in flight at once.
+    pub fn with_parallelism(mut self, max_parallel: usize) -> Self {
+        self.max_parallel = max_parallel.max(1);
+        self
+    }
+
+    /// Fails with `DepthExceeded` if a node deeper than `max_depth` is reached.
+    /// The root is at depth 0.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Walks a tree. Every node is expanded exactly once.
+    pub async fn walk_tree<N, R, F, Fut>(&self, root: N, expand: F) -> Result<Vec<R>, TraversalError>
+    where
+        F: Fn(N) -> Fut,
+        Fut: Future<Output = (R, Vec<N>)>,
+    {
+        self.walk(root, expand, |_| true).await
+    }
+
+    /// Walks a graph, skipping nodes that were already reached.
+    pub async fn walk_graph<N, R, F, Fut>(&self, root: N, expand: F) -> Result<Vec<R>, TraversalError>
+    where
+        N: Clone + Eq + Hash,
+        F: Fn(N) -> Fut,
+        Fut: Future<Output = (R, Vec<N>)>,
+    {
+        let mut seen = HashSet::new();
+        self.walk(root, expand, move |n: &N| seen.insert(n.clone())).await
+    }
+
+    async fn walk<N, R, F, Fut, S>(&self, root: N, expand: F, mut first_visit: S) -> Result<Vec<R>, TraversalError>
+    where
+        F: Fn(N) -> Fut,
+        Fut: Future<Output = (R, Vec<N>)>,
+        S: FnMut(&N) -> bool,
+    {
+        // Outputs are slotted by the order nodes were started, since
+        // expansions may finish in any order.
+        let mut output: Vec<Option<R>> = Vec::new();
+        let mut pending: VecDeque<(N, usize)> = VecDeque::new();
+        let mut in_flight = FuturesUnordered::new();
+        first_visit(&root);
+        pending.push_back((root, 0));
+
+        loop {
+            if self.cancel.is_cancelled() {
+                return Err(TraversalError::Cancelled);
+            }
+            // Keep every slot busy. Breadth-first takes from the front of the
+            // queue, depth-first from the back, which makes `pending` a stack.
+            while in_flight.len() < self.max_parallel {
+                let next = match self.order {
+                    WalkOrder::BreadthFirst => pending.pop_front(),
+                    WalkOrder::DepthFirst => pending.pop_back(),
+                };
+                let Some((node, depth)) = next else { break };
+                let seq = output.len();
+                output.push(None);
+                let expanding = expand(node);
+                in_flight.push(async move { (seq, depth, expanding.await) });
+            }
+            let finished = tokio::select! {
+                _ = self.cancel.cancelled() => return Err(TraversalError::Cancelled),
+                finished = in_flight.next() => finished,
+            };
+            let Some((seq, depth, (result, children))) = finished else { break };
+            output[seq] = Some(result);
+
+            // Only children that will actually be walked count towards the
+            // limit, so a back-edge at the deepest level is not an error.
+            let child_depth = depth + 1;
+            let fresh: Vec<(N, usize)> = children
+                .into_iter()
+                .filter(|c| first_visit(c))
+                .map(|c| (c, child_depth))
+                .collect();
+            if !fresh.is_empty()
+                && let Some(limit) = self.max_depth
+                && child_depth > limit
+            {
+                return Err(TraversalError::DepthExceeded { limit });
+            }
+            match self.order {
+                WalkOrder::BreadthFirst => pending.extend(fresh),
+                // Push so that the first child is on top.
+                WalkOrder::DepthFirst => pending.extend(fresh.into_iter().rev()),
+            }
+        }
+        Ok(output.into_iter().map(|r| r.expect("every started node finished")).collect())
+    }
+}
+
+/// An async memo table. Concurrent requests for the same key share one
+/// computation; other keys are computed independently.
+pub struct AsyncMemo<K, V> {
+    cells: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+    computations: AtomicUsize,
+}
+
+impl<K: Eq + Hash, V: Clone> AsyncMemo<K, V> {
+    pub fn new() -> Self {
+        AsyncMemo {
+            cells: Mutex::new(HashMap::new()),
+            computations: AtomicUsize::new(0),
+        }
+    }
+
+    pub async fn get_or_compute<F, Fut>(&self, key: K, compute: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let cell = Arc::clone(self.cells.lock().unwrap().entry(key).or_default());
+        cell.get_or_init(|| {
+            self.computations.fetch_add(1, Ordering::Relaxed);
+            compute()
+        })
+        .await
+        .clone()
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.cells.lock().unwrap().get(key)?.get().cloned()
+    }
+
+    /// How many times a value was actually computed.
+    pub fn computations(&self) -> usize {
+        self.computations.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|c| c.initialized())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Eq + Hash, V: Clone> Default for AsyncMemo<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fibonacci where both recursive calls run concurrently and share `memo`,
+/// so each `fib(k)` is computed once. `None` if the result does not fit in a
+/// `u64`, which is the case from `n = 94` on.
+pub fn memo_fibonacci(
+    n: u64,
+    memo: Arc<AsyncMemo<u64, Option<u64>>>,
+) -> Pin<Box<dyn Future<Output = Option<u64>> + Send>> {
+    Box::pin(async move {
+        if n < 2 {
+            return Some(n);
+        }
+        let inner = Arc::clone(&memo);
+        memo.get_or_compute(n, move || async move {
+            let (a, b) = tokio::join!(
+                memo_fibonacci(n - 1, Arc::clone(&inner)),
+                memo_fibonacci(n - 2, Arc::clone(&inner))
+            );
+            a?.checked_add(b?)
+        })
+        .await
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exercises::async_rust::exercise_26::TreeNode;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::time::{sleep, Duration};
+
+    fn leaf(value: i32) -> TreeNode {
+        TreeNode { value, children: vec![] }
+    }
+
+    fn sample_tree() -> TreeNode {
+        TreeNode {
+            value: 1,
+            children: vec![
+                TreeNode { value: 2, children: vec![leaf(4), leaf(5)] },
+                TreeNode { value: 3, children: vec![leaf(6)] },
+            ],
+        }
+    }
+
+    async fn expand_tree(node: TreeNode) -> (i32, Vec<TreeNode>) {
+        (node.value, node.children)
+    }
+
+    #[tokio::test]
+    async fn test_breadth_first_tree() {
+        let walker = Walker::new(WalkOrder::BreadthFirst);
+        let values = walker.walk_tree(sample_tree(), expand_tree).await.unwrap();
+        assert_eq!(values, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_depth_first_tree() {
+        let walker = Walker::new(WalkOrder::DepthFirst);
+        let values = walker.walk_tree(sample_tree(), expand_tree).await.unwrap();
+        assert_eq!(values, vec![1, 2, 4, 5, 3, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_parallel_walk_visits_every_node_once() {
+        for order in [WalkOrder::BreadthFirst, WalkOrder::DepthFirst] {
+            let walker = Walker::new(order).with_parallelism(4);
+            let mut values = walker.walk_tree(sample_tree(), expand_tree).await.unwrap();
+            values.sort();
+            assert_eq!(values, vec![1, 2, 3, 4, 5, 6]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parallelism_is_bounded() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let walker = Walker::new(WalkOrder::BreadthFirst).with_parallelism(3);
+        let values = walker
+            .walk_tree(0u32, |n| {
+                let in_flight = Arc::clone(&in_flight);
+                let peak = Arc::clone(&peak);
+                async move {
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    sleep(Duration::from_millis(2)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    let children = if n < 40 { vec![2 * n + 1, 2 * n + 2] } else { vec![] };
+                    (n, children)
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(values.len(), 81);
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+        assert!(peak.load(Ordering::SeqCst) > 1);
+    }
+
+    #[tokio::test]
+    async fn test_slow_node_does_not_stall_other_slots() {
+        // 0 -> 1 (slow), 2; 2 -> 3 -> ... -> 12, all fast.
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        let walker = Walker::new(WalkOrder::BreadthFirst).with_parallelism(2);
+        let values = walker
+            .walk_tree(0u32, |n| {
+                let finished = Arc::clone(&finished);
+                async move {
+                    sleep(Duration::from_millis(if n == 1 { 200 } else { 1 })).await;
+                    finished.lock().unwrap().push(n);
+                    let children = match n {
+                        0 => vec![1, 2],
+                        1 | 12 => vec![],
+                        n => vec![n + 1],
+                    };
+                    (n, children)
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(values, (0..=12).collect::<Vec<_>>());
+        assert_eq!(finished.lock().unwrap().last(), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_graph_walk_skips_revisits() {
+        // 0 -> 1, 2; 1 -> 2, 3; 2 -> 0, 3; 3 -> 1
+        let edges: HashMap<u32, Vec<u32>> = [(0, vec![1, 2]), (1, vec![2, 3]), (2, vec![0, 3]), (3, vec![1])]
+            .into_iter()
+            .collect();
+        let walker = Walker::new(WalkOrder::BreadthFirst).with_parallelism(2);
+        let visited = walker
+            .walk_graph(0u32, |n| {
+                let next = edges[&n].clone();
+                async move { (n, next) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(visited, vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_back_edge_at_depth_limit_is_not_an_error() {
+        // 0 -> 1 -> 0: the back-edge from the deepest node leads nowhere new.
+        let walker = Walker::new(WalkOrder::DepthFirst).with_max_depth(1);
+        let visited = walker
+            .walk_graph(0u32, |n| async move { (n, vec![1 - n]) })
+            .await
+            .unwrap();
+        assert_eq!(visited, vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_deep_chain_does_not_overflow() {
+        let walker = Walker::new(WalkOrder::DepthFirst);
+        let values = walker
+            .walk_tree(0u32, |n| async move {
+                (n, if n < 200_000 { vec![n + 1] } else { vec![] })
+            })
+            .await
+            .unwrap();
+        assert_eq!(values.len(), 200_001);
+        assert_eq!(values.last(), Some(&200_000));
+    }
+
+    #[tokio::test]
+    async fn test_max_depth() {
+        let walker = Walker::new(WalkOrder::DepthFirst).with_max_depth(1);
+        assert_eq!(
+            walker.walk_tree(sample_tree(), expand_tree).await,
+            Err(TraversalError::DepthExceeded { limit: 1 })
+        );
+        let walker = Walker::new(WalkOrder::DepthFirst).with_max_depth(2);
+        assert!(walker.walk_tree(sample_tree(), expand_tree).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_cancellation() {
+        let token = CancellationToken::new();
+        let walker = Walker::new(WalkOrder::BreadthFirst).with_cancellation(token.clone());
+        let canceller = tokio::spawn(async move {
+            sleep(Duration::from_millis(20)).await;
+            token.cancel();
+        });
+        let result = walker
+            .walk_tree(0u64, |n| async move {
+                sleep(Duration::from_millis(5)).await;
+                (n, vec![n + 1])
+            })
+            .await;
+        canceller.await.unwrap();
+        assert_eq!(result, Err(TraversalError::Cancelled));
+    }
+
+    #[tokio::test]
+    async fn test_memo_fibonacci() {
+        let memo = Arc::new(AsyncMemo::new());
+        assert_eq!(memo_fibonacci(10, Arc::clone(&memo)).await, Some(55));
+        assert_eq!(memo_fibonacci(90, Arc::clone(&memo)).await, Some(2_880_067_194_370_816_120));
+        // fib(2)..=fib(90), each computed exactly once.
+        assert_eq!(memo.computations(), 89);
+        assert_eq!(memo.get(&10), Some(Some(55)));
+
+        assert_eq!(memo_fibonacci(93, Arc::clone(&memo)).await, Some(12_200_160_415_121_876_738));
+        assert_eq!(memo_fibonacci(94, Arc::clone(&memo)).await, None);
+        assert_eq!(memo_fibonacci(120, Arc::clone(&memo)).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_memo_shares_concurrent_computation() {
+        let memo = Arc::new(AsyncMemo::<&str, i32>::new());
+        let mut handles = Vec::new();
+        for _ in 0..8 {
+            let memo = Arc::clone(&memo);
+            handles.push(tokio::spawn(async move {
+                memo.get_or_compute("key", || async {
+                    sleep(Duration::from_millis(10)).await;
+                    42
+                })
+                .await
+            }));
+        }
+        for h in handles {
+            assert_eq!(h.await.unwrap(), 42);
+        }
+        assert_eq!(memo.computations(), 1);
+        assert_eq!(memo.len(), 1);
+    }
+}
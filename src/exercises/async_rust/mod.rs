@@ -22,11 +22,11 @@
 //! - Timeouts and cancellation
 //! - Custom future implementations
 //!
-//! ## Difficulty Distribution (30 exercises)
+//! ## Difficulty Distribution (35 exercises)
 //! - Easy: 8 exercises (01-08)
 //! - Medium: 12 exercises (09-20)
 //! - Hard: 8 exercises (21-28)
-//! - Expert: 2 exercises (29-30)
+//! - Expert: 7 exercises (29-35)
 
 pub mod exercise_01;
 pub mod exercise_02;
@@ -58,3 +58,8 @@ pub mod exercise_27;
 pub mod exercise_28;
 pub mod exercise_29;
 pub mod exercise_30;
+pub mod exercise_31;
+pub mod exercise_32;
+pub mod exercise_33;
+pub mod exercise_34;
+pub mod exercise_35;
@@ -0,0 +1,424 @@
+//! Exercise 31: Error Reports - Chains, context frames and backtraces
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Wrap any `std::error::Error` in a single report type
+//! - Record `.context(...)` frames with `#[track_caller]` locations
+//! - Capture an optional `std::backtrace::Backtrace`
+//! - Render the whole cause chain in compact, multi-line and JSON forms
+//!
+//! Exercise 21 walks a `source()` chain, exercise 23 stacks context strings
+//! and exercise 29 carries context next to a result. `Report` combines the
+//! three: it owns the original error, remembers where each piece of context
+//! was added, and can print everything at once. Like `anyhow::Error` it does
+//! not implement `Error` itself, which is what allows a blanket
+//! `From<E: Error>` so that `?` converts any error into a report.
+
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::error::Error as StdError;
+use std::fmt;
+use std::panic::Location;
+
+/// A context message and the place it was attached.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    message: String,
+    location: &'static Location<'static>,
+}
+
+impl Frame {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+}
+
+/// An error built from a plain message, for `Report::msg`.
+#[derive(Debug)]
+struct MessageError(String);
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl StdError for MessageError {}
+
+/// An error with its cause chain, context frames and optional backtrace.
+pub struct Report {
+    error: Box<dyn StdError + Send + Sync + 'static>,
+    /// Innermost first, in the order `.context()` was called.
+    frames: Vec<Frame>,
+    backtrace: Option<Backtrace>,
+}
+
+impl Report {
+    /// Wraps `error`, capturing a backtrace if `RUST_BACKTRACE` enables it.
+    pub fn new<E: StdError + Send + Sync + 'static>(error: E) -> Self {
+        Self::from_boxed(Box::new(error))
+    }
+
+    /// A report whose root error is just `message`.
+    pub fn msg(message: impl fmt::Display) -> Self {
+        Self::new(MessageError(message.to_string()))
+    }
+
+    fn from_boxed(error: Box<dyn StdError + Send + Sync + 'static>) -> Self {
+        let backtrace = Backtrace::capture();
+        let backtrace = (backtrace.status() == BacktraceStatus::Captured).then_some(backtrace);
+        Report {
+            error,
+            frames: Vec::new(),
+            backtrace,
+        }
+    }
+
+    /// Adds a context frame recording the caller's location.
+    #[track_caller]
+    pub fn context(mut self, message: impl fmt::Display) -> Self {
+        self.frames.push(Frame {
+            message: message.to_string(),
+            location: Location::caller(),
+        });
+        self
+    }
+
+    /// Captures a backtrace now, regardless of environment variables.
+    pub fn force_backtrace(mut self) -> Self {
+        self.backtrace = Some(Backtrace::force_capture());
+        self
+    }
+
+    /// Drops any captured backtrace, e.g. for stable output in tests.
+    pub fn without_backtrace(mut self) -> Self {
+        self.backtrace = None;
+        self
+    }
+
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        self.backtrace.as_ref()
+    }
+
+    /// Context frames, innermost first.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// The wrapped error followed by each of its sources.
+    pub fn chain(&self) -> Chain<'_> {
+        Chain {
+            next: Some(self.error.as_ref()),
+        }
+    }
+
+    /// The last error in the chain.
+    pub fn root_cause(&self) -> &(dyn StdError + 'static) {
+        self.chain().last().expect("chain is never empty")
+    }
+
+    /// Number of errors in the chain, as in exercise 21.
+    pub fn depth(&self) -> usize {
+        self.chain().count()
+    }
+
+    /// The first error in the chain of type `E`.
+    pub fn downcast_ref<E: StdError + 'static>(&self) -> Option<&E> {
+        self.chain().find_map(|e| e.downcast_ref::<E>())
+    }
+
+    /// Every message from the outermost context down to the root cause.
+    fn messages(&self) -> Vec<String> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|f| f.message.clone())
+            .chain(self.chain().map(|e| e.to_string()))
+            .collect()
+    }
+
+    /// One line: `outer context: inner context: error: cause`.
+    pub fn render_compact(&self) -> String {
+        self.messages().join(": ")
+    }
+
+    /// The outermost message, then a numbered `Caused by:` list with the
+    /// location of each context frame, then the backtrace if there is one.
+    pub fn render_multiline(&self) -> String {
+        let mut lines: Vec<String> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|f| format!("{}\n      at {}", f.message, f.location))
+            .collect();
+        lines.extend(self.chain().map(|e| e.to_string()));
+
+        let mut out = lines.remove(0);
+        if !lines.is_empty() {
+            out.push_str("\n\nCaused by:");
+            for (i, line) in lines.iter().enumerate() {
+                out.push_str(&format!("\n   {i}: {line}"));
+            }
+        }
+        if let Some(backtrace) = &self.backtrace {
+            out.push_str(&format!("\n\nBacktrace:\n{backtrace}"));
+        }
+        out
+    }
+
+    /// A JSON object with `message`, `context`, `chain` and `backtrace`.
+    pub fn render_json(&self) -> String {
+        let context: Vec<String> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|f| {
+                format!(
+                    "{{\"message\":{},\"file\":{},\"line\":{},\"column\":{}}}",
+                    json_string(&f.message),
+                    json_string(f.location.file()),
+                    f.location.line(),
+                    f.location.column()
+                )
+            })
+            .collect();
+        let chain: Vec<String> = self.chain().map(|e| json_string(&e.to_string())).collect();
+        let backtrace = match &self.backtrace {
+            Some(bt) => json_string(&bt.to_string()),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"message\":{},\"context\":[{}],\"chain\":[{}],\"backtrace\":{}}}",
+            json_string(&self.messages()[0]),
+            context.join(","),
+            chain.join(","),
+            backtrace
+        )
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl<E: StdError + Send + Sync + 'static> From<E> for Report {
+    fn from(error: E) -> Self {
+        Report::new(error)
+    }
+}
+
+/// Compact form; `{:#}` gives the multi-line form.
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if f.alternate() {
+            f.write_str(&self.render_multiline())
+        } else {
+            f.write_str(&self.render_compact())
+        }
+    }
+}
+
+/// Multi-line form, so `fn main() -> Result<(), Report>` prints the chain.
+impl fmt::Debug for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.render_multiline())
+    }
+}
+
+/// Iterator over an error and its sources.
+pub struct Chain<'a> {
+    next: Option<&'a (dyn StdError + 'static)>,
+}
+
+impl<'a> Iterator for Chain<'a> {
+    type Item = &'a (dyn StdError + 'static);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.next?;
+        self.next = current.source();
+        Some(current)
+    }
+}
+
+/// `.context()` for results whose error converts into a `Report`.
+pub trait Context<T> {
+    #[track_caller]
+    fn context(self, message: impl fmt::Display) -> Result<T, Report>;
+
+    /// Like `context`, but only builds the message on failure.
+    #[track_caller]
+    fn with_context<M: fmt::Display, F: FnOnce() -> M>(self, f: F) -> Result<T, Report>;
+}
+
+impl<T, E: Into<Report>> Context<T> for Result<T, E> {
+    #[track_caller]
+    fn context(self, message: impl fmt::Display) -> Result<T, Report> {
+        match self {
+            Ok(value) => Ok(value),
+            Err(e) => Err(e.into().context(message)),
+        }
+    }
+
+    #[track_caller]
+    fn with_context<M: fmt::Display, F: FnOnce() -> M>(self, f: F) -> Result<T, Report> {
+        match self {
+            Ok(value) => Ok(value),
+            Err(e) => Err(e.into().context(f())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::ParseIntError;
+
+    #[derive(Debug)]
+    struct ConfigError {
+        key: String,
+        source: ParseIntError,
+    }
+
+    impl fmt::Display for ConfigError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "invalid value for {}", self.key)
+        }
+    }
+
+    impl StdError for ConfigError {
+        fn source(&self) -> Option<&(dyn StdError + 'static)> {
+            Some(&self.source)
+        }
+    }
+
+    fn parse_port(raw: &str) -> Result<u16, ConfigError> {
+        raw.parse().map_err(|source| ConfigError {
+            key: "port".into(),
+            source,
+        })
+    }
+
+    fn load(raw: &str) -> Result<u16, Report> {
+        let port = parse_port(raw).context("reading server section")?;
+        Ok(port)
+    }
+
+    #[test]
+    fn test_chain_and_depth() {
+        let report = Report::new(parse_port("x").unwrap_err());
+        assert_eq!(report.depth(), 2);
+        let messages: Vec<String> = report.chain().map(|e| e.to_string()).collect();
+        assert_eq!(messages[0], "invalid value for port");
+        assert!(
+            report
+                .root_cause()
+                .downcast_ref::<ParseIntError>()
+                .is_some()
+        );
+        assert_eq!(report.downcast_ref::<ConfigError>().unwrap().key, "port");
+    }
+
+    #[test]
+    fn test_context_records_caller_location() {
+        let line = line!() + 1;
+        let report = load("x").unwrap_err().context("starting server");
+        let frames = report.frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].message(), "reading server section");
+        assert_eq!(frames[1].message(), "starting server");
+        assert_eq!(frames[1].location().line(), line);
+        assert_eq!(frames[1].location().file(), file!());
+        // The first frame points into `load`, not into this module's trait impl.
+        assert!(frames[0].location().line() < line);
+    }
+
+    #[test]
+    fn test_question_mark_and_with_context() {
+        fn run() -> Result<u16, Report> {
+            let value: u16 = "70000".parse()?;
+            Ok(value)
+        }
+        let report = run().unwrap_err();
+        assert!(report.frames().is_empty());
+        assert!(report.downcast_ref::<ParseIntError>().is_some());
+
+        let mut built = false;
+        let ok: Result<u8, Report> = "7".parse::<u8>().with_context(|| {
+            built = true;
+            "never"
+        });
+        assert_eq!(ok.unwrap(), 7);
+        assert!(!built);
+    }
+
+    #[test]
+    fn test_compact_rendering() {
+        let report = load("x").unwrap_err().context("starting server");
+        assert_eq!(
+            report.to_string(),
+            "starting server: reading server section: invalid value for port: \
+             invalid digit found in string"
+        );
+        assert_eq!(Report::msg("plain").render_compact(), "plain");
+    }
+
+    #[test]
+    fn test_multiline_rendering() {
+        let report = load("x")
+            .unwrap_err()
+            .context("starting server")
+            .without_backtrace();
+        let text = format!("{report:#}");
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "starting server");
+        assert!(
+            lines[1]
+                .trim_start()
+                .starts_with(&format!("at {}:", file!()))
+        );
+        assert!(text.contains("Caused by:"));
+        assert!(text.contains("   0: reading server section"));
+        assert!(text.contains("   1: invalid value for port"));
+        assert!(text.contains("   2: invalid digit found in string"));
+        assert!(!text.contains("Backtrace:"));
+        assert_eq!(format!("{report:?}"), text);
+    }
+
+    #[test]
+    fn test_json_rendering() {
+        let report = Report::msg("bad \"quote\"\nline")
+            .context("loading")
+            .without_backtrace();
+        let json = report.render_json();
+        assert!(json.starts_with("{\"message\":\"loading\",\"context\":[{\"message\":\"loading\""));
+        assert!(json.contains(&format!("\"file\":\"{}\"", file!())));
+        assert!(json.contains("\"chain\":[\"bad \\\"quote\\\"\\nline\"]"));
+        assert!(json.ends_with("\"backtrace\":null}"));
+    }
+
+    #[test]
+    fn test_backtrace_capture() {
+        let report = Report::msg("boom").force_backtrace();
+        assert!(report.backtrace().is_some());
+        assert!(report.render_multiline().contains("Backtrace:"));
+        assert!(!report.render_json().ends_with("\"backtrace\":null}"));
+        assert!(report.without_backtrace().backtrace().is_none());
+    }
+}
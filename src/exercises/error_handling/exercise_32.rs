@@ -0,0 +1,760 @@
+//! Exercise 32: Structured Error Logging - Sinks, filters and rotation
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Put log destinations behind an `ErrorSink` trait
+//! - Filter by severity per sink
+//! - Write JSON-lines files with size-based rotation
+//! - Query stored logs by time range, location and severity
+//!
+//! Exercise 27's `ErrorLogger` keeps every `ErrorLog` in one `Vec`. Here the
+//! same records fan out to any number of sinks: memory for tests and
+//! queries, stderr for people, and JSON-lines files for batch tools, one
+//! object per line so `grep '"severity":"CRITICAL"'` just works. A failing
+//! sink does not stop the others from receiving the record.
+
+use super::exercise_27::{ErrorLog, ErrorSeverity};
+use super::exercise_31::json_string;
+use error_derive::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Error)]
+pub enum SinkError {
+    #[error("I/O error on {path:?}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error("{path:?} line {line}: {reason}")]
+    Parse {
+        path: PathBuf,
+        line: usize,
+        reason: String,
+    },
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> SinkError + '_ {
+    move |source| SinkError::Io {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+/// Orders severities so filters can say "this level and above".
+pub fn severity_rank(severity: &ErrorSeverity) -> u8 {
+    match severity {
+        ErrorSeverity::Warning => 0,
+        ErrorSeverity::Error => 1,
+        ErrorSeverity::Critical => 2,
+    }
+}
+
+pub fn severity_label(severity: &ErrorSeverity) -> &'static str {
+    match severity {
+        ErrorSeverity::Warning => "WARNING",
+        ErrorSeverity::Error => "ERROR",
+        ErrorSeverity::Critical => "CRITICAL",
+    }
+}
+
+fn parse_severity(label: &str) -> Option<ErrorSeverity> {
+    match label {
+        "WARNING" => Some(ErrorSeverity::Warning),
+        "ERROR" => Some(ErrorSeverity::Error),
+        "CRITICAL" => Some(ErrorSeverity::Critical),
+        _ => None,
+    }
+}
+
+fn timestamp_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// A destination for error logs.
+pub trait ErrorSink: Send {
+    /// Records below this severity are not passed to `write`.
+    fn min_severity(&self) -> ErrorSeverity;
+
+    fn write(&mut self, log: &ErrorLog) -> Result<(), SinkError>;
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn accepts(&self, log: &ErrorLog) -> bool {
+        severity_rank(&log.severity) >= severity_rank(&self.min_severity())
+    }
+}
+
+/// Filters applied by `MemoryStore::query` and `LogQuery::apply`.
+#[derive(Debug, Clone, Default)]
+pub struct LogQuery {
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+    location: Option<String>,
+    min_severity: Option<ErrorSeverity>,
+    severity: Option<ErrorSeverity>,
+}
+
+impl LogQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records at or after `time`.
+    pub fn since(mut self, time: SystemTime) -> Self {
+        self.since = Some(time);
+        self
+    }
+
+    /// Records strictly before `time`.
+    pub fn until(mut self, time: SystemTime) -> Self {
+        self.until = Some(time);
+        self
+    }
+
+    /// Records whose location contains `location`.
+    pub fn location(mut self, location: impl Into<String>) -> Self {
+        self.location = Some(location.into());
+        self
+    }
+
+    pub fn min_severity(mut self, severity: ErrorSeverity) -> Self {
+        self.min_severity = Some(severity);
+        self
+    }
+
+    pub fn severity(mut self, severity: ErrorSeverity) -> Self {
+        self.severity = Some(severity);
+        self
+    }
+
+    pub fn matches(&self, log: &ErrorLog) -> bool {
+        self.since.is_none_or(|t| log.timestamp >= t)
+            && self.until.is_none_or(|t| log.timestamp < t)
+            && self
+                .location
+                .as_ref()
+                .is_none_or(|l| log.location.contains(l.as_str()))
+            && self
+                .min_severity
+                .as_ref()
+                .is_none_or(|s| severity_rank(&log.severity) >= severity_rank(s))
+            && self.severity.as_ref().is_none_or(|s| log.severity == *s)
+    }
+
+    pub fn apply<'a>(&self, logs: impl IntoIterator<Item = &'a ErrorLog>) -> Vec<ErrorLog> {
+        logs.into_iter()
+            .filter(|log| self.matches(log))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Read side of a `MemorySink`, usable after the sink has moved into a logger.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    logs: Arc<Mutex<Vec<ErrorLog>>>,
+}
+
+impl MemoryStore {
+    pub fn len(&self) -> usize {
+        self.logs.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn all(&self) -> Vec<ErrorLog> {
+        self.logs.lock().unwrap().clone()
+    }
+
+    pub fn query(&self, query: &LogQuery) -> Vec<ErrorLog> {
+        query.apply(self.logs.lock().unwrap().iter())
+    }
+
+    pub fn count_by_severity(&self, severity: ErrorSeverity) -> usize {
+        self.query(&LogQuery::new().severity(severity)).len()
+    }
+}
+
+/// Keeps accepted logs in memory.
+#[derive(Debug)]
+pub struct MemorySink {
+    store: MemoryStore,
+    min_severity: ErrorSeverity,
+}
+
+impl MemorySink {
+    pub fn new(min_severity: ErrorSeverity) -> Self {
+        MemorySink {
+            store: MemoryStore::default(),
+            min_severity,
+        }
+    }
+
+    pub fn store(&self) -> MemoryStore {
+        self.store.clone()
+    }
+}
+
+impl ErrorSink for MemorySink {
+    fn min_severity(&self) -> ErrorSeverity {
+        self.min_severity.clone()
+    }
+
+    fn write(&mut self, log: &ErrorLog) -> Result<(), SinkError> {
+        self.store.logs.lock().unwrap().push(log.clone());
+        Ok(())
+    }
+}
+
+/// Writes `[SEVERITY] <millis> location: message` lines to any writer.
+pub struct WriterSink<W> {
+    writer: W,
+    min_severity: ErrorSeverity,
+}
+
+pub type StderrSink = WriterSink<io::Stderr>;
+
+impl WriterSink<io::Stderr> {
+    pub fn stderr(min_severity: ErrorSeverity) -> Self {
+        WriterSink::new(io::stderr(), min_severity)
+    }
+}
+
+impl<W: Write + Send> WriterSink<W> {
+    pub fn new(writer: W, min_severity: ErrorSeverity) -> Self {
+        WriterSink {
+            writer,
+            min_severity,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> ErrorSink for WriterSink<W> {
+    fn min_severity(&self) -> ErrorSeverity {
+        self.min_severity.clone()
+    }
+
+    fn write(&mut self, log: &ErrorLog) -> Result<(), SinkError> {
+        writeln!(
+            self.writer,
+            "[{}] {} {}: {}",
+            severity_label(&log.severity),
+            timestamp_millis(log.timestamp),
+            log.location,
+            log.message
+        )
+        .map_err(io_error(Path::new("<writer>")))
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.writer.flush().map_err(io_error(Path::new("<writer>")))
+    }
+}
+
+/// Appends one JSON object per log to a file, rotating by size.
+///
+/// When a write would push the file past `max_bytes`, `app.log` becomes
+/// `app.log.1`, `app.log.1` becomes `app.log.2`, and so on; files beyond
+/// `max_files` rotated copies are deleted.
+pub struct JsonLinesSink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    max_files: usize,
+    min_severity: ErrorSeverity,
+}
+
+impl JsonLinesSink {
+    pub fn open(
+        path: impl Into<PathBuf>,
+        max_bytes: u64,
+        max_files: usize,
+        min_severity: ErrorSeverity,
+    ) -> Result<Self, SinkError> {
+        let path = path.into();
+        let file = Self::open_append(&path)?;
+        let size = file.metadata().map_err(io_error(&path))?.len();
+        Ok(JsonLinesSink {
+            path,
+            file,
+            size,
+            max_bytes,
+            max_files,
+            min_severity,
+        })
+    }
+
+    fn open_append(path: &Path) -> Result<File, SinkError> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(io_error(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path of the `n`th rotated file (`n >= 1`).
+    pub fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> Result<(), SinkError> {
+        self.file.flush().map_err(io_error(&self.path))?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path).map_err(io_error(&self.path))?;
+        } else {
+            let oldest = self.rotated_path(self.max_files);
+            if oldest.exists() {
+                fs::remove_file(&oldest).map_err(io_error(&oldest))?;
+            }
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(n + 1)).map_err(io_error(&from))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1)).map_err(io_error(&self.path))?;
+        }
+        self.file = Self::open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl ErrorSink for JsonLinesSink {
+    fn min_severity(&self) -> ErrorSeverity {
+        self.min_severity.clone()
+    }
+
+    fn write(&mut self, log: &ErrorLog) -> Result<(), SinkError> {
+        let line = to_json_line(log);
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file
+            .write_all(line.as_bytes())
+            .map_err(io_error(&self.path))?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.file.flush().map_err(io_error(&self.path))
+    }
+}
+
+/// `{"timestamp_ms":..,"severity":"..","location":"..","message":".."}\n`
+pub fn to_json_line(log: &ErrorLog) -> String {
+    format!(
+        "{{\"timestamp_ms\":{},\"severity\":\"{}\",\"location\":{},\"message\":{}}}\n",
+        timestamp_millis(log.timestamp),
+        severity_label(&log.severity),
+        json_string(&log.location),
+        json_string(&log.message)
+    )
+}
+
+/// Parses one line written by `to_json_line`.
+pub fn from_json_line(line: &str) -> Result<ErrorLog, String> {
+    let body = line
+        .trim()
+        .strip_prefix('{')
+        .and_then(|s| s.strip_suffix('}'))
+        .ok_or("expected a JSON object")?;
+    let mut chars = body.chars().peekable();
+    let (mut millis, mut severity, mut location, mut message) = (None, None, None, None);
+    loop {
+        match chars.peek() {
+            None => break,
+            Some(',') | Some(' ') => {
+                chars.next();
+                continue;
+            }
+            _ => {}
+        }
+        let key = read_json_string(&mut chars)?;
+        if chars.next() != Some(':') {
+            return Err(format!("expected ':' after {key:?}"));
+        }
+        if key == "timestamp_ms" {
+            let digits: String =
+                std::iter::from_fn(|| chars.next_if(char::is_ascii_digit)).collect();
+            millis = Some(digits.parse::<u64>().map_err(|e| e.to_string())?);
+        } else {
+            let value = read_json_string(&mut chars)?;
+            match key.as_str() {
+                "severity" => {
+                    severity =
+                        Some(parse_severity(&value).ok_or(format!("unknown severity {value:?}"))?)
+                }
+                "location" => location = Some(value),
+                "message" => message = Some(value),
+                _ => {}
+            }
+        }
+    }
+    Ok(ErrorLog {
+        timestamp: UNIX_EPOCH + Duration::from_millis(millis.ok_or("missing timestamp_ms")?),
+        severity: severity.ok_or("missing severity")?,
+        location: location.ok_or("missing location")?,
+        message: message.ok_or("missing message")?,
+    })
+}
+
+fn read_json_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, String> {
+    if chars.next() != Some('"') {
+        return Err("expected a string".into());
+    }
+    let mut out = String::new();
+    loop {
+        match chars.next().ok_or("unterminated string")? {
+            '"' => return Ok(out),
+            '\\' => match chars.next().ok_or("unterminated escape")? {
+                'n' => out.push('\n'),
+                'r' => out.push('\r'),
+                't' => out.push('\t'),
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    let code = u32::from_str_radix(&hex, 16).map_err(|e| e.to_string())?;
+                    out.push(char::from_u32(code).ok_or("invalid \\u escape")?);
+                }
+                c => out.push(c),
+            },
+            c => out.push(c),
+        }
+    }
+}
+
+/// Reads every log from a JSON-lines file, e.g. to run a `LogQuery` on it.
+pub fn read_json_lines(path: impl AsRef<Path>) -> Result<Vec<ErrorLog>, SinkError> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(io_error(path))?;
+    let mut logs = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(io_error(path))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        logs.push(from_json_line(&line).map_err(|reason| SinkError::Parse {
+            path: path.to_path_buf(),
+            line: i + 1,
+            reason,
+        })?);
+    }
+    Ok(logs)
+}
+
+/// Sends each log to every sink that accepts its severity.
+#[derive(Default)]
+pub struct StructuredLogger {
+    sinks: Vec<Box<dyn ErrorSink>>,
+}
+
+impl StructuredLogger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_sink(mut self, sink: impl ErrorSink + 'static) -> Self {
+        self.add_sink(sink);
+        self
+    }
+
+    pub fn add_sink(&mut self, sink: impl ErrorSink + 'static) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    /// Writes to every accepting sink, returning the errors of those that
+    /// failed.
+    pub fn log(&mut self, log: ErrorLog) -> Result<(), Vec<SinkError>> {
+        let errors: Vec<SinkError> = self
+            .sinks
+            .iter_mut()
+            .filter(|sink| sink.accepts(&log))
+            .filter_map(|sink| sink.write(&log).err())
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Logs a record stamped with the current time.
+    pub fn record(
+        &mut self,
+        message: impl Into<String>,
+        severity: ErrorSeverity,
+        location: impl Into<String>,
+    ) -> Result<(), Vec<SinkError>> {
+        self.log(ErrorLog {
+            message: message.into(),
+            timestamp: SystemTime::now(),
+            severity,
+            location: location.into(),
+        })
+    }
+
+    pub fn flush(&mut self) -> Result<(), Vec<SinkError>> {
+        let errors: Vec<SinkError> = self
+            .sinks
+            .iter_mut()
+            .filter_map(|sink| sink.flush().err())
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "exercise_32_{}_{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        /// Writes `contents` to `name` inside the directory.
+        fn file(&self, name: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn log_at(secs: u64, severity: ErrorSeverity, location: &str, message: &str) -> ErrorLog {
+        ErrorLog {
+            message: message.into(),
+            timestamp: UNIX_EPOCH + Duration::from_secs(secs),
+            severity,
+            location: location.into(),
+        }
+    }
+
+    #[test]
+    fn test_severity_filter_per_sink() {
+        let all = MemorySink::new(ErrorSeverity::Warning);
+        let critical = MemorySink::new(ErrorSeverity::Critical);
+        let (all_store, critical_store) = (all.store(), critical.store());
+        let mut logger = StructuredLogger::new().with_sink(all).with_sink(critical);
+
+        logger
+            .record("disk 80%", ErrorSeverity::Warning, "monitor")
+            .unwrap();
+        logger
+            .record("bad row", ErrorSeverity::Error, "import")
+            .unwrap();
+        logger
+            .record("db down", ErrorSeverity::Critical, "db::pool")
+            .unwrap();
+
+        assert_eq!(all_store.len(), 3);
+        assert_eq!(critical_store.len(), 1);
+        assert_eq!(critical_store.all()[0].message, "db down");
+        assert_eq!(all_store.count_by_severity(ErrorSeverity::Error), 1);
+    }
+
+    #[test]
+    fn test_query_by_time_location_and_severity() {
+        let sink = MemorySink::new(ErrorSeverity::Warning);
+        let store = sink.store();
+        let mut logger = StructuredLogger::new().with_sink(sink);
+        logger
+            .log(log_at(100, ErrorSeverity::Warning, "db::pool", "slow"))
+            .unwrap();
+        logger
+            .log(log_at(200, ErrorSeverity::Error, "db::query", "timeout"))
+            .unwrap();
+        logger
+            .log(log_at(300, ErrorSeverity::Critical, "http", "panic"))
+            .unwrap();
+        logger
+            .log(log_at(400, ErrorSeverity::Error, "db::pool", "reset"))
+            .unwrap();
+
+        let t = |s| UNIX_EPOCH + Duration::from_secs(s);
+        let messages = |q: &LogQuery| -> Vec<String> {
+            store.query(q).into_iter().map(|l| l.message).collect()
+        };
+        assert_eq!(
+            messages(&LogQuery::new().since(t(200)).until(t(400))),
+            ["timeout", "panic"]
+        );
+        assert_eq!(
+            messages(&LogQuery::new().location("db::")),
+            ["slow", "timeout", "reset"]
+        );
+        assert_eq!(
+            messages(
+                &LogQuery::new()
+                    .min_severity(ErrorSeverity::Error)
+                    .location("db")
+            ),
+            ["timeout", "reset"]
+        );
+        assert_eq!(
+            messages(&LogQuery::new().severity(ErrorSeverity::Critical)),
+            ["panic"]
+        );
+    }
+
+    #[test]
+    fn test_writer_sink_format() {
+        let mut sink = WriterSink::new(Vec::new(), ErrorSeverity::Error);
+        let log = log_at(2, ErrorSeverity::Error, "parse", "bad input");
+        assert!(sink.accepts(&log));
+        assert!(!sink.accepts(&log_at(2, ErrorSeverity::Warning, "x", "y")));
+        sink.write(&log).unwrap();
+        let text = String::from_utf8(sink.into_inner()).unwrap();
+        assert_eq!(text, "[ERROR] 2000 parse: bad input\n");
+    }
+
+    #[test]
+    fn test_json_lines_round_trip() {
+        let dir = TempDir::new();
+        let path = dir.0.join("errors.log");
+        let sink = JsonLinesSink::open(&path, 1 << 20, 3, ErrorSeverity::Warning).unwrap();
+        let mut logger = StructuredLogger::new().with_sink(sink);
+        let tricky = log_at(
+            5,
+            ErrorSeverity::Critical,
+            "job \"nightly\"",
+            "line1\nline2\t\\ \u{1}",
+        );
+        logger
+            .log(log_at(1, ErrorSeverity::Warning, "job", "retrying"))
+            .unwrap();
+        logger.log(tricky.clone()).unwrap();
+        logger.flush().unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert!(
+            text.lines()
+                .nth(1)
+                .unwrap()
+                .contains("\"severity\":\"CRITICAL\"")
+        );
+
+        let logs = read_json_lines(&path).unwrap();
+        assert_eq!(logs[1], tricky);
+        let critical = LogQuery::new()
+            .severity(ErrorSeverity::Critical)
+            .apply(&logs);
+        assert_eq!(critical, vec![tricky]);
+    }
+
+    #[test]
+    fn test_size_based_rotation() {
+        let dir = TempDir::new();
+        let path = dir.0.join("batch.log");
+        let line_len =
+            to_json_line(&log_at(10, ErrorSeverity::Error, "batch", "row 0")).len() as u64;
+        // Room for two lines per file, keeping two rotated files.
+        let mut sink = JsonLinesSink::open(&path, line_len * 2, 2, ErrorSeverity::Warning).unwrap();
+        for i in 0..7 {
+            sink.write(&log_at(
+                10 + i,
+                ErrorSeverity::Error,
+                "batch",
+                &format!("row {i}"),
+            ))
+            .unwrap();
+        }
+        sink.flush().unwrap();
+
+        let messages = |p: &Path| -> Vec<String> {
+            read_json_lines(p)
+                .unwrap()
+                .into_iter()
+                .map(|l| l.message)
+                .collect()
+        };
+        assert_eq!(messages(&path), ["row 6"]);
+        assert_eq!(messages(&sink.rotated_path(1)), ["row 4", "row 5"]);
+        assert_eq!(messages(&sink.rotated_path(2)), ["row 2", "row 3"]);
+        assert!(!sink.rotated_path(3).exists());
+    }
+
+    #[test]
+    fn test_reopen_appends_and_counts_existing_size() {
+        let dir = TempDir::new();
+        let path = dir.0.join("app.log");
+        let line_len = to_json_line(&log_at(10, ErrorSeverity::Error, "a", "m")).len() as u64;
+        {
+            let mut sink =
+                JsonLinesSink::open(&path, line_len * 2, 1, ErrorSeverity::Warning).unwrap();
+            sink.write(&log_at(10, ErrorSeverity::Error, "a", "m"))
+                .unwrap();
+        }
+        let mut sink = JsonLinesSink::open(&path, line_len * 2, 1, ErrorSeverity::Warning).unwrap();
+        sink.write(&log_at(11, ErrorSeverity::Error, "a", "m"))
+            .unwrap();
+        sink.write(&log_at(12, ErrorSeverity::Error, "a", "m"))
+            .unwrap();
+        assert_eq!(read_json_lines(&path).unwrap().len(), 1);
+        assert_eq!(read_json_lines(sink.rotated_path(1)).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_failing_sink_does_not_block_others() {
+        struct Broken;
+        impl ErrorSink for Broken {
+            fn min_severity(&self) -> ErrorSeverity {
+                ErrorSeverity::Warning
+            }
+            fn write(&mut self, _: &ErrorLog) -> Result<(), SinkError> {
+                Err(SinkError::Io {
+                    path: "/dev/full".into(),
+                    source: io::Error::other("no space"),
+                })
+            }
+        }
+        let memory = MemorySink::new(ErrorSeverity::Warning);
+        let store = memory.store();
+        let mut logger = StructuredLogger::new().with_sink(Broken).with_sink(memory);
+        let errors = logger.record("x", ErrorSeverity::Error, "y").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("no space"));
+        assert_eq!(store.len(), 1);
+        assert!(read_json_lines("/nonexistent/dir/x.log").is_err());
+    }
+}
@@ -0,0 +1,420 @@
+//! Exercise 33: Transactional Ledger - Atomic multi-account operations
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Make a sequence of fallible steps all-or-nothing
+//! - Roll back with an undo log, including when the caller panics
+//! - Keep an append-only journal of committed operations
+//! - Rebuild state by replaying the journal
+//!
+//! Exercise 17 transfers between two `&mut Account`s, and the
+//! `memory_management` exercise 28 `Transaction` runs a rollback closure
+//! unless it was committed. `Ledger` does both for any number of accounts:
+//! every step of a `Transaction` is applied immediately and its previous
+//! balance is pushed onto an undo log. `commit` appends the steps to the
+//! journal; dropping the transaction without committing, whether through
+//! `?` or a panic, restores every touched balance.
+
+use error_derive::Error;
+use std::collections::BTreeMap;
+
+pub type AccountId = u32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum LedgerError {
+    #[error("account {0} not found")]
+    AccountNotFound(AccountId),
+    #[error("account {0} already exists")]
+    AccountExists(AccountId),
+    #[error("amount must be positive, got {0}")]
+    InvalidAmount(i64),
+    #[error("cannot transfer from account {0} to itself")]
+    SameAccount(AccountId),
+    #[error("account {account} has {balance}, cannot withdraw {requested}")]
+    Overdraft {
+        account: AccountId,
+        balance: i64,
+        requested: i64,
+    },
+    #[error("balance of account {0} would overflow")]
+    Overflow(AccountId),
+}
+
+/// One step of a transaction, as recorded in the journal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Open {
+        account: AccountId,
+        initial: i64,
+    },
+    Deposit {
+        account: AccountId,
+        amount: i64,
+    },
+    Withdraw {
+        account: AccountId,
+        amount: i64,
+    },
+    Transfer {
+        from: AccountId,
+        to: AccountId,
+        amount: i64,
+    },
+}
+
+/// A committed transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub ops: Vec<Op>,
+}
+
+#[derive(Debug, Default)]
+pub struct Ledger {
+    balances: BTreeMap<AccountId, i64>,
+    journal: Vec<JournalEntry>,
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn balance(&self, account: AccountId) -> Result<i64, LedgerError> {
+        self.balances
+            .get(&account)
+            .copied()
+            .ok_or(LedgerError::AccountNotFound(account))
+    }
+
+    pub fn balances(&self) -> &BTreeMap<AccountId, i64> {
+        &self.balances
+    }
+
+    /// Sum of all balances, widened so that many large accounts cannot
+    /// overflow it.
+    pub fn total(&self) -> i128 {
+        self.balances.values().map(|&b| i128::from(b)).sum()
+    }
+
+    pub fn journal(&self) -> &[JournalEntry] {
+        &self.journal
+    }
+
+    /// Starts a transaction. Nothing is journaled until `commit`.
+    pub fn begin(&mut self) -> Transaction<'_> {
+        Transaction {
+            ledger: self,
+            undo: Vec::new(),
+            ops: Vec::new(),
+            committed: false,
+        }
+    }
+
+    /// Runs `f` in a transaction, committing if it returns `Ok` and
+    /// rolling back otherwise.
+    pub fn transaction<R, E, F>(&mut self, f: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut Transaction<'_>) -> Result<R, E>,
+    {
+        let mut tx = self.begin();
+        let value = f(&mut tx)?;
+        tx.commit();
+        Ok(value)
+    }
+
+    /// Applies `ops` as a single transaction.
+    pub fn apply(&mut self, ops: &[Op]) -> Result<(), LedgerError> {
+        self.transaction(|tx| ops.iter().try_for_each(|op| tx.execute(op.clone())))
+    }
+
+    /// Builds a fresh ledger by re-running every journaled transaction.
+    pub fn replay(journal: &[JournalEntry]) -> Result<Ledger, LedgerError> {
+        let mut ledger = Ledger::new();
+        for entry in journal {
+            ledger.apply(&entry.ops)?;
+        }
+        Ok(ledger)
+    }
+}
+
+/// Pending changes to a `Ledger`, undone on drop unless committed.
+pub struct Transaction<'a> {
+    ledger: &'a mut Ledger,
+    /// Previous balance of each change, `None` for newly opened accounts.
+    undo: Vec<(AccountId, Option<i64>)>,
+    ops: Vec<Op>,
+    committed: bool,
+}
+
+impl Transaction<'_> {
+    pub fn open(&mut self, account: AccountId, initial: i64) -> Result<(), LedgerError> {
+        self.execute(Op::Open { account, initial })
+    }
+
+    pub fn deposit(&mut self, account: AccountId, amount: i64) -> Result<(), LedgerError> {
+        self.execute(Op::Deposit { account, amount })
+    }
+
+    pub fn withdraw(&mut self, account: AccountId, amount: i64) -> Result<(), LedgerError> {
+        self.execute(Op::Withdraw { account, amount })
+    }
+
+    pub fn transfer(
+        &mut self,
+        from: AccountId,
+        to: AccountId,
+        amount: i64,
+    ) -> Result<(), LedgerError> {
+        self.execute(Op::Transfer { from, to, amount })
+    }
+
+    /// Balance as seen inside the transaction.
+    pub fn balance(&self, account: AccountId) -> Result<i64, LedgerError> {
+        self.ledger.balance(account)
+    }
+
+    /// Validates and applies one step. A failed step changes nothing.
+    pub fn execute(&mut self, op: Op) -> Result<(), LedgerError> {
+        match op {
+            Op::Open { account, initial } => {
+                if initial < 0 {
+                    return Err(LedgerError::InvalidAmount(initial));
+                }
+                if self.ledger.balances.contains_key(&account) {
+                    return Err(LedgerError::AccountExists(account));
+                }
+                self.set(account, initial);
+            }
+            Op::Deposit { account, amount } => {
+                let new = self.credited(account, amount)?;
+                self.set(account, new);
+            }
+            Op::Withdraw { account, amount } => {
+                let new = self.debited(account, amount)?;
+                self.set(account, new);
+            }
+            Op::Transfer { from, to, amount } => {
+                if from == to {
+                    return Err(LedgerError::SameAccount(from));
+                }
+                let debited = self.debited(from, amount)?;
+                let credited = self.credited(to, amount)?;
+                self.set(from, debited);
+                self.set(to, credited);
+            }
+        }
+        self.ops.push(op);
+        Ok(())
+    }
+
+    fn credited(&self, account: AccountId, amount: i64) -> Result<i64, LedgerError> {
+        if amount <= 0 {
+            return Err(LedgerError::InvalidAmount(amount));
+        }
+        self.balance(account)?
+            .checked_add(amount)
+            .ok_or(LedgerError::Overflow(account))
+    }
+
+    fn debited(&self, account: AccountId, amount: i64) -> Result<i64, LedgerError> {
+        if amount <= 0 {
+            return Err(LedgerError::InvalidAmount(amount));
+        }
+        let balance = self.balance(account)?;
+        if balance < amount {
+            return Err(LedgerError::Overdraft {
+                account,
+                balance,
+                requested: amount,
+            });
+        }
+        Ok(balance - amount)
+    }
+
+    fn set(&mut self, account: AccountId, balance: i64) {
+        let previous = self.ledger.balances.insert(account, balance);
+        self.undo.push((account, previous));
+    }
+
+    /// Makes the changes permanent and journals them.
+    pub fn commit(mut self) {
+        self.committed = true;
+        if !self.ops.is_empty() {
+            let seq = self.ledger.journal.len() as u64 + 1;
+            let ops = std::mem::take(&mut self.ops);
+            self.ledger.journal.push(JournalEntry { seq, ops });
+        }
+    }
+
+    /// Undoes every change made so far.
+    pub fn rollback(self) {}
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        for (account, previous) in self.undo.drain(..).rev() {
+            match previous {
+                Some(balance) => self.ledger.balances.insert(account, balance),
+                None => self.ledger.balances.remove(&account),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    fn funded() -> Ledger {
+        let mut ledger = Ledger::new();
+        ledger
+            .transaction(|tx| {
+                tx.open(1, 1000)?;
+                tx.open(2, 500)?;
+                tx.open(3, 0)
+            })
+            .unwrap();
+        ledger
+    }
+
+    #[test]
+    fn test_multi_step_commit() {
+        let mut ledger = funded();
+        ledger
+            .transaction(|tx| {
+                tx.transfer(1, 2, 300)?;
+                tx.transfer(2, 3, 800)?;
+                tx.deposit(1, 50)
+            })
+            .unwrap();
+        assert_eq!(ledger.balance(1), Ok(750));
+        assert_eq!(ledger.balance(2), Ok(0));
+        assert_eq!(ledger.balance(3), Ok(800));
+        assert_eq!(ledger.journal().len(), 2);
+        assert_eq!(ledger.journal()[1].seq, 2);
+        assert_eq!(ledger.journal()[1].ops.len(), 3);
+    }
+
+    #[test]
+    fn test_failure_rolls_back_every_step() {
+        let mut ledger = funded();
+        let before = ledger.balances().clone();
+        let result = ledger.transaction(|tx| {
+            tx.transfer(1, 3, 900)?;
+            tx.open(4, 10)?;
+            assert_eq!(tx.balance(3), Ok(900));
+            tx.transfer(2, 1, 600)
+        });
+        assert_eq!(
+            result,
+            Err(LedgerError::Overdraft {
+                account: 2,
+                balance: 500,
+                requested: 600
+            })
+        );
+        assert_eq!(ledger.balances(), &before);
+        assert_eq!(ledger.balance(4), Err(LedgerError::AccountNotFound(4)));
+        assert_eq!(ledger.journal().len(), 1);
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        let mut ledger = funded();
+        let mut tx = ledger.begin();
+        assert_eq!(tx.withdraw(9, 1), Err(LedgerError::AccountNotFound(9)));
+        assert_eq!(tx.deposit(1, 0), Err(LedgerError::InvalidAmount(0)));
+        assert_eq!(tx.transfer(1, 1, 5), Err(LedgerError::SameAccount(1)));
+        assert_eq!(tx.open(2, 0), Err(LedgerError::AccountExists(2)));
+        tx.deposit(3, i64::MAX).unwrap();
+        assert_eq!(tx.deposit(3, 1), Err(LedgerError::Overflow(3)));
+        // A failed transfer leaves both sides untouched.
+        assert_eq!(tx.transfer(1, 3, 1), Err(LedgerError::Overflow(3)));
+        assert_eq!(tx.balance(1), Ok(1000));
+        tx.rollback();
+        assert_eq!(ledger.balance(3), Ok(0));
+        assert_eq!(
+            LedgerError::Overdraft {
+                account: 7,
+                balance: 5,
+                requested: 9
+            }
+            .to_string(),
+            "account 7 has 5, cannot withdraw 9"
+        );
+    }
+
+    #[test]
+    fn test_panic_rolls_back() {
+        let mut ledger = funded();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = ledger.transaction(|tx| -> Result<(), LedgerError> {
+                tx.withdraw(1, 1000)?;
+                panic!("crashed mid-transaction");
+            });
+        }));
+        assert!(result.is_err());
+        assert_eq!(ledger.balance(1), Ok(1000));
+        assert_eq!(ledger.journal().len(), 1);
+    }
+
+    #[test]
+    fn test_custom_error_type_in_closure() {
+        #[derive(Debug, PartialEq)]
+        enum AppError {
+            Ledger(LedgerError),
+            Fraud,
+        }
+        impl From<LedgerError> for AppError {
+            fn from(e: LedgerError) -> Self {
+                AppError::Ledger(e)
+            }
+        }
+        let mut ledger = funded();
+        let result = ledger.transaction(|tx| {
+            tx.transfer(1, 2, 10)?;
+            Err::<(), _>(AppError::Fraud)
+        });
+        assert_eq!(result, Err(AppError::Fraud));
+        assert_eq!(ledger.balance(1), Ok(1000));
+    }
+
+    #[test]
+    fn test_replay_reproduces_balances() {
+        let mut ledger = funded();
+        for i in 0..20 {
+            let _ = ledger.transaction(|tx| {
+                tx.transfer(1 + i % 3, 1 + (i + 1) % 3, 70 * (i as i64 % 5 + 1))?;
+                tx.deposit(1 + i % 2, 5)
+            });
+        }
+        let _ = ledger.apply(&[Op::Withdraw {
+            account: 3,
+            amount: i64::MAX,
+        }]);
+        let replayed = Ledger::replay(ledger.journal()).unwrap();
+        assert_eq!(replayed.balances(), ledger.balances());
+        assert_eq!(replayed.journal(), ledger.journal());
+        assert_eq!(
+            replayed.total(),
+            1500 + 5 * ledger.journal()[1..].len() as i128
+        );
+    }
+
+    #[test]
+    fn test_total_does_not_overflow() {
+        let mut ledger = Ledger::new();
+        ledger
+            .transaction(|tx| {
+                tx.open(1, i64::MAX)?;
+                tx.open(2, i64::MAX)
+            })
+            .unwrap();
+        assert_eq!(ledger.total(), 2 * i64::MAX as i128);
+    }
+}
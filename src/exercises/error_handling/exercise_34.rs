@@ -0,0 +1,773 @@
+//! Exercise 34: Layered Configuration - Defaults, file, env and CLI
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Merge configuration layers with a clear precedence order
+//! - Remember which layer each value came from
+//! - Report every problem at once instead of stopping at the first
+//! - Keep loading testable by injecting files, env maps and arguments
+//!
+//! Exercise 18 parses one flat `HashMap`. Real tools read built-in defaults,
+//! then an INI/TOML-style file, then `APP_*` environment variables, then
+//! `--key=value` arguments, each overriding the one before. Every value keeps
+//! its `Layer`, so an error such as `env APP_SERVER__PORT: invalid value
+//! "http" for server.port` points at the place to fix. Problems are
+//! collected in an `ErrorAccumulator`, the type exercise 30 asks you to
+//! write, and returned together.
+
+use error_derive::Error;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Where a configuration value came from, lowest precedence first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Layer {
+    Defaults,
+    File { path: PathBuf, line: usize },
+    Env { var: String },
+    Cli { arg: String },
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Layer::Defaults => write!(f, "defaults"),
+            Layer::File { path, line } => write!(f, "file {}:{}", path.display(), line),
+            Layer::Env { var } => write!(f, "env {var}"),
+            Layer::Cli { arg } => write!(f, "cli {arg}"),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("cannot read {path:?}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error("{layer}: {message}")]
+    Syntax { layer: Layer, message: String },
+    #[error("{layer}: invalid value {value:?} for {key}: {reason}")]
+    InvalidValue {
+        layer: Layer,
+        key: String,
+        value: String,
+        reason: String,
+    },
+    #[error("{layer}: unknown key {key}")]
+    UnknownKey { layer: Layer, key: String },
+    #[error("missing required key {key}")]
+    Missing { key: String },
+    #[error("{type_name} could not be built from the settings")]
+    Rejected { type_name: &'static str },
+}
+
+impl ConfigError {
+    /// The key the error is about, if it is about one.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            ConfigError::InvalidValue { key, .. }
+            | ConfigError::UnknownKey { key, .. }
+            | ConfigError::Missing { key } => Some(key),
+            ConfigError::Io { .. } | ConfigError::Syntax { .. } | ConfigError::Rejected { .. } => {
+                None
+            }
+        }
+    }
+
+    pub fn layer(&self) -> Option<&Layer> {
+        match self {
+            ConfigError::Syntax { layer, .. }
+            | ConfigError::InvalidValue { layer, .. }
+            | ConfigError::UnknownKey { layer, .. } => Some(layer),
+            ConfigError::Io { .. } | ConfigError::Missing { .. } | ConfigError::Rejected { .. } => {
+                None
+            }
+        }
+    }
+}
+
+/// Collects errors so that one pass can report all of them.
+#[derive(Debug)]
+pub struct ErrorAccumulator<E> {
+    errors: Vec<E>,
+}
+
+impl<E> Default for ErrorAccumulator<E> {
+    fn default() -> Self {
+        ErrorAccumulator { errors: Vec::new() }
+    }
+}
+
+impl<E> ErrorAccumulator<E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, error: E) {
+        self.errors.push(error);
+    }
+
+    pub fn extend(&mut self, errors: impl IntoIterator<Item = E>) {
+        self.errors.extend(errors);
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    pub fn count(&self) -> usize {
+        self.errors.len()
+    }
+
+    pub fn errors(&self) -> &[E] {
+        &self.errors
+    }
+
+    /// `Ok(value)` if nothing was recorded, otherwise every error.
+    pub fn into_result<T>(self, value: T) -> Result<T, Vec<E>> {
+        if self.errors.is_empty() {
+            Ok(value)
+        } else {
+            Err(self.errors)
+        }
+    }
+}
+
+/// Merged raw values, each tagged with the layer that set it last.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    values: BTreeMap<String, (String, Layer)>,
+}
+
+impl Settings {
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>, layer: Layer) {
+        self.values.insert(key.into(), (value.into(), layer));
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|(value, _)| value.as_str())
+    }
+
+    pub fn layer_of(&self, key: &str) -> Option<&Layer> {
+        self.values.get(key).map(|(_, layer)| layer)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.values.keys().map(String::as_str)
+    }
+}
+
+/// Typed access to `Settings` that records problems instead of failing.
+pub struct SettingsReader<'a> {
+    settings: &'a Settings,
+    errors: ErrorAccumulator<ConfigError>,
+    used: HashSet<String>,
+}
+
+impl<'a> SettingsReader<'a> {
+    pub fn new(settings: &'a Settings) -> Self {
+        SettingsReader {
+            settings,
+            errors: ErrorAccumulator::new(),
+            used: HashSet::new(),
+        }
+    }
+
+    /// Parses `key`, recording `Missing` if no layer set it.
+    pub fn required<T: FromStr>(&mut self, key: &str) -> Option<T>
+    where
+        T::Err: fmt::Display,
+    {
+        if self.settings.get(key).is_none() {
+            self.errors.add(ConfigError::Missing { key: key.into() });
+            self.used.insert(key.into());
+            return None;
+        }
+        self.optional(key)
+    }
+
+    /// Parses `key` if some layer set it.
+    pub fn optional<T: FromStr>(&mut self, key: &str) -> Option<T>
+    where
+        T::Err: fmt::Display,
+    {
+        self.used.insert(key.into());
+        let (raw, layer) = self.settings.values.get(key)?;
+        match raw.parse() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.errors.add(ConfigError::InvalidValue {
+                    layer: layer.clone(),
+                    key: key.into(),
+                    value: raw.clone(),
+                    reason: e.to_string(),
+                });
+                None
+            }
+        }
+    }
+
+    /// Records an `InvalidValue` for `key` unless `ok` holds.
+    pub fn check(&mut self, key: &str, ok: bool, reason: impl Into<String>) {
+        if ok {
+            return;
+        }
+        if let Some((raw, layer)) = self.settings.values.get(key) {
+            self.errors.add(ConfigError::InvalidValue {
+                layer: layer.clone(),
+                key: key.into(),
+                value: raw.clone(),
+                reason: reason.into(),
+            });
+        }
+    }
+
+    pub fn errors(&self) -> &[ConfigError] {
+        self.errors.errors()
+    }
+
+    /// Every problem found, plus an `UnknownKey` for each value nobody read.
+    ///
+    /// Unread values from the env layer are ignored: the process environment
+    /// is shared with other tools, and a prefix such as `APP_` matches their
+    /// variables too.
+    pub fn finish<T>(mut self, value: Option<T>) -> Result<T, Vec<ConfigError>> {
+        for (key, (_, layer)) in &self.settings.values {
+            if !self.used.contains(key) && !matches!(layer, Layer::Env { .. }) {
+                self.errors.add(ConfigError::UnknownKey {
+                    layer: layer.clone(),
+                    key: key.clone(),
+                });
+            }
+        }
+        match (value, self.errors.into_result(())) {
+            (Some(value), Ok(())) => Ok(value),
+            (_, Err(errors)) => Err(errors),
+            // `from_settings` gave up without recording why.
+            (None, Ok(())) => Err(vec![ConfigError::Rejected {
+                type_name: std::any::type_name::<T>(),
+            }]),
+        }
+    }
+}
+
+/// A configuration struct that can be built from merged settings.
+pub trait FromSettings: Sized {
+    /// The built-in layer.
+    fn defaults() -> Vec<(&'static str, &'static str)>;
+
+    /// Reads every field, returning `None` if any of them failed. Problems
+    /// go to `reader` so they can all be reported together.
+    fn from_settings(reader: &mut SettingsReader<'_>) -> Option<Self>;
+}
+
+/// Collects the layers and merges them in precedence order.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigLoader {
+    file: Option<(PathBuf, bool)>,
+    env: HashMap<String, String>,
+    env_prefix: String,
+    args: Vec<String>,
+}
+
+impl ConfigLoader {
+    pub fn new() -> Self {
+        ConfigLoader {
+            env_prefix: "APP_".into(),
+            ..Self::default()
+        }
+    }
+
+    /// A file that must exist.
+    pub fn file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file = Some((path.into(), true));
+        self
+    }
+
+    /// A file that is skipped if it does not exist.
+    pub fn optional_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file = Some((path.into(), false));
+        self
+    }
+
+    /// Environment variables to read, e.g. `std::env::vars().collect()`.
+    pub fn env(mut self, vars: HashMap<String, String>) -> Self {
+        self.env = vars;
+        self
+    }
+
+    /// Only variables starting with `prefix` are read (default `APP_`).
+    pub fn env_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.env_prefix = prefix.into();
+        self
+    }
+
+    /// Command-line arguments, without the program name.
+    pub fn args<I: IntoIterator<Item = S>, S: Into<String>>(mut self, args: I) -> Self {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Merges defaults < file < env < CLI.
+    pub fn merge(
+        &self,
+        defaults: &[(&str, &str)],
+        errors: &mut ErrorAccumulator<ConfigError>,
+    ) -> Settings {
+        let mut settings = Settings::default();
+        for (key, value) in defaults {
+            settings.set(*key, *value, Layer::Defaults);
+        }
+        if let Some((path, required)) = &self.file {
+            match std::fs::read_to_string(path) {
+                Ok(text) => parse_file(&text, path, &mut settings, errors),
+                Err(e) if e.kind() == io::ErrorKind::NotFound && !required => {}
+                Err(source) => errors.add(ConfigError::Io {
+                    path: path.clone(),
+                    source,
+                }),
+            }
+        }
+        let mut env: Vec<_> = self
+            .env
+            .iter()
+            .filter_map(|(var, value)| Some((var, var.strip_prefix(&self.env_prefix)?, value)))
+            .collect();
+        env.sort();
+        for (var, rest, value) in env {
+            // APP_SERVER__PORT -> server.port
+            let key = rest.to_lowercase().replace("__", ".");
+            settings.set(key, value.clone(), Layer::Env { var: var.clone() });
+        }
+        parse_args(&self.args, &mut settings, errors);
+        settings
+    }
+
+    /// Loads `T`, returning every problem from every layer at once.
+    pub fn load<T: FromSettings>(&self) -> Result<T, Vec<ConfigError>> {
+        let mut layer_errors = ErrorAccumulator::new();
+        let settings = self.merge(&T::defaults(), &mut layer_errors);
+        let mut reader = SettingsReader::new(&settings);
+        let value = T::from_settings(&mut reader);
+        let result = reader.finish(value);
+        if !layer_errors.has_errors() {
+            return result;
+        }
+        if let Err(errors) = result {
+            layer_errors.extend(errors);
+        }
+        Err(layer_errors.into_result(()).unwrap_err())
+    }
+}
+
+/// Parses `[section]` headers and `key = value` lines. `#` and `;` start
+/// comments, and values may be wrapped in double quotes.
+fn parse_file(
+    text: &str,
+    path: &Path,
+    settings: &mut Settings,
+    errors: &mut ErrorAccumulator<ConfigError>,
+) {
+    let mut section = String::new();
+    for (i, line) in text.lines().enumerate() {
+        let layer = Layer::File {
+            path: path.to_path_buf(),
+            line: i + 1,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            match name.strip_suffix(']') {
+                Some(name) if !name.trim().is_empty() => section = name.trim().to_string(),
+                _ => errors.add(ConfigError::Syntax {
+                    layer,
+                    message: format!("malformed section header {line:?}"),
+                }),
+            }
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            errors.add(ConfigError::Syntax {
+                layer,
+                message: format!("expected `key = value`, got {line:?}"),
+            });
+            continue;
+        };
+        let key = key.trim();
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        let key = if section.is_empty() {
+            key.to_string()
+        } else {
+            format!("{section}.{key}")
+        };
+        settings.set(key, value, layer);
+    }
+}
+
+/// Parses `--key=value` and `--key value`.
+fn parse_args(
+    args: &[String],
+    settings: &mut Settings,
+    errors: &mut ErrorAccumulator<ConfigError>,
+) {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let layer = Layer::Cli { arg: arg.clone() };
+        let Some(flag) = arg.strip_prefix("--") else {
+            errors.add(ConfigError::Syntax {
+                layer,
+                message: "expected --key=value".into(),
+            });
+            continue;
+        };
+        let (key, value) = match flag.split_once('=') {
+            Some((key, value)) => (key, value.to_string()),
+            None => match args.next() {
+                Some(value) => (flag, value.clone()),
+                None => {
+                    errors.add(ConfigError::Syntax {
+                        layer,
+                        message: format!("missing value for --{flag}"),
+                    });
+                    continue;
+                }
+            },
+        };
+        settings.set(key, value, layer);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub timeout: u32,
+    pub max_connections: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    pub level: String,
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppConfig {
+    pub server: ServerConfig,
+    pub log: LogConfig,
+    pub database_url: String,
+}
+
+const LOG_LEVELS: [&str; 5] = ["trace", "debug", "info", "warn", "error"];
+
+impl FromSettings for AppConfig {
+    fn defaults() -> Vec<(&'static str, &'static str)> {
+        vec![
+            ("server.host", "localhost"),
+            ("server.port", "8080"),
+            ("server.timeout", "30"),
+            ("server.max_connections", "100"),
+            ("log.level", "info"),
+        ]
+    }
+
+    fn from_settings(r: &mut SettingsReader<'_>) -> Option<Self> {
+        let host: Option<String> = r.required("server.host");
+        let port: Option<u16> = r.required("server.port");
+        let timeout: Option<u32> = r.required("server.timeout");
+        let max_connections: Option<u32> = r.required("server.max_connections");
+        let level: Option<String> = r.required("log.level");
+        let file: Option<PathBuf> = r.optional("log.file");
+        let database_url: Option<String> = r.required("database.url");
+
+        r.check(
+            "server.host",
+            host.as_ref().is_none_or(|h| !h.is_empty()),
+            "must not be empty",
+        );
+        r.check("server.port", port != Some(0), "must not be 0");
+        r.check(
+            "server.timeout",
+            timeout.is_none_or(|t| (1..=3600).contains(&t)),
+            "must be between 1 and 3600 seconds",
+        );
+        r.check(
+            "server.max_connections",
+            max_connections != Some(0),
+            "must be at least 1",
+        );
+        r.check(
+            "log.level",
+            level.as_deref().is_none_or(|l| LOG_LEVELS.contains(&l)),
+            format!("must be one of {}", LOG_LEVELS.join(", ")),
+        );
+
+        if !r.errors().is_empty() {
+            return None;
+        }
+        Some(AppConfig {
+            server: ServerConfig {
+                host: host?,
+                port: port?,
+                timeout: timeout?,
+                max_connections: max_connections?,
+            },
+            log: LogConfig {
+                level: level?,
+                file,
+            },
+            database_url: database_url?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A fresh directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "exercise_34_{}_{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        /// Writes `contents` to `name` inside the directory.
+        fn file(&self, name: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn env(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_defaults_only() {
+        let config: AppConfig = ConfigLoader::new()
+            .args(["--database.url=postgres://db"])
+            .load()
+            .unwrap();
+        assert_eq!(config.server.host, "localhost");
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.log.level, "info");
+        assert_eq!(config.log.file, None);
+        assert_eq!(config.database_url, "postgres://db");
+    }
+
+    #[test]
+    fn test_layer_precedence() {
+        let dir = TempDir::new();
+        let file = dir.file(
+            "app.ini",
+            "# app config\nport_unused_at_top = 1\n[server]\nhost = \"file.example\"\nport = 9000\ntimeout = 10\n\n[database]\nurl = postgres://file\n",
+        );
+        let loader = ConfigLoader::new()
+            .file(&file)
+            .env(env(&[
+                ("APP_SERVER__PORT", "9100"),
+                ("APP_LOG__LEVEL", "debug"),
+                ("HOME", "/root"),
+            ]))
+            .args(["--server.port", "9200", "--log.file=/var/log/app.log"]);
+        let mut errors = ErrorAccumulator::new();
+        let settings = loader.merge(&AppConfig::defaults(), &mut errors);
+        assert!(!errors.has_errors());
+        assert_eq!(settings.get("server.host"), Some("file.example"));
+        assert_eq!(settings.get("server.port"), Some("9200"));
+        assert_eq!(settings.get("log.level"), Some("debug"));
+        assert_eq!(
+            settings.layer_of("server.max_connections"),
+            Some(&Layer::Defaults)
+        );
+        assert_eq!(
+            settings.layer_of("server.timeout"),
+            Some(&Layer::File {
+                path: file.clone(),
+                line: 6
+            })
+        );
+        assert_eq!(
+            settings.layer_of("log.level"),
+            Some(&Layer::Env {
+                var: "APP_LOG__LEVEL".into()
+            })
+        );
+
+        // The stray top-level key is reported, with its file and line.
+        let errors = loader.load::<AppConfig>().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string(),
+            format!("file {}:2: unknown key port_unused_at_top", file.display())
+        );
+    }
+
+    #[test]
+    fn test_every_problem_reported_with_layer_and_key() {
+        let dir = TempDir::new();
+        let file = dir.file(
+            "app.ini",
+            "[server]\ntimeout = 0\nthis line is broken\n[log]\nlevel = loud\n",
+        );
+        let errors = ConfigLoader::new()
+            .file(&file)
+            .env(env(&[("APP_SERVER__PORT", "http")]))
+            .args(["--server.max_connections=0", "stray"])
+            .load::<AppConfig>()
+            .unwrap_err();
+
+        let rendered: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        let keys: HashSet<&str> = errors.iter().filter_map(|e| e.key()).collect();
+        assert_eq!(
+            keys,
+            HashSet::from([
+                "server.port",
+                "server.timeout",
+                "server.max_connections",
+                "log.level",
+                "database.url"
+            ]),
+            "{rendered:#?}"
+        );
+        assert_eq!(errors.len(), 7, "{rendered:#?}");
+        assert!(rendered.contains(
+            &"env APP_SERVER__PORT: invalid value \"http\" for server.port: invalid digit found in string"
+                .to_string()
+        ));
+        assert!(rendered.contains(&"missing required key database.url".to_string()));
+        assert!(rendered.contains(&"cli stray: expected --key=value".to_string()));
+        assert!(
+            rendered
+                .iter()
+                .any(|e| e.ends_with(":3: expected `key = value`, got \"this line is broken\""))
+        );
+        let level = errors
+            .iter()
+            .find(|e| e.key() == Some("log.level"))
+            .unwrap();
+        assert!(matches!(level.layer(), Some(Layer::File { line: 5, .. })));
+        assert!(level.to_string().contains("must be one of trace, debug"));
+    }
+
+    #[test]
+    fn test_unknown_env_vars_are_ignored() {
+        let config: AppConfig = ConfigLoader::new()
+            .env(env(&[
+                ("APP_DATABASE__URL", "sqlite://x"),
+                ("APP_ENGINE_PATH", "/opt/engine"),
+                ("APP_LOG__COLOUR", "always"),
+            ]))
+            .load()
+            .unwrap();
+        assert_eq!(config.database_url, "sqlite://x");
+
+        let errors = ConfigLoader::new()
+            .env(env(&[("APP_DATABASE__URL", "sqlite://x")]))
+            .args(["--log.colour=always"])
+            .load::<AppConfig>()
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string(),
+            "cli --log.colour=always: unknown key log.colour"
+        );
+    }
+
+    #[test]
+    fn test_rejection_without_error_is_reported() {
+        struct Never;
+        impl FromSettings for Never {
+            fn defaults() -> Vec<(&'static str, &'static str)> {
+                Vec::new()
+            }
+            fn from_settings(_: &mut SettingsReader<'_>) -> Option<Self> {
+                None
+            }
+        }
+        let errors = ConfigLoader::new().load::<Never>().err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], ConfigError::Rejected { .. }));
+        assert!(
+            errors[0]
+                .to_string()
+                .ends_with("Never could not be built from the settings")
+        );
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let path = std::env::temp_dir().join("exercise_34_does_not_exist.ini");
+        let errors = ConfigLoader::new()
+            .file(&path)
+            .load::<AppConfig>()
+            .unwrap_err();
+        assert!(matches!(errors[0], ConfigError::Io { .. }));
+        assert_eq!(errors.len(), 2); // plus the missing database.url
+
+        let config: AppConfig = ConfigLoader::new()
+            .optional_file(&path)
+            .env(env(&[("APP_DATABASE__URL", "sqlite://x")]))
+            .load()
+            .unwrap();
+        assert_eq!(config.database_url, "sqlite://x");
+    }
+
+    #[test]
+    fn test_custom_prefix_and_struct() {
+        #[derive(Debug)]
+        struct Worker {
+            threads: usize,
+            name: Option<String>,
+        }
+        impl FromSettings for Worker {
+            fn defaults() -> Vec<(&'static str, &'static str)> {
+                vec![("threads", "4")]
+            }
+            fn from_settings(r: &mut SettingsReader<'_>) -> Option<Self> {
+                let threads = r.required("threads");
+                let name = r.optional("name");
+                Some(Worker {
+                    threads: threads?,
+                    name,
+                })
+            }
+        }
+        let worker: Worker = ConfigLoader::new()
+            .env_prefix("WORKER_")
+            .env(env(&[("WORKER_THREADS", "16"), ("APP_THREADS", "1")]))
+            .load()
+            .unwrap();
+        assert_eq!(worker.threads, 16);
+        assert_eq!(worker.name, None);
+    }
+}
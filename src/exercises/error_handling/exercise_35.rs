@@ -0,0 +1,660 @@
+//! Exercise 35: Declarative Validation - Composable rules with field paths
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Express validation as small rules that compose with `and`, `or`, `not`
+//! - Validate nested structs and collections with paths like `user.tags[2]`
+//! - Accumulate every violation instead of stopping at the first
+//! - Wrap existing validators instead of rewriting them
+//!
+//! Exercise 20 validates one struct with hand-written checks. Here a rule is
+//! a value implementing the `Rule` trait: it appends `Violation`s for a value
+//! at a `FieldPath`. `Validator<T>` is itself a rule made of per-field rules,
+//! so nested structs nest validators, and `adapt` turns a `generics`
+//! exercise 18 `Validator` into a rule. The built-in `email`, `username` and
+//! `password` rules enforce the requirements of exercises 12, 7 and 14.
+
+use crate::exercises::generics::exercise_18::Validator as GenericValidator;
+use error_derive::Error;
+use std::borrow::Borrow;
+use std::fmt;
+use std::marker::PhantomData;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Field(String),
+    Index(usize),
+}
+
+/// Location of a value inside the validated root, e.g. `user.address.zip`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FieldPath(Vec<Segment>);
+
+impl FieldPath {
+    pub fn root() -> Self {
+        Self::default()
+    }
+
+    pub fn field(&self, name: &str) -> Self {
+        let mut path = self.clone();
+        path.0.push(Segment::Field(name.to_string()));
+        path
+    }
+
+    pub fn index(&self, index: usize) -> Self {
+        let mut path = self.clone();
+        path.0.push(Segment::Index(index));
+        path
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for FieldPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("(root)");
+        }
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                Segment::Field(name) if i == 0 => f.write_str(name)?,
+                Segment::Field(name) => write!(f, ".{name}")?,
+                Segment::Index(index) => write!(f, "[{index}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One failed rule.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{path}: {message}")]
+pub struct Violation {
+    pub path: FieldPath,
+    /// Stable identifier for the rule, e.g. `"email"` or `"min_len"`.
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Violation {
+    pub fn new(path: &FieldPath, code: &'static str, message: impl Into<String>) -> Self {
+        Violation {
+            path: path.clone(),
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Collects violations so that one pass can report all of them.
+#[derive(Debug)]
+pub struct ErrorAccumulator<E> {
+    errors: Vec<E>,
+}
+
+impl<E> Default for ErrorAccumulator<E> {
+    fn default() -> Self {
+        ErrorAccumulator { errors: Vec::new() }
+    }
+}
+
+impl<E> ErrorAccumulator<E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, error: E) {
+        self.errors.push(error);
+    }
+
+    pub fn extend(&mut self, errors: impl IntoIterator<Item = E>) {
+        self.errors.extend(errors);
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    pub fn count(&self) -> usize {
+        self.errors.len()
+    }
+
+    pub fn errors(&self) -> &[E] {
+        &self.errors
+    }
+
+    /// `Ok(value)` if nothing was recorded, otherwise every error.
+    pub fn into_result<T>(self, value: T) -> Result<T, Vec<E>> {
+        if self.errors.is_empty() {
+            Ok(value)
+        } else {
+            Err(self.errors)
+        }
+    }
+}
+
+pub type Violations = ErrorAccumulator<Violation>;
+
+/// Checks a value, adding a `Violation` for each problem found.
+pub trait Rule<T: ?Sized> {
+    fn check(&self, value: &T, path: &FieldPath, out: &mut Violations);
+
+    /// Both rules must pass; violations from both are reported.
+    fn and<R: Rule<T>>(self, other: R) -> And<Self, R>
+    where
+        Self: Sized,
+    {
+        And(self, other)
+    }
+
+    /// Either rule may pass; if neither does, both sets are reported.
+    fn or<R: Rule<T>>(self, other: R) -> Or<Self, R>
+    where
+        Self: Sized,
+    {
+        Or(self, other)
+    }
+
+    /// Passes when this rule fails, reporting `message` otherwise.
+    fn not(self, code: &'static str, message: impl Into<String>) -> Not<Self>
+    where
+        Self: Sized,
+    {
+        Not {
+            rule: self,
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Applies this rule to every element of a slice or `Vec` whose
+    /// elements borrow as `T`, e.g. a `Rule<str>` over `Vec<String>`.
+    fn each(self) -> Each<Self, T>
+    where
+        Self: Sized,
+    {
+        Each(self, PhantomData)
+    }
+}
+
+impl<T: ?Sized> Rule<T> for Box<dyn Rule<T>> {
+    fn check(&self, value: &T, path: &FieldPath, out: &mut Violations) {
+        (**self).check(value, path, out)
+    }
+}
+
+fn check_into<T: ?Sized>(rule: &impl Rule<T>, value: &T, path: &FieldPath) -> Violations {
+    let mut out = Violations::new();
+    rule.check(value, path, &mut out);
+    out
+}
+
+pub struct And<A, B>(A, B);
+
+impl<T: ?Sized, A: Rule<T>, B: Rule<T>> Rule<T> for And<A, B> {
+    fn check(&self, value: &T, path: &FieldPath, out: &mut Violations) {
+        self.0.check(value, path, out);
+        self.1.check(value, path, out);
+    }
+}
+
+pub struct Or<A, B>(A, B);
+
+impl<T: ?Sized, A: Rule<T>, B: Rule<T>> Rule<T> for Or<A, B> {
+    fn check(&self, value: &T, path: &FieldPath, out: &mut Violations) {
+        let left = check_into(&self.0, value, path);
+        if !left.has_errors() {
+            return;
+        }
+        let right = check_into(&self.1, value, path);
+        if right.has_errors() {
+            out.extend(left.errors().to_vec());
+            out.extend(right.errors().to_vec());
+        }
+    }
+}
+
+pub struct Not<A> {
+    rule: A,
+    code: &'static str,
+    message: String,
+}
+
+impl<T: ?Sized, A: Rule<T>> Rule<T> for Not<A> {
+    fn check(&self, value: &T, path: &FieldPath, out: &mut Violations) {
+        if !check_into(&self.rule, value, path).has_errors() {
+            out.add(Violation::new(path, self.code, self.message.clone()));
+        }
+    }
+}
+
+/// `U` is the type the inner rule checks; elements only need to borrow as it.
+pub struct Each<A, U: ?Sized>(A, PhantomData<fn(&U)>);
+
+impl<T, U, A> Rule<[T]> for Each<A, U>
+where
+    T: Borrow<U>,
+    U: ?Sized,
+    A: Rule<U>,
+{
+    fn check(&self, values: &[T], path: &FieldPath, out: &mut Violations) {
+        for (i, value) in values.iter().enumerate() {
+            self.0.check(value.borrow(), &path.index(i), out);
+        }
+    }
+}
+
+impl<T, U, A> Rule<Vec<T>> for Each<A, U>
+where
+    T: Borrow<U>,
+    U: ?Sized,
+    A: Rule<U>,
+{
+    fn check(&self, values: &Vec<T>, path: &FieldPath, out: &mut Violations) {
+        Rule::<[T]>::check(self, values.as_slice(), path, out)
+    }
+}
+
+/// A rule from a predicate and the violation to report when it is false.
+pub struct Check<F> {
+    predicate: F,
+    code: &'static str,
+    message: String,
+}
+
+pub fn check<T: ?Sized, F: Fn(&T) -> bool>(
+    code: &'static str,
+    message: impl Into<String>,
+    predicate: F,
+) -> Check<F> {
+    Check {
+        predicate,
+        code,
+        message: message.into(),
+    }
+}
+
+impl<T: ?Sized, F: Fn(&T) -> bool> Rule<T> for Check<F> {
+    fn check(&self, value: &T, path: &FieldPath, out: &mut Violations) {
+        if !(self.predicate)(value) {
+            out.add(Violation::new(path, self.code, self.message.clone()));
+        }
+    }
+}
+
+/// A rule from a function that reports any number of violations.
+pub struct FnRule<F>(F);
+
+pub fn rule_fn<T: ?Sized, F: Fn(&T, &FieldPath, &mut Violations)>(f: F) -> FnRule<F> {
+    FnRule(f)
+}
+
+impl<T: ?Sized, F: Fn(&T, &FieldPath, &mut Violations)> Rule<T> for FnRule<F> {
+    fn check(&self, value: &T, path: &FieldPath, out: &mut Violations) {
+        (self.0)(value, path, out)
+    }
+}
+
+/// Adapts a `generics` exercise 18 validator into a rule.
+pub struct Adapt<V> {
+    validator: V,
+    code: &'static str,
+}
+
+pub fn adapt<V>(code: &'static str, validator: V) -> Adapt<V> {
+    Adapt { validator, code }
+}
+
+impl<T, V> Rule<T> for Adapt<V>
+where
+    V: GenericValidator<T>,
+    V::Error: fmt::Display,
+{
+    fn check(&self, value: &T, path: &FieldPath, out: &mut Violations) {
+        if let Err(e) = self.validator.validate(value) {
+            out.add(Violation::new(path, self.code, e.to_string()));
+        }
+    }
+}
+
+pub fn non_empty() -> impl Rule<str> {
+    check("required", "must not be empty", |s: &str| {
+        !s.trim().is_empty()
+    })
+}
+
+pub fn min_len(min: usize) -> impl Rule<str> {
+    check(
+        "min_len",
+        format!("must be at least {min} characters"),
+        move |s: &str| s.chars().count() >= min,
+    )
+}
+
+pub fn max_len(max: usize) -> impl Rule<str> {
+    check(
+        "max_len",
+        format!("must be at most {max} characters"),
+        move |s: &str| s.chars().count() <= max,
+    )
+}
+
+pub fn range<T: PartialOrd + fmt::Display + 'static>(min: T, max: T) -> impl Rule<T> {
+    let message = format!("must be between {min} and {max}");
+    check("range", message, move |v: &T| *v >= min && *v <= max)
+}
+
+/// `username@domain.tld`, as in exercise 12.
+pub fn email() -> impl Rule<str> {
+    rule_fn(|s: &str, path: &FieldPath, out: &mut Violations| {
+        if let Some(message) = email_problem(s) {
+            out.add(Violation::new(path, "email", message));
+        }
+    })
+}
+
+/// 3-20 ASCII letters and digits, as in exercise 7.
+pub fn username() -> impl Rule<str> {
+    rule_fn(|s: &str, path: &FieldPath, out: &mut Violations| {
+        if let Some(message) = username_problem(s) {
+            out.add(Violation::new(path, "username", message));
+        }
+    })
+}
+
+/// Exercise 14's password requirements, one violation per unmet one.
+pub fn password() -> impl Rule<str> {
+    rule_fn(|s: &str, path: &FieldPath, out: &mut Violations| {
+        for message in password_problems(s) {
+            out.add(Violation::new(path, "password", message));
+        }
+    })
+}
+
+fn email_problem(email: &str) -> Option<&'static str> {
+    if email.is_empty() {
+        return Some("Email cannot be empty");
+    }
+    let (user, domain) = match email.matches('@').count() {
+        0 => return Some("Email must contain an @ sign"),
+        1 => email.split_once('@')?,
+        _ => return Some("Email must contain only one @ sign"),
+    };
+    if user.is_empty() {
+        return Some("Email must have a username before @");
+    }
+    if domain.is_empty() {
+        return Some("Email must have a domain after @");
+    }
+    if !domain.contains('.') || domain.split('.').any(str::is_empty) {
+        return Some("Email domain must look like domain.tld");
+    }
+    None
+}
+
+fn username_problem(username: &str) -> Option<&'static str> {
+    let len = username.chars().count();
+    if len == 0 {
+        Some("Username cannot be empty")
+    } else if len < 3 {
+        Some("Username must be at least 3 characters")
+    } else if len > 20 {
+        Some("Username must be at most 20 characters")
+    } else if !username.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Some("Username must be alphanumeric")
+    } else {
+        None
+    }
+}
+
+fn password_problems(password: &str) -> Vec<&'static str> {
+    let len = password.chars().count();
+    let requirements = [
+        (len >= 8, "Password must be at least 8 characters"),
+        (len <= 128, "Password must be at most 128 characters"),
+        (
+            password.chars().any(char::is_uppercase),
+            "Password must contain an uppercase letter",
+        ),
+        (
+            password.chars().any(char::is_lowercase),
+            "Password must contain a lowercase letter",
+        ),
+        (
+            password.chars().any(|c| c.is_ascii_digit()),
+            "Password must contain a digit",
+        ),
+        (
+            !password.chars().all(char::is_alphanumeric),
+            "Password must contain a special character",
+        ),
+    ];
+    requirements
+        .into_iter()
+        .filter(|(met, _)| !met)
+        .map(|(_, message)| message)
+        .collect()
+}
+
+type FieldCheck<T> = Box<dyn Fn(&T, &FieldPath, &mut Violations)>;
+
+/// Rules for the fields of a `T`. Also a `Rule<T>`, so validators nest.
+pub struct Validator<T> {
+    checks: Vec<FieldCheck<T>>,
+}
+
+impl<T> Default for Validator<T> {
+    fn default() -> Self {
+        Validator { checks: Vec::new() }
+    }
+}
+
+impl<T: 'static> Validator<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks the field returned by `get`, reported under `name`.
+    pub fn field<U, G, R>(mut self, name: &'static str, get: G, rule: R) -> Self
+    where
+        U: ?Sized,
+        G: Fn(&T) -> &U + 'static,
+        R: Rule<U> + 'static,
+    {
+        self.checks.push(Box::new(move |value, path, out| {
+            rule.check(get(value), &path.field(name), out)
+        }));
+        self
+    }
+
+    /// Checks the whole value, e.g. for rules that compare fields.
+    pub fn rule<R: Rule<T> + 'static>(mut self, rule: R) -> Self {
+        self.checks.push(Box::new(move |value, path, out| {
+            rule.check(value, path, out)
+        }));
+        self
+    }
+
+    /// Runs every rule, returning all violations if any failed.
+    pub fn validate(&self, value: &T) -> Result<(), Vec<Violation>> {
+        check_into(self, value, &FieldPath::root()).into_result(())
+    }
+}
+
+impl<T> Rule<T> for Validator<T> {
+    fn check(&self, value: &T, path: &FieldPath, out: &mut Violations) {
+        for check in &self.checks {
+            check(value, path, out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Address {
+        street: String,
+        zip: String,
+    }
+
+    #[derive(Debug)]
+    struct User {
+        username: String,
+        email: String,
+        password: String,
+        age: u32,
+        address: Address,
+        tags: Vec<String>,
+    }
+
+    fn valid_user() -> User {
+        User {
+            username: "alice42".into(),
+            email: "alice@example.com".into(),
+            password: "Secur3!pass".into(),
+            age: 30,
+            address: Address {
+                street: "1 Main St".into(),
+                zip: "12345".into(),
+            },
+            tags: vec!["admin".into(), "ops".into()],
+        }
+    }
+
+    fn user_validator() -> Validator<User> {
+        let zip = check("zip", "must be 5 digits", |z: &str| {
+            z.len() == 5 && z.chars().all(|c| c.is_ascii_digit())
+        });
+        let address = Validator::new()
+            .field("street", |a: &Address| a.street.as_str(), non_empty())
+            .field("zip", |a: &Address| a.zip.as_str(), zip);
+        Validator::new()
+            .field("username", |u: &User| u.username.as_str(), username())
+            .field("email", |u: &User| u.email.as_str(), email())
+            .field("password", |u: &User| u.password.as_str(), password())
+            .field("age", |u: &User| &u.age, range(13, 130))
+            .field("address", |u: &User| &u.address, address)
+            .field(
+                "tags",
+                |u: &User| &u.tags,
+                non_empty().and(max_len(8)).each(),
+            )
+            .rule(check(
+                "password_not_username",
+                "password must not contain the username",
+                |u: &User| {
+                    !u.password
+                        .to_lowercase()
+                        .contains(&u.username.to_lowercase())
+                },
+            ))
+    }
+
+    fn paths(violations: &[Violation]) -> Vec<String> {
+        violations.iter().map(|v| v.path.to_string()).collect()
+    }
+
+    #[test]
+    fn test_valid_value_passes() {
+        assert_eq!(user_validator().validate(&valid_user()), Ok(()));
+    }
+
+    #[test]
+    fn test_accumulates_all_violations_with_nested_paths() {
+        let mut user = valid_user();
+        user.username = "al".into();
+        user.email = "alice@localhost".into();
+        user.age = 7;
+        user.address.zip = "12a".into();
+        user.tags[1] = "much-too-long".into();
+        let violations = user_validator().validate(&user).unwrap_err();
+        assert_eq!(
+            paths(&violations),
+            ["username", "email", "age", "address.zip", "tags[1]"]
+        );
+        assert_eq!(
+            violations[0].to_string(),
+            "username: Username must be at least 3 characters"
+        );
+        assert_eq!(violations[1].code, "email");
+        assert_eq!(violations[3].to_string(), "address.zip: must be 5 digits");
+    }
+
+    #[test]
+    fn test_password_rule_reports_each_requirement() {
+        let mut user = valid_user();
+        user.password = "alice42".into();
+        let violations = user_validator().validate(&user).unwrap_err();
+        let messages: Vec<&str> = violations.iter().map(|v| v.message.as_str()).collect();
+        assert!(messages.contains(&"Password must be at least 8 characters"));
+        assert!(messages.contains(&"Password must contain an uppercase letter"));
+        assert!(messages.contains(&"Password must contain a special character"));
+        assert_eq!(violations.last().unwrap().path, FieldPath::root());
+        assert_eq!(violations.last().unwrap().code, "password_not_username");
+    }
+
+    #[test]
+    fn test_and_or_not() {
+        let root = FieldPath::root();
+        let id = email().or(username());
+        assert!(!check_into(&id, "bob@example.com", &root).has_errors());
+        assert!(!check_into(&id, "bob", &root).has_errors());
+        let both = check_into(&id, "b@", &root);
+        assert_eq!(both.count(), 2);
+        assert_eq!(both.errors()[0].code, "email");
+        assert_eq!(both.errors()[1].code, "username");
+
+        let not_admin =
+            check("admin", "", |s: &str| s == "admin").not("reserved", "name is reserved");
+        let name = username().and(not_admin);
+        assert!(!check_into(&name, "bob", &root).has_errors());
+        let reserved = check_into(&name, "admin", &root);
+        assert_eq!(reserved.errors()[0].message, "name is reserved");
+
+        let short_and_bad = check_into(&min_len(5).and(username()), "a!", &root);
+        assert_eq!(short_and_bad.count(), 2);
+    }
+
+    #[test]
+    fn test_each_and_paths() {
+        let rule = range(1, 10).each();
+        let out = check_into(
+            &rule,
+            &vec![3, 0, 5, 11][..],
+            &FieldPath::root().field("scores"),
+        );
+        assert_eq!(paths(out.errors()), ["scores[1]", "scores[3]"]);
+        assert_eq!(out.errors()[0].message, "must be between 1 and 10");
+
+        let grid: Vec<Vec<i32>> = vec![vec![1, 2], vec![3, 99]];
+        let nested = Rule::<Vec<i32>>::each(range(0, 9).each());
+        let out = check_into(&nested, &grid, &FieldPath::root().field("grid"));
+        assert_eq!(paths(out.errors()), ["grid[1][1]"]);
+        assert_eq!(FieldPath::root().to_string(), "(root)");
+    }
+
+    #[test]
+    fn test_adapts_generic_validator() {
+        struct Even;
+        impl GenericValidator<i32> for Even {
+            type Error = String;
+            fn validate(&self, value: &i32) -> Result<(), String> {
+                if value % 2 == 0 {
+                    Ok(())
+                } else {
+                    Err(format!("{value} is odd"))
+                }
+            }
+        }
+        let validator =
+            Validator::new().field("n", |n: &i32| n, adapt("even", Even).and(range(0, 100)));
+        assert_eq!(validator.validate(&4), Ok(()));
+        let violations = validator.validate(&101).unwrap_err();
+        assert_eq!(violations.len(), 2);
+        assert_eq!(violations[0].to_string(), "n: 101 is odd");
+    }
+}
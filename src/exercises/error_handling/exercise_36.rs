@@ -0,0 +1,604 @@
+//! Exercise 36: Recovery Policies - Retry, fallback, cache, degrade, default
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Describe error recovery as data instead of nested `match`es
+//! - Compose retries, fallback sources, a last-good cache, degraded values
+//!   and defaults in one chain
+//! - Report which path produced the final value and what failed on the way
+//! - Run the same policy shape over closures and futures
+//!
+//! Exercise 22 hard-codes "try primary, then fallback" and "fetch or
+//! default", and `options_result` exercise 28 hard-codes a list of parsing
+//! strategies. A `RecoveryPolicy` lists the steps once: retry the operation
+//! up to N times, then try each step in the order it was added until one
+//! yields a value. The result says whether the value came from the primary,
+//! a named fallback, the cache, a degraded value or the default.
+
+use error_derive::Error;
+use futures::future::BoxFuture;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Which step produced the value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecoveryPath {
+    /// The operation itself, on the given attempt (1-based).
+    Primary {
+        attempt: u32,
+    },
+    Fallback {
+        name: String,
+    },
+    Cached,
+    Degraded,
+    Default,
+}
+
+/// A value together with how it was obtained.
+#[derive(Debug)]
+pub struct Recovered<T, E> {
+    pub value: T,
+    pub path: RecoveryPath,
+    /// Every failure seen before the value was produced, in order.
+    pub errors: Vec<E>,
+    /// Set when the value came from a `degrade` step.
+    pub warning: Option<String>,
+}
+
+impl<T, E> Recovered<T, E> {
+    /// True if the operation succeeded without any recovery step.
+    pub fn is_primary(&self) -> bool {
+        matches!(self.path, RecoveryPath::Primary { .. })
+    }
+}
+
+/// Every step failed and nothing in the chain always succeeds.
+#[derive(Debug, Error)]
+#[error(
+    "all recovery paths failed: {attempts} attempts of the operation, {fallback_failures} failed fallbacks"
+)]
+pub struct RecoveryError<E: fmt::Debug> {
+    /// How many times the operation itself was tried.
+    pub attempts: u32,
+    /// How many fallback sources were tried and failed.
+    pub fallback_failures: usize,
+    /// The operation's errors followed by the fallbacks' errors.
+    pub errors: Vec<E>,
+}
+
+/// The last successful value, shared between runs and policies.
+#[derive(Debug)]
+pub struct LastGood<T>(Arc<Mutex<Option<T>>>);
+
+impl<T> Clone for LastGood<T> {
+    fn clone(&self) -> Self {
+        LastGood(Arc::clone(&self.0))
+    }
+}
+
+impl<T> Default for LastGood<T> {
+    fn default() -> Self {
+        LastGood(Arc::new(Mutex::new(None)))
+    }
+}
+
+impl<T: Clone> LastGood<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self) -> Option<T> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, value: T) {
+        *self.0.lock().unwrap() = Some(value);
+    }
+}
+
+enum Step<S, T> {
+    Fallback {
+        name: String,
+        source: S,
+    },
+    Cached,
+    Degrade {
+        warning: String,
+        value: Box<dyn Fn() -> T + Send>,
+    },
+    Default(T),
+}
+
+/// The next thing `run` has to do after the retries failed.
+enum Next<'a, S, T> {
+    /// A step that needs no source produced the value.
+    Found((T, RecoveryPath, Option<String>)),
+    /// Call this fallback source.
+    Call { name: String, source: &'a mut S },
+    /// Every step has been tried.
+    Exhausted,
+}
+
+/// A retry count plus an ordered list of recovery steps.
+///
+/// `S` is the kind of fallback source: plain closures for
+/// `RecoveryPolicy`, closures returning futures for `AsyncRecoveryPolicy`.
+pub struct Policy<S, T> {
+    attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    steps: Vec<Step<S, T>>,
+    cache: Option<LastGood<T>>,
+}
+
+pub type SyncSource<T, E> = Box<dyn FnMut() -> Result<T, E> + Send>;
+pub type AsyncSource<T, E> = Box<dyn FnMut() -> BoxFuture<'static, Result<T, E>> + Send>;
+
+pub type RecoveryPolicy<T, E> = Policy<SyncSource<T, E>, T>;
+pub type AsyncRecoveryPolicy<T, E> = Policy<AsyncSource<T, E>, T>;
+
+impl<S, T: Clone> Default for Policy<S, T> {
+    fn default() -> Self {
+        Policy {
+            attempts: 1,
+            backoff: Duration::ZERO,
+            max_backoff: Duration::MAX,
+            steps: Vec::new(),
+            cache: None,
+        }
+    }
+}
+
+impl<S, T: Clone> Policy<S, T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tries the operation up to `attempts` times in total.
+    pub fn retry(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// Waits `initial` before the second attempt, doubling each time after.
+    pub fn backoff(mut self, initial: Duration) -> Self {
+        self.backoff = initial;
+        self
+    }
+
+    /// Caps the wait between attempts at `max`.
+    pub fn max_backoff(mut self, max: Duration) -> Self {
+        self.max_backoff = max;
+        self
+    }
+
+    /// Remembers successful values in `cache` and, at this point in the
+    /// chain, serves the last one.
+    pub fn cached(mut self, cache: &LastGood<T>) -> Self {
+        self.cache = Some(cache.clone());
+        self.steps.push(Step::Cached);
+        self
+    }
+
+    /// Serves `value()` with a warning. Always succeeds.
+    pub fn degrade(
+        mut self,
+        warning: impl Into<String>,
+        value: impl Fn() -> T + Send + 'static,
+    ) -> Self {
+        self.steps.push(Step::Degrade {
+            warning: warning.into(),
+            value: Box::new(value),
+        });
+        self
+    }
+
+    /// Serves `value`. Always succeeds.
+    pub fn default_value(mut self, value: T) -> Self {
+        self.steps.push(Step::Default(value));
+        self
+    }
+
+    fn delay_before(&self, attempt: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(2)))
+            .min(self.max_backoff)
+    }
+
+    /// A value from the operation or a fallback source, which the cache
+    /// remembers.
+    fn succeeded<E>(&self, value: T, path: RecoveryPath, errors: Vec<E>) -> Recovered<T, E> {
+        if let Some(cache) = &self.cache {
+            cache.set(value.clone());
+        }
+        recovered((value, path, None), errors)
+    }
+
+    fn exhausted<E: fmt::Debug>(&self, errors: Vec<E>) -> RecoveryError<E> {
+        RecoveryError {
+            attempts: self.attempts,
+            fallback_failures: errors.len() - self.attempts as usize,
+            errors,
+        }
+    }
+
+    /// Walks the steps from `*next`, resolving the ones that need no source,
+    /// until one produces a value or a fallback source has to be called.
+    fn next_step(&mut self, next: &mut usize) -> Next<'_, S, T> {
+        for step in &mut self.steps[*next..] {
+            *next += 1;
+            let found = match step {
+                Step::Fallback { name, source } => {
+                    return Next::Call {
+                        name: name.clone(),
+                        source,
+                    };
+                }
+                Step::Cached => self
+                    .cache
+                    .as_ref()
+                    .and_then(LastGood::get)
+                    .map(|value| (value, RecoveryPath::Cached, None)),
+                Step::Degrade { warning, value } => {
+                    Some((value(), RecoveryPath::Degraded, Some(warning.clone())))
+                }
+                Step::Default(value) => Some((value.clone(), RecoveryPath::Default, None)),
+            };
+            if let Some(found) = found {
+                return Next::Found(found);
+            }
+        }
+        Next::Exhausted
+    }
+}
+
+fn recovered<T, E>(
+    (value, path, warning): (T, RecoveryPath, Option<String>),
+    errors: Vec<E>,
+) -> Recovered<T, E> {
+    Recovered {
+        value,
+        path,
+        errors,
+        warning,
+    }
+}
+
+impl<T: Clone, E: fmt::Debug> RecoveryPolicy<T, E> {
+    /// Tries `source` if everything before it failed.
+    pub fn fallback(
+        mut self,
+        name: impl Into<String>,
+        source: impl FnMut() -> Result<T, E> + Send + 'static,
+    ) -> Self {
+        self.steps.push(Step::Fallback {
+            name: name.into(),
+            source: Box::new(source),
+        });
+        self
+    }
+
+    pub fn run(
+        &mut self,
+        mut operation: impl FnMut() -> Result<T, E>,
+    ) -> Result<Recovered<T, E>, RecoveryError<E>> {
+        let mut errors = Vec::new();
+        for attempt in 1..=self.attempts {
+            if attempt > 1 {
+                std::thread::sleep(self.delay_before(attempt));
+            }
+            match operation() {
+                Ok(value) => {
+                    let path = RecoveryPath::Primary { attempt };
+                    return Ok(self.succeeded(value, path, errors));
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+        let mut next = 0;
+        loop {
+            let (name, result) = match self.next_step(&mut next) {
+                Next::Found(found) => return Ok(recovered(found, errors)),
+                Next::Call { name, source } => (name, source()),
+                Next::Exhausted => return Err(self.exhausted(errors)),
+            };
+            match result {
+                Ok(value) => {
+                    let path = RecoveryPath::Fallback { name };
+                    return Ok(self.succeeded(value, path, errors));
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static, E: fmt::Debug + Send + 'static> AsyncRecoveryPolicy<T, E> {
+    /// Awaits `source()` if everything before it failed.
+    pub fn fallback<F, Fut>(mut self, name: impl Into<String>, mut source: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, E>> + Send + 'static,
+    {
+        self.steps.push(Step::Fallback {
+            name: name.into(),
+            source: Box::new(move || Box::pin(source())),
+        });
+        self
+    }
+
+    pub async fn run<F, Fut>(
+        &mut self,
+        mut operation: F,
+    ) -> Result<Recovered<T, E>, RecoveryError<E>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut errors = Vec::new();
+        for attempt in 1..=self.attempts {
+            if attempt > 1 {
+                tokio::time::sleep(self.delay_before(attempt)).await;
+            }
+            match operation().await {
+                Ok(value) => {
+                    let path = RecoveryPath::Primary { attempt };
+                    return Ok(self.succeeded(value, path, errors));
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+        let mut next = 0;
+        loop {
+            let (name, pending) = match self.next_step(&mut next) {
+                Next::Found(found) => return Ok(recovered(found, errors)),
+                Next::Call { name, source } => (name, source()),
+                Next::Exhausted => return Err(self.exhausted(errors)),
+            };
+            match pending.await {
+                Ok(value) => {
+                    let path = RecoveryPath::Fallback { name };
+                    return Ok(self.succeeded(value, path, errors));
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::exercise_22::{Data, FetchError};
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn data(value: i32, source: &str) -> Data {
+        Data {
+            value,
+            source: source.to_string(),
+        }
+    }
+
+    /// Fails `failures` times, then succeeds.
+    fn flaky(failures: u32) -> impl FnMut() -> Result<Data, FetchError> {
+        let mut calls = 0;
+        move || {
+            calls += 1;
+            if calls <= failures {
+                Err(FetchError::Timeout)
+            } else {
+                Ok(data(calls as i32, "primary"))
+            }
+        }
+    }
+
+    #[test]
+    fn test_primary_success_and_retry() {
+        let mut policy = RecoveryPolicy::<Data, FetchError>::new().retry(3);
+        let out = policy.run(flaky(0)).unwrap();
+        assert_eq!(out.path, RecoveryPath::Primary { attempt: 1 });
+        assert!(out.is_primary() && out.errors.is_empty());
+
+        let out = policy.run(flaky(2)).unwrap();
+        assert_eq!(out.path, RecoveryPath::Primary { attempt: 3 });
+        assert_eq!(out.errors, [FetchError::Timeout, FetchError::Timeout]);
+
+        let err = policy.run(flaky(3)).unwrap_err();
+        assert_eq!((err.attempts, err.fallback_failures), (3, 0));
+        assert_eq!(
+            err.to_string(),
+            "all recovery paths failed: 3 attempts of the operation, 0 failed fallbacks"
+        );
+
+        let mut policy = RecoveryPolicy::<Data, FetchError>::new()
+            .retry(2)
+            .fallback("replica", || Err(FetchError::NetworkError))
+            .fallback("archive", || Err(FetchError::NetworkError));
+        let err = policy.run(flaky(5)).unwrap_err();
+        assert_eq!((err.attempts, err.fallback_failures), (2, 2));
+        assert_eq!(err.errors.len(), 4);
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_cap() {
+        let policy = RecoveryPolicy::<Data, FetchError>::new()
+            .retry(40)
+            .backoff(Duration::from_millis(10))
+            .max_backoff(Duration::from_millis(50));
+        let delays: Vec<_> = (2..=6).map(|a| policy.delay_before(a)).collect();
+        assert_eq!(delays, [10, 20, 40, 50, 50].map(Duration::from_millis));
+        assert_eq!(policy.delay_before(40), Duration::from_millis(50));
+
+        let uncapped = RecoveryPolicy::<Data, FetchError>::new().backoff(Duration::from_secs(1));
+        // Saturates instead of overflowing.
+        assert_eq!(
+            uncapped.delay_before(100),
+            Duration::from_secs(u32::MAX.into())
+        );
+    }
+
+    #[test]
+    fn test_retry_then_fallback_then_default() {
+        let replica_calls = Arc::new(AtomicU32::new(0));
+        let calls = Arc::clone(&replica_calls);
+        let mut policy = RecoveryPolicy::new()
+            .retry(3)
+            .fallback("replica", move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(FetchError::NetworkError)
+            })
+            .fallback("archive", || Ok(data(7, "archive")))
+            .default_value(data(0, "default"));
+
+        let out = policy.run(|| Err(FetchError::ParseError)).unwrap();
+        assert_eq!(
+            out.path,
+            RecoveryPath::Fallback {
+                name: "archive".into()
+            }
+        );
+        assert_eq!(out.value, data(7, "archive"));
+        assert_eq!(out.errors.len(), 4);
+        assert_eq!(out.errors[3], FetchError::NetworkError);
+        assert_eq!(replica_calls.load(Ordering::SeqCst), 1);
+
+        let mut policy = RecoveryPolicy::new()
+            .fallback("replica", || Err(FetchError::NetworkError))
+            .default_value(data(0, "default"));
+        let out = policy.run(|| Err(FetchError::Timeout)).unwrap();
+        assert_eq!(out.path, RecoveryPath::Default);
+        assert_eq!(out.value.source, "default");
+    }
+
+    #[test]
+    fn test_cached_last_good_value() {
+        let cache = LastGood::new();
+        let mut policy = RecoveryPolicy::new()
+            .retry(2)
+            .cached(&cache)
+            .default_value(data(0, "default"));
+
+        // Nothing cached yet, so the default is used.
+        let out = policy.run(|| Err(FetchError::Timeout)).unwrap();
+        assert_eq!(out.path, RecoveryPath::Default);
+
+        policy.run(|| Ok(data(42, "primary"))).unwrap();
+        assert_eq!(cache.get(), Some(data(42, "primary")));
+
+        let out = policy.run(|| Err(FetchError::Timeout)).unwrap();
+        assert_eq!(out.path, RecoveryPath::Cached);
+        assert_eq!(out.value.value, 42);
+
+        // Fallback values are cached too, and the cache is shared.
+        let mut other = RecoveryPolicy::new()
+            .fallback("replica", || Ok(data(9, "replica")))
+            .cached(&cache);
+        other.run(|| Err(FetchError::Timeout)).unwrap();
+        assert_eq!(cache.get().unwrap().value, 9);
+    }
+
+    #[test]
+    fn test_degrade_with_warning() {
+        let mut policy = RecoveryPolicy::new()
+            .fallback("replica", || Err(FetchError::NetworkError))
+            .degrade("serving placeholder data", || data(-1, "placeholder"))
+            .default_value(data(0, "unreachable"));
+        let out = policy.run(|| Err(FetchError::Timeout)).unwrap();
+        assert_eq!(out.path, RecoveryPath::Degraded);
+        assert_eq!(out.warning.as_deref(), Some("serving placeholder data"));
+        assert_eq!(out.value.source, "placeholder");
+        assert_eq!(out.errors, [FetchError::Timeout, FetchError::NetworkError]);
+    }
+
+    #[test]
+    fn test_resilient_parse_as_policy() {
+        fn parse(s: &'static str) -> Recovered<i32, String> {
+            RecoveryPolicy::new()
+                .fallback("trimmed", move || {
+                    s.trim().parse().map_err(|e| format!("{e}"))
+                })
+                .fallback("float", move || {
+                    s.trim()
+                        .parse::<f64>()
+                        .map(|f| f as i32)
+                        .map_err(|e| format!("{e}"))
+                })
+                .run(|| s.parse::<i32>().map_err(|e| e.to_string()))
+                .unwrap_or_else(|e| Recovered {
+                    value: i32::MIN,
+                    path: RecoveryPath::Default,
+                    errors: e.errors,
+                    warning: None,
+                })
+        }
+        assert!(parse("42").is_primary());
+        assert_eq!(
+            parse("  42 ").path,
+            RecoveryPath::Fallback {
+                name: "trimmed".into()
+            }
+        );
+        let float = parse("42.7");
+        assert_eq!(
+            (float.value, float.path),
+            (
+                42,
+                RecoveryPath::Fallback {
+                    name: "float".into()
+                }
+            )
+        );
+        assert_eq!(parse("abc").errors.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_async_policy() {
+        let cache = LastGood::new();
+        let mut policy = AsyncRecoveryPolicy::new()
+            .retry(3)
+            .backoff(Duration::from_millis(1))
+            .fallback("replica", || async {
+                tokio::task::yield_now().await;
+                Err(FetchError::NetworkError)
+            })
+            .cached(&cache)
+            .degrade("offline", || data(0, "offline"));
+
+        let attempts = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&attempts);
+        let out = policy
+            .run(|| {
+                let counter = Arc::clone(&counter);
+                async move {
+                    match counter.fetch_add(1, Ordering::SeqCst) {
+                        0 | 1 => Err(FetchError::Timeout),
+                        n => Ok(data(n as i32, "primary")),
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(out.path, RecoveryPath::Primary { attempt: 3 });
+        assert_eq!(cache.get().unwrap().value, 2);
+
+        let out = policy
+            .run(|| async { Err(FetchError::Timeout) })
+            .await
+            .unwrap();
+        assert_eq!(out.path, RecoveryPath::Cached);
+        assert_eq!(out.errors.len(), 4);
+
+        let mut offline = AsyncRecoveryPolicy::new()
+            .fallback("replica", || async { Err(FetchError::NetworkError) })
+            .degrade("offline", || data(0, "offline"));
+        let out = offline
+            .run(|| async { Err(FetchError::Timeout) })
+            .await
+            .unwrap();
+        assert_eq!(out.path, RecoveryPath::Degraded);
+        assert_eq!(out.warning.as_deref(), Some("offline"));
+    }
+}
@@ -16,11 +16,11 @@
 //! - Error combinators (map_err, and_then, or_else)
 //! - Error patterns and best practices
 //!
-//! ## Difficulty Distribution (30 exercises)
+//! ## Difficulty Distribution (36 exercises)
 //! - Easy: 8 exercises (01-08)
 //! - Medium: 12 exercises (09-20)
 //! - Hard: 8 exercises (21-28)
-//! - Expert: 2 exercises (29-30)
+//! - Expert: 8 exercises (29-36)
 
 pub mod exercise_01;
 pub mod exercise_02;
@@ -52,3 +52,9 @@ pub mod exercise_27;
 pub mod exercise_28;
 pub mod exercise_29;
 pub mod exercise_30;
+pub mod exercise_31;
+pub mod exercise_32;
+pub mod exercise_33;
+pub mod exercise_34;
+pub mod exercise_35;
+pub mod exercise_36;
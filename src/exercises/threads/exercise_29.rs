@@ -6,12 +6,18 @@
 //! - Use atomic pointers
 //! - Handle ABA problem considerations
 //! - Master complex CAS loops
+//! - Reclaim popped nodes with epoch-based reclamation (exercise 34)
 
+use super::exercise_34 as epoch;
+use std::mem::ManuallyDrop;
 use std::ptr;
 use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Arc;
+use std::thread;
 
 struct Node<T> {
-    data: T,
+    // Moved out by `pop`; the node itself is freed later by the collector.
+    data: ManuallyDrop<T>,
     next: *mut Node<T>,
 }
 
@@ -21,25 +27,72 @@ pub struct LockFreeStack<T> {
 
 impl<T> LockFreeStack<T> {
     pub fn new() -> Self {
-        todo!("Implement new")
+        LockFreeStack {
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
     }
 
     pub fn push(&self, data: T) {
-        todo!("Implement push")
+        let node = Box::into_raw(Box::new(Node {
+            data: ManuallyDrop::new(data),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*node).next = head };
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
     }
 
     pub fn pop(&self) -> Option<T> {
-        todo!("Implement pop")
+        // While pinned, no node we load can be freed, so reading `next` is
+        // safe and a freed-and-reallocated head (ABA) cannot appear.
+        let guard = epoch::pin();
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            if head.is_null() {
+                return None;
+            }
+            let next = unsafe { (*head).next };
+            match self
+                .head
+                .compare_exchange_weak(head, next, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    let data = unsafe { ptr::read(&(*head).data) };
+                    unsafe { guard.defer_destroy(head) };
+                    return Some(ManuallyDrop::into_inner(data));
+                }
+                Err(current) => head = current,
+            }
+        }
     }
 
     pub fn is_empty(&self) -> bool {
-        todo!("Implement is_empty")
+        self.head.load(Ordering::Acquire).is_null()
+    }
+}
+
+impl<T> Default for LockFreeStack<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl<T> Drop for LockFreeStack<T> {
     fn drop(&mut self) {
-        todo!("Implement drop")
+        let mut current = *self.head.get_mut();
+        while !current.is_null() {
+            let mut node = unsafe { Box::from_raw(current) };
+            unsafe { ManuallyDrop::drop(&mut node.data) };
+            current = node.next;
+        }
     }
 }
 
@@ -47,8 +100,24 @@ unsafe impl<T: Send> Send for LockFreeStack<T> {}
 unsafe impl<T: Send> Sync for LockFreeStack<T> {}
 
 /// Test concurrent push and pop operations on lock-free stack.
+/// Each thread pushes its items and then pops the same number; returns the
+/// total number of successful pops.
 pub fn test_lock_free_stack(n_threads: usize, operations_per_thread: usize) -> usize {
-    todo!("Implement test_lock_free_stack")
+    let stack = Arc::new(LockFreeStack::new());
+    let handles: Vec<_> = (0..n_threads)
+        .map(|t| {
+            let stack = Arc::clone(&stack);
+            thread::spawn(move || {
+                for i in 0..operations_per_thread {
+                    stack.push(t * operations_per_thread + i);
+                }
+                (0..operations_per_thread)
+                    .filter(|_| stack.pop().is_some())
+                    .count()
+            })
+        })
+        .collect();
+    handles.into_iter().map(|h| h.join().unwrap()).sum()
 }
 
 #[cfg(test)]
@@ -81,4 +150,63 @@ mod tests {
         let count = test_lock_free_stack(10, 10);
         assert_eq!(count, 100);
     }
+
+    struct Tracked(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_no_leak_or_use_after_free_under_contention() {
+        // Miri runs the same interleavings, just far fewer of them.
+        const THREADS: usize = 4;
+        let pairs_per_thread: usize = if cfg!(miri) { 50 } else { 250_000 };
+
+        let dropped = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let drained = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let stack = Arc::new(LockFreeStack::new());
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let stack = Arc::clone(&stack);
+                let dropped = Arc::clone(&dropped);
+                let drained = Arc::clone(&drained);
+                thread::spawn(move || {
+                    let mut popped = 0;
+                    for _ in 0..pairs_per_thread {
+                        stack.push(Tracked(Arc::clone(&dropped)));
+                        if stack.pop().is_some() {
+                            popped += 1;
+                        }
+                    }
+                    // Deferred after every node this thread popped, so it
+                    // only runs in or after the pass that frees them all.
+                    epoch::pin().defer(move || {
+                        drained.fetch_add(1, Ordering::SeqCst);
+                    });
+                    popped
+                })
+            })
+            .collect();
+        let popped: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        // Every thread pops right after pushing, so no pop can come up empty.
+        assert_eq!(popped, THREADS * pairs_per_thread);
+        assert!(stack.is_empty());
+        assert_eq!(dropped.load(Ordering::SeqCst), THREADS * pairs_per_thread);
+
+        // Every popped node must be freed once the epoch moves on. The
+        // collector is shared with other tests, so track this test's own
+        // garbage rather than the global pending count.
+        drop(stack);
+        for _ in 0..2_000 {
+            if drained.load(Ordering::SeqCst) == THREADS {
+                break;
+            }
+            epoch::flush_all();
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(drained.load(Ordering::SeqCst), THREADS);
+    }
 }
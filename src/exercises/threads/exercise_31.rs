@@ -0,0 +1,446 @@
+//! Exercise 31: Reusable Thread Pool
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Keep a fixed set of workers alive across many jobs
+//! - Return job results through handles, including panic payloads
+//! - Isolate panics so a failing job does not take down its worker
+//! - Shut down gracefully or immediately, and resize at runtime
+//!
+//! Exercise 13 spawns workers for a single batch; this pool outlives any
+//! batch and can be shared by reference between threads.
+
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() -> bool + Send + 'static>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolError {
+    /// The pool has been shut down and no longer accepts jobs.
+    ShutDown,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolError::ShutDown => write!(f, "thread pool is shut down"),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+pub enum JobError {
+    /// The job panicked; this is the payload passed to `panic!`.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The job was discarded by `shutdown_now` before it ran.
+    Cancelled,
+}
+
+impl JobError {
+    /// The panic message, if the payload was a string.
+    pub fn panic_message(&self) -> Option<&str> {
+        match self {
+            JobError::Panicked(payload) => payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str)),
+            JobError::Cancelled => None,
+        }
+    }
+}
+
+impl fmt::Debug for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobError::Panicked(_) => write!(f, "Panicked({:?})", self.panic_message()),
+            JobError::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobError::Panicked(_) => match self.panic_message() {
+                Some(msg) => write!(f, "job panicked: {}", msg),
+                None => write!(f, "job panicked"),
+            },
+            JobError::Cancelled => write!(f, "job was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+/// Handle to the result of a job started with `ThreadPool::submit`.
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<thread::Result<T>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Blocks until the job has finished.
+    pub fn join(self) -> Result<T, JobError> {
+        match self.receiver.recv() {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(payload)) => Err(JobError::Panicked(payload)),
+            Err(_) => Err(JobError::Cancelled),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PoolStats {
+    pub workers: usize,
+    pub queued: usize,
+    pub active: usize,
+    pub completed: u64,
+    pub panicked: u64,
+}
+
+struct State {
+    queue: VecDeque<Job>,
+    target: usize,
+    live: usize,
+    active: usize,
+    accepting: bool,
+    completed: u64,
+    panicked: u64,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    work_available: Condvar,
+    idle: Condvar,
+}
+
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    handles: Mutex<Vec<thread::JoinHandle<()>>>,
+}
+
+impl ThreadPool {
+    /// Creates a pool with `size` workers (at least one).
+    pub fn new(size: usize) -> ThreadPool {
+        let size = size.max(1);
+        let pool = ThreadPool {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    queue: VecDeque::new(),
+                    target: size,
+                    live: 0,
+                    active: 0,
+                    accepting: true,
+                    completed: 0,
+                    panicked: 0,
+                }),
+                work_available: Condvar::new(),
+                idle: Condvar::new(),
+            }),
+            handles: Mutex::new(Vec::new()),
+        };
+        pool.spawn_workers(size);
+        pool
+    }
+
+    /// Queues a job whose result is not needed.
+    pub fn execute<F>(&self, f: F) -> Result<(), PoolError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.enqueue(Box::new(move || panic::catch_unwind(AssertUnwindSafe(f)).is_err()))
+    }
+
+    /// Queues a job and returns a handle to its result.
+    pub fn submit<T, F>(&self, f: F) -> Result<JobHandle<T>, PoolError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        self.enqueue(Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let panicked = result.is_err();
+            // The caller may have dropped the handle; that is fine.
+            let _ = sender.send(result);
+            panicked
+        }))?;
+        Ok(JobHandle { receiver })
+    }
+
+    fn enqueue(&self, job: Job) -> Result<(), PoolError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.accepting {
+            return Err(PoolError::ShutDown);
+        }
+        state.queue.push_back(job);
+        drop(state);
+        self.shared.work_available.notify_one();
+        Ok(())
+    }
+
+    /// Changes the number of workers. Extra workers retire after their
+    /// current job; new workers start immediately.
+    pub fn resize(&self, size: usize) -> Result<(), PoolError> {
+        let size = size.max(1);
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.accepting {
+            return Err(PoolError::ShutDown);
+        }
+        state.target = size;
+        let missing = size.saturating_sub(state.live);
+        drop(state);
+        self.shared.work_available.notify_all();
+        self.spawn_workers(missing);
+        Ok(())
+    }
+
+    /// Blocks until the queue is empty and no job is running.
+    pub fn wait_idle(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        while !state.queue.is_empty() || state.active > 0 {
+            state = self.shared.idle.wait(state).unwrap();
+        }
+    }
+
+    /// Stops accepting jobs, runs everything already queued, then joins all workers.
+    pub fn shutdown(&self) {
+        self.shared.state.lock().unwrap().accepting = false;
+        self.shared.work_available.notify_all();
+        self.join_workers();
+    }
+
+    /// Stops accepting jobs, discards queued jobs, waits for running jobs and
+    /// joins all workers. Returns how many jobs were discarded; their handles
+    /// report `JobError::Cancelled`.
+    pub fn shutdown_now(&self) -> usize {
+        let discarded: Vec<Job> = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.accepting = false;
+            state.queue.drain(..).collect()
+        };
+        self.shared.work_available.notify_all();
+        self.shared.idle.notify_all();
+        let count = discarded.len();
+        drop(discarded);
+        self.join_workers();
+        count
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let state = self.shared.state.lock().unwrap();
+        PoolStats {
+            workers: state.live,
+            queued: state.queue.len(),
+            active: state.active,
+            completed: state.completed,
+            panicked: state.panicked,
+        }
+    }
+
+    fn spawn_workers(&self, count: usize) {
+        if count == 0 {
+            return;
+        }
+        self.shared.state.lock().unwrap().live += count;
+        let mut handles = self.handles.lock().unwrap();
+        handles.retain(|h| !h.is_finished());
+        for _ in 0..count {
+            let shared = Arc::clone(&self.shared);
+            handles.push(thread::spawn(move || worker_loop(&shared)));
+        }
+    }
+
+    fn join_workers(&self) {
+        let handles: Vec<_> = self.handles.lock().unwrap().drain(..).collect();
+        for handle in handles {
+            // Jobs run under catch_unwind, so workers never panic.
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn worker_loop(shared: &Shared) {
+    loop {
+        let job = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if state.live > state.target {
+                    state.live -= 1;
+                    return;
+                }
+                if let Some(job) = state.queue.pop_front() {
+                    state.active += 1;
+                    break job;
+                }
+                if !state.accepting {
+                    state.live -= 1;
+                    return;
+                }
+                state = shared.work_available.wait(state).unwrap();
+            }
+        };
+
+        let panicked = job();
+
+        let mut state = shared.state.lock().unwrap();
+        state.active -= 1;
+        state.completed += 1;
+        if panicked {
+            state.panicked += 1;
+        }
+        if state.queue.is_empty() && state.active == 0 {
+            shared.idle.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_submit_returns_results() {
+        let pool = ThreadPool::new(4);
+        let handles: Vec<_> = (0..20).map(|i| pool.submit(move || i * i).unwrap()).collect();
+        let results: Vec<i32> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, (0..20).map(|i| i * i).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_execute_and_wait_idle() {
+        let pool = ThreadPool::new(3);
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..50 {
+            let counter = Arc::clone(&counter);
+            pool.execute(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+        pool.wait_idle();
+        assert_eq!(counter.load(Ordering::SeqCst), 50);
+        assert_eq!(pool.stats().completed, 50);
+    }
+
+    #[test]
+    fn test_panic_is_isolated() {
+        let pool = ThreadPool::new(1);
+        let bad = pool.submit(|| -> i32 { panic!("bad job") }).unwrap();
+        let good = pool.submit(|| 7).unwrap();
+        let err = bad.join().unwrap_err();
+        assert_eq!(err.panic_message(), Some("bad job"));
+        assert_eq!(good.join().unwrap(), 7);
+
+        pool.execute(|| panic!("fire and forget")).unwrap();
+        pool.wait_idle();
+        let stats = pool.stats();
+        assert_eq!(stats.panicked, 2);
+        assert_eq!(stats.workers, 1);
+    }
+
+    #[test]
+    fn test_graceful_shutdown_runs_queued_jobs() {
+        let pool = ThreadPool::new(1);
+        let handles: Vec<_> = (0..5)
+            .map(|i| {
+                pool.submit(move || {
+                    thread::sleep(Duration::from_millis(5));
+                    i
+                })
+                .unwrap()
+            })
+            .collect();
+        pool.shutdown();
+        let results: Vec<i32> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, vec![0, 1, 2, 3, 4]);
+        assert_eq!(pool.execute(|| {}), Err(PoolError::ShutDown));
+        assert_eq!(pool.stats().workers, 0);
+    }
+
+    #[test]
+    fn test_shutdown_now_cancels_queued_jobs() {
+        let pool = ThreadPool::new(1);
+        let (started_tx, started_rx) = mpsc::channel();
+        let running = pool
+            .submit(move || {
+                started_tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(30));
+                1
+            })
+            .unwrap();
+        let queued: Vec<_> = (0..3).map(|i| pool.submit(move || i).unwrap()).collect();
+        started_rx.recv().unwrap();
+
+        assert_eq!(pool.shutdown_now(), 3);
+        assert_eq!(running.join().unwrap(), 1);
+        for handle in queued {
+            assert!(matches!(handle.join(), Err(JobError::Cancelled)));
+        }
+    }
+
+    #[test]
+    fn test_resize() {
+        let pool = ThreadPool::new(2);
+        pool.resize(5).unwrap();
+        assert_eq!(pool.stats().workers, 5);
+
+        // Five jobs that wait for each other can only finish with five workers.
+        let barrier = Arc::new(std::sync::Barrier::new(5));
+        let handles: Vec<_> = (0..5)
+            .map(|_| {
+                let barrier = Arc::clone(&barrier);
+                pool.submit(move || barrier.wait().is_leader()).unwrap()
+            })
+            .collect();
+        let leaders = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .filter(|is_leader| *is_leader)
+            .count();
+        assert_eq!(leaders, 1);
+
+        pool.resize(1).unwrap();
+        for _ in 0..100 {
+            if pool.stats().workers == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(pool.stats().workers, 1);
+        assert_eq!(pool.submit(|| 3).unwrap().join().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_pool_shared_between_threads() {
+        let pool = Arc::new(ThreadPool::new(4));
+        let submitters: Vec<_> = (0..4)
+            .map(|t| {
+                let pool = Arc::clone(&pool);
+                thread::spawn(move || {
+                    (0..25)
+                        .map(|i| pool.submit(move || t * 100 + i).unwrap())
+                        .map(|h| h.join().unwrap())
+                        .sum::<i32>()
+                })
+            })
+            .collect();
+        let total: i32 = submitters.into_iter().map(|h| h.join().unwrap()).sum();
+        let expected: i32 = (0..4).map(|t| (0..25).map(|i| t * 100 + i).sum::<i32>()).sum();
+        assert_eq!(total, expected);
+    }
+}
@@ -0,0 +1,682 @@
+//! Exercise 32: Work-Stealing Scheduler
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Give every worker its own deque and steal from random victims when idle
+//! - Implement `join(a, b)` fork-join that borrows from the caller's stack
+//! - Keep the waiting thread busy instead of blocking inside `join`
+//! - Build `par_map`, `par_filter` and `par_reduce` on recursive splitting
+//!
+//! Exercises 14 and 15 cut the input into `n_threads` equal chunks, so one
+//! slow chunk leaves the other threads idle. Here the input is split
+//! recursively with `join`; idle workers steal the unstarted halves, which
+//! spreads skewed work automatically. `parallel_map` and `parallel_sum` at the
+//! bottom keep the exercise 14/15 signatures on top of this scheduler.
+//!
+//! Owners push and pop at the back of their deque (LIFO, cache friendly) and
+//! thieves take from the front (FIFO, the biggest pieces of work).
+
+use std::cell::{Cell, UnsafeCell};
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread;
+
+/// A type-erased pointer to a job that lives on some thread's stack.
+struct JobRef {
+    data: *const (),
+    execute: unsafe fn(*const ()),
+}
+
+// The job behind the pointer is only touched by the thread that runs it, and
+// the owner waits for its latch before the job goes out of scope.
+unsafe impl Send for JobRef {}
+
+impl JobRef {
+    unsafe fn execute(self) {
+        unsafe { (self.execute)(self.data) }
+    }
+}
+
+trait Latch {
+    fn set(&self);
+}
+
+/// Waited on by a worker that keeps doing other work, and parks when there
+/// is none.
+struct ParkLatch {
+    done: AtomicBool,
+    owner: thread::Thread,
+}
+
+impl Latch for ParkLatch {
+    fn set(&self) {
+        // Clone the handle first: once `done` is stored the owner may free
+        // the job, and `self` with it.
+        let owner = self.owner.clone();
+        self.done.store(true, Ordering::Release);
+        owner.unpark();
+    }
+}
+
+/// Blocked on by a thread outside the pool.
+struct LockLatch {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Latch for LockLatch {
+    fn set(&self) {
+        // Keep the pair alive ourselves: once notified, the waiter may free
+        // the job (and with it `self`) before we unlock.
+        let inner = Arc::clone(&self.inner);
+        let mut done = inner.0.lock().unwrap();
+        *done = true;
+        inner.1.notify_all();
+    }
+}
+
+impl LockLatch {
+    fn wait(&self) {
+        let mut done = self.inner.0.lock().unwrap();
+        while !*done {
+            done = self.inner.1.wait(done).unwrap();
+        }
+    }
+}
+
+struct StackJob<F, R, L> {
+    func: UnsafeCell<Option<F>>,
+    result: UnsafeCell<Option<thread::Result<R>>>,
+    latch: L,
+}
+
+impl<F, R, L> StackJob<F, R, L>
+where
+    F: FnOnce() -> R + Send,
+    R: Send,
+    L: Latch,
+{
+    fn new(func: F, latch: L) -> Self {
+        StackJob {
+            func: UnsafeCell::new(Some(func)),
+            result: UnsafeCell::new(None),
+            latch,
+        }
+    }
+
+    fn as_job_ref(&self) -> JobRef {
+        JobRef {
+            data: self as *const Self as *const (),
+            execute: Self::execute,
+        }
+    }
+
+    unsafe fn execute(data: *const ()) {
+        let this = unsafe { &*(data as *const Self) };
+        let func = unsafe { (*this.func.get()).take() }.expect("job executed twice");
+        let result = panic::catch_unwind(AssertUnwindSafe(func));
+        unsafe { *this.result.get() = Some(result) };
+        this.latch.set();
+    }
+
+    /// Only valid once the latch has been set.
+    fn into_result(self) -> thread::Result<R> {
+        self.result.into_inner().expect("job not executed")
+    }
+}
+
+struct Shared {
+    deques: Vec<Mutex<VecDeque<JobRef>>>,
+    injector: Mutex<VecDeque<JobRef>>,
+    sleep_lock: Mutex<()>,
+    wake: Condvar,
+    shutdown: AtomicBool,
+    steals: AtomicU64,
+    executed: AtomicU64,
+}
+
+impl Shared {
+    fn notify(&self) {
+        let _guard = self.sleep_lock.lock().unwrap();
+        self.wake.notify_one();
+    }
+}
+
+struct WorkerThread {
+    shared: Arc<Shared>,
+    index: usize,
+    rng: Cell<u64>,
+}
+
+thread_local! {
+    static CURRENT_WORKER: Cell<*const WorkerThread> = const { Cell::new(ptr::null()) };
+}
+
+impl WorkerThread {
+    fn current() -> Option<&'static WorkerThread> {
+        let worker = CURRENT_WORKER.with(Cell::get);
+        // Set for the whole lifetime of the worker loop, which owns the value.
+        unsafe { worker.as_ref() }
+    }
+
+    fn push(&self, job: JobRef) {
+        self.shared.deques[self.index].lock().unwrap().push_back(job);
+        self.shared.notify();
+    }
+
+    fn pop(&self) -> Option<JobRef> {
+        self.shared.deques[self.index].lock().unwrap().pop_back()
+    }
+
+    fn next_random(&self) -> usize {
+        // xorshift64
+        let mut x = self.rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.set(x);
+        x as usize
+    }
+
+    fn steal(&self) -> Option<JobRef> {
+        if let Some(job) = self.shared.injector.lock().unwrap().pop_front() {
+            return Some(job);
+        }
+        let n = self.shared.deques.len();
+        if n < 2 {
+            return None;
+        }
+        let start = self.next_random() % n;
+        for offset in 0..n {
+            let victim = (start + offset) % n;
+            if victim == self.index {
+                continue;
+            }
+            if let Some(job) = self.shared.deques[victim].lock().unwrap().pop_front() {
+                self.shared.steals.fetch_add(1, Ordering::Relaxed);
+                return Some(job);
+            }
+        }
+        None
+    }
+
+    fn find_work(&self) -> Option<JobRef> {
+        self.pop().or_else(|| self.steal())
+    }
+
+    fn run(&self, job: JobRef) {
+        self.shared.executed.fetch_add(1, Ordering::Relaxed);
+        unsafe { job.execute() };
+    }
+
+    /// Runs other jobs until `latch` is set, parking when there are none.
+    fn wait_until(&self, latch: &ParkLatch) {
+        while !latch.done.load(Ordering::Acquire) {
+            match self.find_work() {
+                Some(job) => self.run(job),
+                // An unpark from `set` that lands before this makes `park`
+                // return immediately, so the wake-up cannot be lost.
+                None => thread::park(),
+            }
+        }
+    }
+
+    fn join<A, B, RA, RB>(&self, a: A, b: B) -> (RA, RB)
+    where
+        A: FnOnce() -> RA + Send,
+        B: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send,
+    {
+        let latch = ParkLatch {
+            done: AtomicBool::new(false),
+            owner: thread::current(),
+        };
+        let job_b = StackJob::new(b, latch);
+        let job_b_ref = job_b.as_job_ref();
+        let job_b_id = job_b_ref.data;
+        self.push(job_b_ref);
+
+        let result_a = panic::catch_unwind(AssertUnwindSafe(a));
+
+        // Take `b` back if nobody stole it; otherwise help until it is done.
+        // Even if `a` panicked we must wait, because `b` borrows this frame.
+        while !job_b.latch.done.load(Ordering::Acquire) {
+            match self.pop() {
+                Some(job) if job.data == job_b_id => {
+                    unsafe { job.execute() };
+                    break;
+                }
+                Some(job) => self.run(job),
+                None => {
+                    self.wait_until(&job_b.latch);
+                    break;
+                }
+            }
+        }
+
+        let result_b = job_b.into_result();
+        match (result_a, result_b) {
+            (Ok(ra), Ok(rb)) => (ra, rb),
+            (Err(payload), _) | (_, Err(payload)) => panic::resume_unwind(payload),
+        }
+    }
+}
+
+fn worker_main(shared: Arc<Shared>, index: usize) {
+    let worker = WorkerThread {
+        shared,
+        index,
+        rng: Cell::new(0x9E37_79B9_7F4A_7C15 ^ (index as u64 + 1)),
+    };
+    CURRENT_WORKER.with(|w| w.set(&worker));
+    loop {
+        if let Some(job) = worker.find_work() {
+            worker.run(job);
+            continue;
+        }
+        // Look again while holding the sleep lock. A push after this point
+        // must take the lock to notify, so it cannot slip in before `wait`.
+        let guard = worker.shared.sleep_lock.lock().unwrap();
+        if worker.shared.shutdown.load(Ordering::Acquire) {
+            break;
+        }
+        if let Some(job) = worker.find_work() {
+            drop(guard);
+            worker.run(job);
+            continue;
+        }
+        drop(worker.shared.wake.wait(guard).unwrap());
+    }
+    CURRENT_WORKER.with(|w| w.set(ptr::null()));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedulerStats {
+    pub workers: usize,
+    pub steals: u64,
+    pub executed: u64,
+}
+
+/// A fixed set of work-stealing worker threads.
+pub struct Scheduler {
+    shared: Arc<Shared>,
+    handles: Vec<thread::JoinHandle<()>>,
+}
+
+impl Scheduler {
+    pub fn new(n_workers: usize) -> Scheduler {
+        let n_workers = n_workers.max(1);
+        let shared = Arc::new(Shared {
+            deques: (0..n_workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+            injector: Mutex::new(VecDeque::new()),
+            sleep_lock: Mutex::new(()),
+            wake: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+            steals: AtomicU64::new(0),
+            executed: AtomicU64::new(0),
+        });
+        let handles = (0..n_workers)
+            .map(|i| {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || worker_main(shared, i))
+            })
+            .collect();
+        Scheduler { shared, handles }
+    }
+
+    pub fn num_workers(&self) -> usize {
+        self.shared.deques.len()
+    }
+
+    pub fn stats(&self) -> SchedulerStats {
+        SchedulerStats {
+            workers: self.num_workers(),
+            steals: self.shared.steals.load(Ordering::Relaxed),
+            executed: self.shared.executed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Runs `f` on one of this scheduler's workers and returns its result.
+    /// A panic inside `f` is propagated to the caller.
+    pub fn install<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R + Send,
+        R: Send,
+    {
+        if let Some(worker) = WorkerThread::current()
+            && Arc::ptr_eq(&worker.shared, &self.shared)
+        {
+            return f();
+        }
+        let job = StackJob::new(
+            f,
+            LockLatch {
+                inner: Arc::new((Mutex::new(false), Condvar::new())),
+            },
+        );
+        self.shared.injector.lock().unwrap().push_back(job.as_job_ref());
+        self.shared.notify();
+        job.latch.wait();
+        match job.into_result() {
+            Ok(value) => value,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    /// Runs `a` and `b` potentially in parallel on this scheduler.
+    pub fn join<A, B, RA, RB>(&self, a: A, b: B) -> (RA, RB)
+    where
+        A: FnOnce() -> RA + Send,
+        B: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send,
+    {
+        self.install(|| join(a, b))
+    }
+
+    fn grain(&self, len: usize) -> usize {
+        (len / (self.num_workers() * 8)).max(1)
+    }
+
+    /// Maps every element in parallel, keeping the input order.
+    pub fn par_map<T, U, F>(&self, items: &[T], f: F) -> Vec<U>
+    where
+        T: Sync,
+        U: Send,
+        F: Fn(&T) -> U + Sync,
+    {
+        let grain = self.grain(items.len());
+        self.install(|| par_map_rec(items, &f, grain))
+    }
+
+    /// Keeps the elements matching `predicate`, in input order.
+    pub fn par_filter<T, P>(&self, items: &[T], predicate: P) -> Vec<T>
+    where
+        T: Sync + Send + Clone,
+        P: Fn(&T) -> bool + Sync,
+    {
+        let grain = self.grain(items.len());
+        self.install(|| par_filter_rec(items, &predicate, grain))
+    }
+
+    /// Maps every element and combines the results with `reduce`, which must
+    /// be associative with `identity` as its neutral element.
+    pub fn par_reduce<T, U, M, R>(&self, items: &[T], identity: U, map: M, reduce: R) -> U
+    where
+        T: Sync,
+        U: Send + Sync + Clone,
+        M: Fn(&T) -> U + Sync,
+        R: Fn(U, U) -> U + Sync,
+    {
+        let grain = self.grain(items.len());
+        self.install(|| par_reduce_rec(items, &identity, &map, &reduce, grain))
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Release);
+        {
+            let _guard = self.shared.sleep_lock.lock().unwrap();
+            self.shared.wake.notify_all();
+        }
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// The scheduler used by `join` when called outside any worker.
+pub fn global_scheduler() -> &'static Scheduler {
+    static GLOBAL: OnceLock<Scheduler> = OnceLock::new();
+    GLOBAL.get_or_init(|| {
+        Scheduler::new(thread::available_parallelism().map_or(4, |n| n.get()))
+    })
+}
+
+/// Leaf size that splits `len` items into about `n_threads` pieces. More
+/// pieces than the global scheduler has workers would not run any sooner.
+fn grain_for(len: usize, n_threads: usize) -> usize {
+    let pieces = n_threads.clamp(1, global_scheduler().num_workers());
+    len.div_ceil(pieces).max(1)
+}
+
+/// Fork-join: runs `a` and `b` potentially in parallel and returns both
+/// results. Inside a scheduler this uses the current worker; elsewhere it
+/// uses `global_scheduler()`.
+pub fn join<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send,
+    B: FnOnce() -> RB + Send,
+    RA: Send,
+    RB: Send,
+{
+    match WorkerThread::current() {
+        Some(worker) => worker.join(a, b),
+        None => global_scheduler().install(|| join(a, b)),
+    }
+}
+
+fn par_map_rec<T, U, F>(items: &[T], f: &F, grain: usize) -> Vec<U>
+where
+    T: Sync,
+    U: Send,
+    F: Fn(&T) -> U + Sync,
+{
+    if items.len() <= grain {
+        return items.iter().map(f).collect();
+    }
+    let (left, right) = items.split_at(items.len() / 2);
+    let (mut left, right) = join(|| par_map_rec(left, f, grain), || par_map_rec(right, f, grain));
+    left.extend(right);
+    left
+}
+
+fn par_filter_rec<T, P>(items: &[T], predicate: &P, grain: usize) -> Vec<T>
+where
+    T: Sync + Send + Clone,
+    P: Fn(&T) -> bool + Sync,
+{
+    if items.len() <= grain {
+        return items.iter().filter(|x| predicate(x)).cloned().collect();
+    }
+    let (left, right) = items.split_at(items.len() / 2);
+    let (mut left, right) = join(
+        || par_filter_rec(left, predicate, grain),
+        || par_filter_rec(right, predicate, grain),
+    );
+    left.extend(right);
+    left
+}
+
+fn par_reduce_rec<T, U, M, R>(items: &[T], identity: &U, map: &M, reduce: &R, grain: usize) -> U
+where
+    T: Sync,
+    U: Send + Sync + Clone,
+    M: Fn(&T) -> U + Sync,
+    R: Fn(U, U) -> U + Sync,
+{
+    if items.len() <= grain {
+        return items
+            .iter()
+            .fold(identity.clone(), |acc, x| reduce(acc, map(x)));
+    }
+    let (left, right) = items.split_at(items.len() / 2);
+    let (left, right) = join(
+        || par_reduce_rec(left, identity, map, reduce, grain),
+        || par_reduce_rec(right, identity, map, reduce, grain),
+    );
+    reduce(left, right)
+}
+
+/// Exercise 14's `parallel_map`, rewritten on the work-stealing scheduler.
+/// Runs on `global_scheduler()`, using at most `n_threads` of its workers.
+pub fn parallel_map<T, U, F>(data: Vec<T>, n_threads: usize, f: F) -> Vec<U>
+where
+    T: Send + 'static + Clone,
+    U: Send + 'static,
+    F: Fn(T) -> U + Send + Sync + 'static,
+{
+    // `T` is only `Send`, so each element moves through its own `Mutex`,
+    // which makes the slice shareable between workers.
+    let cells: Vec<Mutex<Option<T>>> = data.into_iter().map(|x| Mutex::new(Some(x))).collect();
+    let take = |cell: &Mutex<Option<T>>| {
+        f(cell.lock().unwrap().take().expect("each element is mapped once"))
+    };
+    let grain = grain_for(cells.len(), n_threads);
+    global_scheduler().install(|| par_map_rec(&cells, &take, grain))
+}
+
+/// Exercise 15's `parallel_sum`, rewritten on the work-stealing scheduler.
+/// Runs on `global_scheduler()`, using at most `n_threads` of its workers.
+pub fn parallel_sum(numbers: Vec<i64>, n_threads: usize) -> i64 {
+    let grain = grain_for(numbers.len(), n_threads);
+    global_scheduler().install(|| par_reduce_rec(&numbers, &0, &|x: &i64| *x, &|a, b| a + b, grain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    fn fib(n: u64) -> u64 {
+        if n < 2 {
+            return n;
+        }
+        let (a, b) = join(|| fib(n - 1), || fib(n - 2));
+        a + b
+    }
+
+    #[test]
+    fn test_join_recursive() {
+        let scheduler = Scheduler::new(4);
+        assert_eq!(scheduler.install(|| fib(20)), 6765);
+        assert!(scheduler.stats().executed > 0);
+    }
+
+    #[test]
+    fn test_join_borrows_stack() {
+        let mut left = vec![1, 2, 3];
+        let mut right = vec![4, 5, 6];
+        join(|| left.push(10), || right.push(20));
+        assert_eq!(left, vec![1, 2, 3, 10]);
+        assert_eq!(right, vec![4, 5, 6, 20]);
+    }
+
+    #[test]
+    fn test_join_propagates_panic() {
+        let scheduler = Scheduler::new(2);
+        let finished_b = AtomicBool::new(false);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            scheduler.join(
+                || panic!("left side failed"),
+                || {
+                    thread::sleep(Duration::from_millis(10));
+                    finished_b.store(true, Ordering::SeqCst);
+                },
+            )
+        }));
+        assert!(result.is_err());
+        // The other half must have completed before the panic escaped.
+        assert!(finished_b.load(Ordering::SeqCst));
+        // The scheduler is still usable.
+        assert_eq!(scheduler.install(|| 1 + 1), 2);
+    }
+
+    #[test]
+    fn test_par_map_and_filter() {
+        let scheduler = Scheduler::new(4);
+        let data: Vec<i32> = (0..10_000).collect();
+        let doubled = scheduler.par_map(&data, |x| x * 2);
+        assert_eq!(doubled, data.iter().map(|x| x * 2).collect::<Vec<_>>());
+        let even = scheduler.par_filter(&data, |x| x % 2 == 0);
+        assert_eq!(even, data.iter().copied().filter(|x| x % 2 == 0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_par_reduce() {
+        let scheduler = Scheduler::new(3);
+        let words = vec!["a", "bb", "ccc", "dddd"];
+        let total_len = scheduler.par_reduce(&words, 0usize, |w| w.len(), |a, b| a + b);
+        assert_eq!(total_len, 10);
+        let joined = scheduler.par_reduce(&words, String::new(), |w| w.to_string(), |a, b| a + &b);
+        assert_eq!(joined, "abbcccdddd");
+    }
+
+    #[test]
+    fn test_skewed_work_is_stolen() {
+        let scheduler = Scheduler::new(4);
+        let threads_used = Mutex::new(std::collections::HashSet::new());
+        let data: Vec<u64> = (0..64).collect();
+        // All the expensive items sit in the first quarter of the input.
+        let result = scheduler.par_map(&data, |&x| {
+            threads_used.lock().unwrap().insert(thread::current().id());
+            if x < 16 {
+                thread::sleep(Duration::from_millis(5));
+            }
+            x + 1
+        });
+        assert_eq!(result, (1..=64).collect::<Vec<_>>());
+        assert!(scheduler.stats().steals > 0);
+        assert!(threads_used.lock().unwrap().len() > 1);
+    }
+
+    #[test]
+    fn test_install_from_many_threads() {
+        let scheduler = Arc::new(Scheduler::new(2));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let scheduler = Arc::clone(&scheduler);
+                let calls = Arc::clone(&calls);
+                thread::spawn(move || {
+                    let data: Vec<i64> = (0..1000).map(|x| x * i).collect();
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    scheduler.par_reduce(&data, 0, |x| *x, |a, b| a + b)
+                })
+            })
+            .collect();
+        let sums: Vec<i64> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(sums, vec![0, 499_500, 999_000, 1_498_500]);
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_thread_count_is_clamped_to_global_scheduler() {
+        let workers = global_scheduler().num_workers();
+        assert_eq!(grain_for(1000, 1_000_000), 1000usize.div_ceil(workers));
+        assert_eq!(grain_for(1000, 0), 1000);
+        assert_eq!(grain_for(0, 4), 1);
+        let data: Vec<i64> = (1..=1000).collect();
+        assert_eq!(parallel_sum(data.clone(), 1000), 500_500);
+        assert_eq!(parallel_map(data, 1000, |x| x * 2)[999], 2000);
+    }
+
+    #[test]
+    fn test_idle_workers_wake_for_new_work() {
+        let scheduler = Scheduler::new(3);
+        for round in 0..50 {
+            // Workers are asleep between rounds and must be woken by the push.
+            thread::sleep(Duration::from_micros(200));
+            assert_eq!(scheduler.install(|| join(|| round, || round + 1)), (round, round + 1));
+        }
+    }
+
+    #[test]
+    fn test_parallel_map_matches_exercise_14() {
+        assert_eq!(parallel_map(vec![1, 2, 3, 4, 5], 2, |x| x * 2), vec![2, 4, 6, 8, 10]);
+        assert_eq!(parallel_map(vec!["a", "b", "c"], 2, |s| s.to_uppercase()), vec!["A", "B", "C"]);
+        assert_eq!(parallel_map(Vec::<i32>::new(), 2, |x| x * 2), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn test_parallel_sum_matches_exercise_15() {
+        assert_eq!(parallel_sum((1..=100).collect(), 4), 5050);
+        assert_eq!(parallel_sum(vec![], 3), 0);
+        assert_eq!(parallel_sum(vec![-5, 5, 10], 8), 10);
+    }
+}
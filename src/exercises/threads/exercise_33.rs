@@ -0,0 +1,701 @@
+//! Exercise 33: Lock-Free MPMC Queues
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Build a bounded MPMC ring buffer with per-slot sequence numbers (Vyukov)
+//! - Build an unbounded Michael–Scott queue with a dummy head node
+//! - Reclaim dequeued nodes safely with hazard pointers
+//! - Measure throughput against `std::sync::mpsc::sync_channel`
+//!
+//! `ArrayQueue` never frees memory while in use, so it needs no reclamation.
+//! `SegQueue` unlinks nodes that another thread may still be reading; a node
+//! is only freed once no thread has published it as a hazard.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Aligns a value to its own cache line to avoid false sharing.
+#[derive(Debug, Default)]
+#[repr(align(64))]
+pub struct CachePadded<T>(pub T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+struct Slot<T> {
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A bounded multi-producer multi-consumer queue (Dmitry Vyukov's design).
+///
+/// Each slot carries a sequence number telling producers and consumers
+/// whose turn it is, so a single CAS on the head or tail claims a slot.
+pub struct ArrayQueue<T> {
+    buffer: Box<[Slot<T>]>,
+    mask: usize,
+    enqueue_pos: CachePadded<AtomicUsize>,
+    dequeue_pos: CachePadded<AtomicUsize>,
+}
+
+unsafe impl<T: Send> Send for ArrayQueue<T> {}
+unsafe impl<T: Send> Sync for ArrayQueue<T> {}
+
+impl<T> ArrayQueue<T> {
+    /// Creates a queue holding at least `capacity` items (rounded up to a power of two).
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(2).next_power_of_two();
+        let buffer = (0..capacity)
+            .map(|i| Slot {
+                sequence: AtomicUsize::new(i),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect();
+        ArrayQueue {
+            buffer,
+            mask: capacity - 1,
+            enqueue_pos: CachePadded(AtomicUsize::new(0)),
+            dequeue_pos: CachePadded(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Pushes `value`, or hands it back if the queue is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.enqueue_pos.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.buffer[pos & self.mask];
+            let seq = slot.sequence.load(Ordering::Acquire);
+            let diff = seq as isize - pos as isize;
+            if diff == 0 {
+                match self.enqueue_pos.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.sequence.store(pos + 1, Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                return Err(value);
+            } else {
+                pos = self.enqueue_pos.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let mut pos = self.dequeue_pos.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.buffer[pos & self.mask];
+            let seq = slot.sequence.load(Ordering::Acquire);
+            let diff = seq as isize - (pos + 1) as isize;
+            if diff == 0 {
+                match self.dequeue_pos.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        slot.sequence.store(pos + self.mask + 1, Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                return None;
+            } else {
+                pos = self.dequeue_pos.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Approximate number of items; exact when no other thread is active.
+    pub fn len(&self) -> usize {
+        let tail = self.enqueue_pos.load(Ordering::Acquire);
+        let head = self.dequeue_pos.load(Ordering::Acquire);
+        tail.saturating_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Drop for ArrayQueue<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+struct Retired {
+    ptr: *mut u8,
+    free: unsafe fn(*mut u8),
+}
+
+/// One thread's hazard pointers plus the nodes it has retired.
+struct HazardRecord {
+    active: AtomicBool,
+    hazards: [AtomicPtr<u8>; 2],
+    retired: UnsafeCell<Vec<Retired>>,
+    next: *mut HazardRecord,
+}
+
+/// The set of hazard records for one data structure. Records are never
+/// freed before the domain, so a thread can always read the whole list.
+struct HazardDomain {
+    head: AtomicPtr<HazardRecord>,
+    records: AtomicUsize,
+}
+
+impl HazardDomain {
+    fn new() -> Self {
+        HazardDomain {
+            head: AtomicPtr::new(ptr::null_mut()),
+            records: AtomicUsize::new(0),
+        }
+    }
+
+    /// Claims an idle record, or adds a new one.
+    fn acquire(&self) -> HazardGuard<'_> {
+        let mut current = self.head.load(Ordering::Acquire);
+        while let Some(record) = unsafe { current.as_ref() } {
+            if !record.active.load(Ordering::Relaxed)
+                && record
+                    .active
+                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return HazardGuard { domain: self, record };
+            }
+            current = record.next;
+        }
+
+        let record = Box::into_raw(Box::new(HazardRecord {
+            active: AtomicBool::new(true),
+            hazards: [AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut())],
+            retired: UnsafeCell::new(Vec::new()),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            unsafe { (*record).next = head };
+            match self
+                .head
+                .compare_exchange_weak(head, record, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+        self.records.fetch_add(1, Ordering::Relaxed);
+        HazardGuard {
+            domain: self,
+            record: unsafe { &*record },
+        }
+    }
+
+    fn collect_hazards(&self) -> Vec<*mut u8> {
+        let mut hazards = Vec::new();
+        let mut current = self.head.load(Ordering::Acquire);
+        while let Some(record) = unsafe { current.as_ref() } {
+            for hazard in &record.hazards {
+                let p = hazard.load(Ordering::SeqCst);
+                if !p.is_null() {
+                    hazards.push(p);
+                }
+            }
+            current = record.next;
+        }
+        hazards
+    }
+}
+
+impl Drop for HazardDomain {
+    fn drop(&mut self) {
+        let mut current = *self.head.get_mut();
+        while !current.is_null() {
+            let record = unsafe { Box::from_raw(current) };
+            for retired in record.retired.into_inner() {
+                unsafe { (retired.free)(retired.ptr) };
+            }
+            current = record.next;
+        }
+    }
+}
+
+struct HazardGuard<'a> {
+    domain: &'a HazardDomain,
+    record: &'a HazardRecord,
+}
+
+impl HazardGuard<'_> {
+    /// Loads `src` and publishes it in hazard slot `index`, retrying until
+    /// the published pointer is still current.
+    fn protect<N>(&self, index: usize, src: &AtomicPtr<N>) -> *mut N {
+        let mut p = src.load(Ordering::Acquire);
+        loop {
+            self.record.hazards[index].store(p as *mut u8, Ordering::SeqCst);
+            let again = src.load(Ordering::SeqCst);
+            if again == p {
+                return p;
+            }
+            p = again;
+        }
+    }
+
+    fn clear(&self) {
+        for hazard in &self.record.hazards {
+            hazard.store(ptr::null_mut(), Ordering::Release);
+        }
+    }
+
+    /// Hands `ptr` over for freeing once no thread protects it.
+    unsafe fn retire(&self, ptr: *mut u8, free: unsafe fn(*mut u8)) {
+        let retired = unsafe { &mut *self.record.retired.get() };
+        retired.push(Retired { ptr, free });
+        let threshold = 2 * self.domain.records.load(Ordering::Relaxed) + 16;
+        if retired.len() >= threshold {
+            let hazards = self.domain.collect_hazards();
+            retired.retain(|r| {
+                if hazards.contains(&r.ptr) {
+                    true
+                } else {
+                    unsafe { (r.free)(r.ptr) };
+                    false
+                }
+            });
+        }
+    }
+}
+
+impl Drop for HazardGuard<'_> {
+    fn drop(&mut self) {
+        self.clear();
+        self.record.active.store(false, Ordering::Release);
+    }
+}
+
+struct Node<T> {
+    value: UnsafeCell<MaybeUninit<T>>,
+    next: AtomicPtr<Node<T>>,
+}
+
+impl<T> Node<T> {
+    fn alloc(value: MaybeUninit<T>) -> *mut Node<T> {
+        Box::into_raw(Box::new(Node {
+            value: UnsafeCell::new(value),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }))
+    }
+
+    /// Frees a node whose value has already been moved out.
+    unsafe fn free(ptr: *mut u8) {
+        drop(unsafe { Box::from_raw(ptr as *mut Node<T>) });
+    }
+}
+
+/// An unbounded multi-producer multi-consumer queue (Michael & Scott).
+///
+/// `head` always points to a dummy node; the first real value lives in
+/// `head.next`. Dequeuing swings `head` forward and retires the old dummy.
+pub struct SegQueue<T> {
+    head: CachePadded<AtomicPtr<Node<T>>>,
+    tail: CachePadded<AtomicPtr<Node<T>>>,
+    domain: HazardDomain,
+}
+
+unsafe impl<T: Send> Send for SegQueue<T> {}
+unsafe impl<T: Send> Sync for SegQueue<T> {}
+
+impl<T> SegQueue<T> {
+    pub fn new() -> Self {
+        let dummy = Node::<T>::alloc(MaybeUninit::uninit());
+        SegQueue {
+            head: CachePadded(AtomicPtr::new(dummy)),
+            tail: CachePadded(AtomicPtr::new(dummy)),
+            domain: HazardDomain::new(),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        let node = Node::alloc(MaybeUninit::new(value));
+        let guard = self.domain.acquire();
+        loop {
+            let tail = guard.protect(0, &self.tail);
+            let next = unsafe { (*tail).next.load(Ordering::Acquire) };
+            if tail != self.tail.load(Ordering::Acquire) {
+                continue;
+            }
+            if next.is_null() {
+                let linked = unsafe {
+                    (*tail)
+                        .next
+                        .compare_exchange(ptr::null_mut(), node, Ordering::Release, Ordering::Relaxed)
+                        .is_ok()
+                };
+                if linked {
+                    let _ = self
+                        .tail
+                        .compare_exchange(tail, node, Ordering::Release, Ordering::Relaxed);
+                    return;
+                }
+            } else {
+                // Another producer linked a node but has not moved the tail yet.
+                let _ = self
+                    .tail
+                    .compare_exchange(tail, next, Ordering::Release, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let guard = self.domain.acquire();
+        loop {
+            let head = guard.protect(0, &self.head);
+            let tail = self.tail.load(Ordering::Acquire);
+            let next = guard.protect(1, unsafe { &(*head).next });
+            if head != self.head.load(Ordering::Acquire) {
+                continue;
+            }
+            if next.is_null() {
+                return None;
+            }
+            if head == tail {
+                let _ = self
+                    .tail
+                    .compare_exchange(tail, next, Ordering::Release, Ordering::Relaxed);
+                continue;
+            }
+            if self
+                .head
+                .compare_exchange(head, next, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                // `next` is the new dummy; we own its value. It stays
+                // protected by hazard 1 until the read is done.
+                let value = unsafe { (*(*next).value.get()).assume_init_read() };
+                guard.clear();
+                unsafe { guard.retire(head as *mut u8, Node::<T>::free) };
+                return Some(value);
+            }
+        }
+    }
+
+    /// Whether the queue looked empty at the moment of the call.
+    pub fn is_empty(&self) -> bool {
+        let guard = self.domain.acquire();
+        let head = guard.protect(0, &self.head);
+        unsafe { (*head).next.load(Ordering::Acquire).is_null() }
+    }
+}
+
+impl<T> Default for SegQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for SegQueue<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+        let dummy = *self.head.0.get_mut();
+        unsafe { Node::<T>::free(dummy as *mut u8) };
+        // Retired nodes are freed when `domain` drops.
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThroughputResult {
+    pub name: &'static str,
+    pub items: usize,
+    pub elapsed: Duration,
+    /// Sum of every received item; equal across queues when nothing was lost.
+    pub checksum: u64,
+}
+
+impl ThroughputResult {
+    pub fn items_per_sec(&self) -> f64 {
+        self.items as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Runs `producers` threads each sending `per_producer` numbers through
+/// `push`, while `consumers` threads drain them through `pop`.
+fn run_stress<P, C>(name: &'static str, producers: usize, consumers: usize, per_producer: usize, push: P, pop: C) -> ThroughputResult
+where
+    P: Fn(u64) + Send + Sync + 'static,
+    C: Fn() -> Option<u64> + Send + Sync + 'static,
+{
+    let push = Arc::new(push);
+    let pop = Arc::new(pop);
+    let total = producers * per_producer;
+    let received = Arc::new(AtomicUsize::new(0));
+    let start = Arc::new(Barrier::new(producers + consumers + 1));
+
+    let producer_handles: Vec<_> = (0..producers)
+        .map(|p| {
+            let push = Arc::clone(&push);
+            let start = Arc::clone(&start);
+            thread::spawn(move || {
+                start.wait();
+                for i in 0..per_producer {
+                    push((p * per_producer + i) as u64);
+                }
+            })
+        })
+        .collect();
+    let consumer_handles: Vec<_> = (0..consumers)
+        .map(|_| {
+            let pop = Arc::clone(&pop);
+            let received = Arc::clone(&received);
+            let start = Arc::clone(&start);
+            thread::spawn(move || {
+                start.wait();
+                let mut sum = 0u64;
+                while received.load(Ordering::Relaxed) < total {
+                    match pop() {
+                        Some(v) => {
+                            sum += v;
+                            received.fetch_add(1, Ordering::Relaxed);
+                        }
+                        None => thread::yield_now(),
+                    }
+                }
+                sum
+            })
+        })
+        .collect();
+
+    start.wait();
+    let began = Instant::now();
+    for h in producer_handles {
+        h.join().unwrap();
+    }
+    let checksum = consumer_handles.into_iter().map(|h| h.join().unwrap()).sum();
+    ThroughputResult {
+        name,
+        items: total,
+        elapsed: began.elapsed(),
+        checksum,
+    }
+}
+
+/// Compares `ArrayQueue`, `SegQueue` and `mpsc::sync_channel` under the same
+/// producer/consumer load. The channel's receiver is shared through a mutex,
+/// since `mpsc` supports only one consumer.
+pub fn compare_throughput(producers: usize, consumers: usize, per_producer: usize, capacity: usize) -> Vec<ThroughputResult> {
+    let producers = producers.max(1);
+    let consumers = consumers.max(1);
+
+    let array = Arc::new(ArrayQueue::new(capacity));
+    let array_pop = Arc::clone(&array);
+    let array_result = run_stress(
+        "ArrayQueue",
+        producers,
+        consumers,
+        per_producer,
+        move |mut v| {
+            while let Err(back) = array.push(v) {
+                v = back;
+                thread::yield_now();
+            }
+        },
+        move || array_pop.pop(),
+    );
+
+    let seg = Arc::new(SegQueue::new());
+    let seg_pop = Arc::clone(&seg);
+    let seg_result = run_stress(
+        "SegQueue",
+        producers,
+        consumers,
+        per_producer,
+        move |v| seg.push(v),
+        move || seg_pop.pop(),
+    );
+
+    let (sender, receiver) = mpsc::sync_channel(capacity.max(1));
+    let receiver = Mutex::new(receiver);
+    let channel_result = run_stress(
+        "mpsc::sync_channel",
+        producers,
+        consumers,
+        per_producer,
+        move |v| sender.send(v).unwrap(),
+        move || receiver.lock().unwrap().try_recv().ok(),
+    );
+
+    vec![array_result, seg_result, channel_result]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_array_queue_fifo_and_capacity() {
+        let q = ArrayQueue::new(3);
+        assert_eq!(q.capacity(), 4);
+        for i in 0..4 {
+            q.push(i).unwrap();
+        }
+        assert_eq!(q.push(99), Err(99));
+        assert_eq!(q.len(), 4);
+        assert_eq!(q.pop(), Some(0));
+        q.push(4).unwrap();
+        assert_eq!((1..=4).map(|_| q.pop().unwrap()).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+        assert_eq!(q.pop(), None);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn test_seg_queue_fifo() {
+        let q = SegQueue::new();
+        assert!(q.is_empty());
+        for i in 0..100 {
+            q.push(i);
+        }
+        for i in 0..100 {
+            assert_eq!(q.pop(), Some(i));
+        }
+        assert_eq!(q.pop(), None);
+    }
+
+    #[test]
+    fn test_drop_releases_remaining_items() {
+        let item = Arc::new(());
+        {
+            let array = ArrayQueue::new(8);
+            let seg = SegQueue::new();
+            for _ in 0..5 {
+                array.push(Arc::clone(&item)).unwrap();
+                seg.push(Arc::clone(&item));
+            }
+            drop(seg.pop());
+            assert_eq!(Arc::strong_count(&item), 10);
+        }
+        assert_eq!(Arc::strong_count(&item), 1);
+    }
+
+    fn mpmc_exactly_once<Q, P, C>(queue: Arc<Q>, push: P, pop: C)
+    where
+        Q: Send + Sync + 'static,
+        P: Fn(&Q, usize) + Send + Sync + Copy + 'static,
+        C: Fn(&Q) -> Option<usize> + Send + Sync + Copy + 'static,
+    {
+        const PRODUCERS: usize = 4;
+        const PER_PRODUCER: usize = 5_000;
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|p| {
+                let q = Arc::clone(&queue);
+                thread::spawn(move || {
+                    for i in 0..PER_PRODUCER {
+                        push(&q, p * PER_PRODUCER + i);
+                    }
+                })
+            })
+            .collect();
+        let seen = Arc::new(Mutex::new(HashSet::new()));
+        let remaining = Arc::new(AtomicUsize::new(PRODUCERS * PER_PRODUCER));
+        let consumers: Vec<_> = (0..4)
+            .map(|_| {
+                let q = Arc::clone(&queue);
+                let seen = Arc::clone(&seen);
+                let remaining = Arc::clone(&remaining);
+                thread::spawn(move || {
+                    while remaining.load(Ordering::SeqCst) > 0 {
+                        if let Some(v) = pop(&q) {
+                            assert!(seen.lock().unwrap().insert(v), "duplicate {}", v);
+                            remaining.fetch_sub(1, Ordering::SeqCst);
+                        } else {
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for h in producers.into_iter().chain(consumers) {
+            h.join().unwrap();
+        }
+        assert_eq!(seen.lock().unwrap().len(), PRODUCERS * PER_PRODUCER);
+    }
+
+    #[test]
+    fn test_array_queue_mpmc() {
+        mpmc_exactly_once(
+            Arc::new(ArrayQueue::new(64)),
+            |q: &ArrayQueue<usize>, mut v| {
+                while let Err(back) = q.push(v) {
+                    v = back;
+                    thread::yield_now();
+                }
+            },
+            |q| q.pop(),
+        );
+    }
+
+    #[test]
+    fn test_seg_queue_mpmc() {
+        mpmc_exactly_once(Arc::new(SegQueue::new()), |q: &SegQueue<usize>, v| q.push(v), |q| q.pop());
+    }
+
+    #[test]
+    fn test_seg_queue_per_producer_order() {
+        let q = Arc::new(SegQueue::new());
+        let producers: Vec<_> = (0..3)
+            .map(|p| {
+                let q = Arc::clone(&q);
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        q.push((p, i));
+                    }
+                })
+            })
+            .collect();
+        for h in producers {
+            h.join().unwrap();
+        }
+        let mut last = [-1i32; 3];
+        while let Some((p, i)) = q.pop() {
+            assert!(i > last[p]);
+            last[p] = i;
+        }
+        assert_eq!(last, [999, 999, 999]);
+    }
+
+    #[test]
+    fn test_compare_throughput() {
+        let results = compare_throughput(2, 2, 2_000, 128);
+        let names: Vec<_> = results.iter().map(|r| r.name).collect();
+        assert_eq!(names, vec!["ArrayQueue", "SegQueue", "mpsc::sync_channel"]);
+        let expected: u64 = (0..4_000u64).sum();
+        for r in &results {
+            assert_eq!(r.items, 4_000);
+            assert_eq!(r.checksum, expected, "{} lost or duplicated items", r.name);
+            assert!(r.items_per_sec() > 0.0);
+        }
+    }
+}
@@ -0,0 +1,440 @@
+//! Exercise 34: Epoch-Based Memory Reclamation
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Pin threads to a global epoch while they read shared pointers
+//! - Defer destruction of unlinked nodes until no pinned thread can see them
+//! - Advance the global epoch once every pinned thread has caught up
+//! - Hand garbage of exiting threads over to the survivors
+//!
+//! A node unlinked while the global epoch is `e` may still be read by threads
+//! pinned in `e` (or `e - 1` that have not noticed the advance yet). The epoch
+//! can only move past `e + 1` after every pinned thread has re-pinned in a
+//! later epoch, so garbage tagged `e` is safe to free once the global epoch
+//! reaches `e + 2`. `exercise_29::LockFreeStack` uses this module to free
+//! popped nodes without use-after-free or ABA.
+
+use std::cell::{Cell, RefCell};
+use std::ptr;
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// Local garbage is collected once a thread has this many deferred items.
+/// Threads that stop deferring before reaching it should call `flush`.
+const COLLECT_THRESHOLD: usize = 64;
+
+enum Deferred {
+    Call(Box<dyn FnOnce() + Send>),
+    /// Type-erased `Box` destruction; a fn pointer needs no `'static` bound on `T`.
+    Destroy { ptr: *mut u8, free: unsafe fn(*mut u8) },
+}
+
+// `Destroy` pointers are only handed over by `defer_destroy`, whose caller
+// promises the pointee may be dropped on any thread.
+unsafe impl Send for Deferred {}
+
+impl Deferred {
+    fn run(self) {
+        match self {
+            Deferred::Call(f) => f(),
+            Deferred::Destroy { ptr, free } => unsafe { free(ptr) },
+        }
+    }
+}
+
+unsafe fn free_box<T>(ptr: *mut u8) {
+    drop(unsafe { Box::from_raw(ptr as *mut T) });
+}
+
+/// A thread's slot in the participant list. Slots are reused by later
+/// threads but never freed, so the list can be walked without locking.
+struct Participant {
+    /// `epoch << 1 | 1` while pinned, `0` while not.
+    state: AtomicUsize,
+    in_use: AtomicBool,
+    next: *mut Participant,
+}
+
+struct Global {
+    epoch: AtomicUsize,
+    participants: AtomicPtr<Participant>,
+    /// Garbage left behind by threads that exited before it could be freed.
+    orphans: Mutex<Vec<(usize, Deferred)>>,
+    pending: AtomicUsize,
+}
+
+fn global() -> &'static Global {
+    static GLOBAL: OnceLock<Global> = OnceLock::new();
+    GLOBAL.get_or_init(|| Global {
+        epoch: AtomicUsize::new(0),
+        participants: AtomicPtr::new(ptr::null_mut()),
+        orphans: Mutex::new(Vec::new()),
+        pending: AtomicUsize::new(0),
+    })
+}
+
+impl Global {
+    fn register(&self) -> &'static Participant {
+        let mut current = self.participants.load(Ordering::Acquire);
+        while let Some(p) = unsafe { current.as_ref() } {
+            if !p.in_use.load(Ordering::Relaxed)
+                && p
+                    .in_use
+                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return p;
+            }
+            current = p.next;
+        }
+        let participant = Box::into_raw(Box::new(Participant {
+            state: AtomicUsize::new(0),
+            in_use: AtomicBool::new(true),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.participants.load(Ordering::Acquire);
+        loop {
+            unsafe { (*participant).next = head };
+            match self.participants.compare_exchange_weak(
+                head,
+                participant,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return unsafe { &*participant },
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Advances the global epoch if every pinned thread has observed it.
+    fn try_advance(&self) -> usize {
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        let mut current = self.participants.load(Ordering::Acquire);
+        while let Some(p) = unsafe { current.as_ref() } {
+            let state = p.state.load(Ordering::Relaxed);
+            if state & 1 == 1 && state >> 1 != epoch {
+                return epoch;
+            }
+            current = p.next;
+        }
+        fence(Ordering::Acquire);
+        match self
+            .epoch
+            .compare_exchange(epoch, epoch + 1, Ordering::Release, Ordering::Relaxed)
+        {
+            Ok(_) => epoch + 1,
+            Err(now) => now,
+        }
+    }
+
+    fn collect_orphans(&self, epoch: usize) {
+        let ready: Vec<Deferred> = match self.orphans.try_lock() {
+            Ok(mut orphans) => {
+                let (ready, keep): (Vec<_>, Vec<_>) =
+                    orphans.drain(..).partition(|(tag, _)| tag + 2 <= epoch);
+                *orphans = keep;
+                ready.into_iter().map(|(_, f)| f).collect()
+            }
+            Err(_) => return,
+        };
+        self.run(ready);
+    }
+
+    fn run(&self, deferred: Vec<Deferred>) {
+        let count = deferred.len();
+        for item in deferred {
+            item.run();
+        }
+        self.pending.fetch_sub(count, Ordering::Relaxed);
+    }
+}
+
+struct Local {
+    participant: &'static Participant,
+    guards: Cell<usize>,
+    bag: RefCell<Vec<(usize, Deferred)>>,
+}
+
+impl Local {
+    fn new() -> Self {
+        Local {
+            participant: global().register(),
+            guards: Cell::new(0),
+            bag: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn pin(&self) {
+        let guards = self.guards.get();
+        self.guards.set(guards + 1);
+        if guards == 0 {
+            let epoch = global().epoch.load(Ordering::Relaxed);
+            self.participant.state.store(epoch << 1 | 1, Ordering::Relaxed);
+            // Order the announcement before any load of shared pointers.
+            fence(Ordering::SeqCst);
+        }
+    }
+
+    fn unpin(&self) {
+        let guards = self.guards.get() - 1;
+        self.guards.set(guards);
+        if guards == 0 {
+            self.participant.state.store(0, Ordering::Release);
+            if self.bag.borrow().len() >= COLLECT_THRESHOLD {
+                self.collect();
+            }
+        }
+    }
+
+    fn defer(&self, f: Deferred) {
+        // Pairs with the fence in `try_advance`: either that fence comes
+        // first and this load sees the epoch it then saw (or a newer one), or
+        // this fence comes first and the advancing thread sees every pin made
+        // before the caller unlinked the object. Without it the tag could
+        // lag one epoch behind and free the object while a thread pinned in
+        // the newer epoch still holds it.
+        fence(Ordering::SeqCst);
+        let epoch = global().epoch.load(Ordering::Relaxed);
+        global().pending.fetch_add(1, Ordering::Relaxed);
+        self.bag.borrow_mut().push((epoch, f));
+    }
+
+    /// Tries to advance the epoch and runs every deferred item that is old enough.
+    fn collect(&self) {
+        let epoch = global().try_advance();
+        let ready: Vec<Deferred> = {
+            let mut bag = self.bag.borrow_mut();
+            let (ready, keep): (Vec<_>, Vec<_>) =
+                bag.drain(..).partition(|(tag, _)| tag + 2 <= epoch);
+            *bag = keep;
+            ready.into_iter().map(|(_, f)| f).collect()
+        };
+        global().run(ready);
+        global().collect_orphans(epoch);
+    }
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        let bag = std::mem::take(self.bag.get_mut());
+        if !bag.is_empty() {
+            global().orphans.lock().unwrap().extend(bag);
+        }
+        self.participant.state.store(0, Ordering::Release);
+        self.participant.in_use.store(false, Ordering::Release);
+    }
+}
+
+thread_local! {
+    static LOCAL: Local = Local::new();
+}
+
+/// Keeps the current thread pinned. Pointers loaded from shared structures
+/// stay valid until the guard is dropped.
+pub struct Guard {
+    // Guards are tied to the thread that created them.
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+/// Pins the current thread. Guards nest; the thread is unpinned when the
+/// outermost guard drops.
+pub fn pin() -> Guard {
+    LOCAL.with(Local::pin);
+    Guard {
+        _not_send: std::marker::PhantomData,
+    }
+}
+
+impl Guard {
+    /// Runs `f` once no thread pinned now can still be using what it frees.
+    pub fn defer<F: FnOnce() + Send + 'static>(&self, f: F) {
+        LOCAL.with(|local| local.defer(Deferred::Call(Box::new(f))));
+    }
+
+    /// Defers `drop(Box::from_raw(ptr))`.
+    ///
+    /// # Safety
+    /// `ptr` must come from `Box::into_raw`, be unreachable for threads that
+    /// pin from now on, and be destroyed only once. Its drop may run on
+    /// another thread.
+    pub unsafe fn defer_destroy<T>(&self, ptr: *mut T) {
+        LOCAL.with(|local| {
+            local.defer(Deferred::Destroy {
+                ptr: ptr as *mut u8,
+                free: free_box::<T>,
+            })
+        });
+    }
+
+    /// Tries to advance the epoch and frees whatever has become safe.
+    pub fn flush(&self) {
+        LOCAL.with(Local::collect);
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        // `try_with`: the guard may be dropped during thread-local teardown.
+        let _ = LOCAL.try_with(Local::unpin);
+    }
+}
+
+/// The current global epoch.
+pub fn epoch() -> usize {
+    global().epoch.load(Ordering::Relaxed)
+}
+
+/// Number of deferred destructions that have not run yet, across all threads.
+pub fn pending() -> usize {
+    global().pending.load(Ordering::Relaxed)
+}
+
+/// Tries to advance the epoch and frees this thread's garbage (and adopted
+/// garbage of exited threads) that has become safe. Returns how many items
+/// are still waiting in this thread's bag.
+///
+/// Garbage is otherwise only collected once a bag reaches
+/// `COLLECT_THRESHOLD`, so a thread that is about to go idle with a few
+/// deferred items should call this, repeatedly if it must see them freed.
+pub fn flush() -> usize {
+    LOCAL.with(|local| {
+        local.collect();
+        local.bag.borrow().len()
+    })
+}
+
+/// Repeatedly advances the epoch from an unpinned thread and runs ready
+/// garbage. Returns the number of items still pending afterwards, which is
+/// zero unless some other thread is pinned or holds unflushed garbage.
+pub fn flush_all() -> usize {
+    for _ in 0..4 {
+        LOCAL.with(Local::collect);
+    }
+    pending()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    struct DropCounter(Arc<AtomicUsize>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_guards_nest() {
+        let outer = pin();
+        let inner = pin();
+        drop(inner);
+        let state = LOCAL.with(|l| l.participant.state.load(Ordering::SeqCst));
+        assert_eq!(state & 1, 1);
+        drop(outer);
+        let state = LOCAL.with(|l| l.participant.state.load(Ordering::SeqCst));
+        assert_eq!(state, 0);
+    }
+
+    #[test]
+    fn test_pinned_thread_blocks_reclamation() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let pinned = Arc::new(Barrier::new(2));
+        let release = Arc::new(Barrier::new(2));
+        let holder = {
+            let pinned = Arc::clone(&pinned);
+            let release = Arc::clone(&release);
+            thread::spawn(move || {
+                let _guard = pin();
+                pinned.wait();
+                release.wait();
+            })
+        };
+        pinned.wait();
+
+        let guard = pin();
+        let node = Box::into_raw(Box::new(DropCounter(Arc::clone(&drops))));
+        unsafe { guard.defer_destroy(node) };
+        drop(guard);
+        for _ in 0..8 {
+            pin().flush();
+        }
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        release.wait();
+        holder.join().unwrap();
+        for _ in 0..8 {
+            if drops.load(Ordering::SeqCst) == 1 {
+                break;
+            }
+            pin().flush();
+        }
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_exited_thread_garbage_is_adopted() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&drops);
+        thread::spawn(move || {
+            let guard = pin();
+            for _ in 0..10 {
+                let node = Box::into_raw(Box::new(DropCounter(Arc::clone(&counter))));
+                unsafe { guard.defer_destroy(node) };
+            }
+        })
+        .join()
+        .unwrap();
+        for _ in 0..16 {
+            if drops.load(Ordering::SeqCst) == 10 {
+                break;
+            }
+            flush_all();
+        }
+        assert_eq!(drops.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn test_flush_frees_small_bag_of_live_thread() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&drops);
+        let (freed_tx, freed_rx) = std::sync::mpsc::channel();
+        let worker = thread::spawn(move || {
+            let guard = pin();
+            for _ in 0..3 {
+                let node = Box::into_raw(Box::new(DropCounter(Arc::clone(&counter))));
+                unsafe { guard.defer_destroy(node) };
+            }
+            drop(guard);
+            // Well below COLLECT_THRESHOLD: nothing frees these but `flush`.
+            let mut left = 3;
+            for _ in 0..2_000 {
+                left = flush();
+                if left == 0 {
+                    break;
+                }
+                thread::sleep(std::time::Duration::from_millis(1));
+            }
+            freed_tx.send(left).unwrap();
+            // Still alive here, so nothing was handed over as orphans.
+            thread::park();
+        });
+        assert_eq!(freed_rx.recv().unwrap(), 0);
+        assert_eq!(drops.load(Ordering::SeqCst), 3);
+        worker.thread().unpark();
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn test_epoch_advances_when_unpinned() {
+        let before = epoch();
+        flush_all();
+        assert!(epoch() > before);
+    }
+}
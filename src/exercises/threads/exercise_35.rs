@@ -0,0 +1,655 @@
+//! Exercise 35: Read-Optimised Primitives - SeqLock and RCU Cell
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Take consistent snapshots without blocking writers (sequence locks)
+//! - Copy shared data with atomic loads so torn reads are never observed
+//! - Publish new versions of data while readers keep old ones (RCU)
+//! - Measure reader throughput against `std::sync::RwLock`
+//!
+//! Exercise 30's `SeqLock<T: Copy>` reads `T` directly, which races with the
+//! writer. This `SeqLock` copies the value word by word (or byte by byte)
+//! with relaxed atomics into a `MaybeUninit<T>` and only turns it into a `T`
+//! once the sequence number proves the copy was not torn. That works for any
+//! `NoUninit` type, even ones with invalid bit patterns such as `bool` or
+//! `char`. Types with padding or enum payloads are excluded: their
+//! uninitialised bytes may not be read as integers, which is what the atomic
+//! copy does. For data that cannot be copied byte-wise (`String`, `Vec`, ...)
+//! `RcuCell` hands readers an `Arc<T>` of the current version instead; old
+//! versions are released through exercise 34's epoch collector.
+
+use super::exercise_34 as epoch;
+use std::cell::UnsafeCell;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicU64, AtomicUsize, Ordering, fence};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Types whose value is fully captured by a copy of their bytes, all of
+/// which are always initialised.
+///
+/// # Safety
+/// Every byte of every value must be initialised: no padding and no enum
+/// variants with payloads of different sizes. A bitwise copy must also be a
+/// valid, independent value: no drop glue, no owned pointers, no interior
+/// references.
+pub unsafe trait NoUninit: Sized {}
+
+macro_rules! no_uninit {
+    ($($t:ty),*) => { $(unsafe impl NoUninit for $t {})* };
+}
+
+no_uninit!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool, char
+);
+
+unsafe impl<T: NoUninit, const N: usize> NoUninit for [T; N] {}
+
+/// A sequence lock for any `NoUninit` type.
+///
+/// The sequence number is odd while a write is in progress. Readers retry
+/// until they see the same even number before and after their copy.
+pub struct SeqLock<T> {
+    seq: AtomicUsize,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: NoUninit + Send> Send for SeqLock<T> {}
+unsafe impl<T: NoUninit + Send> Sync for SeqLock<T> {}
+
+impl<T: NoUninit> SeqLock<T> {
+    pub fn new(value: T) -> Self {
+        SeqLock {
+            seq: AtomicUsize::new(0),
+            data: UnsafeCell::new(MaybeUninit::new(value)),
+        }
+    }
+
+    /// Returns a consistent copy of the value.
+    pub fn read(&self) -> T {
+        loop {
+            if let Some(value) = self.try_read() {
+                return value;
+            }
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Returns a copy, or `None` if a write overlapped the attempt.
+    pub fn try_read(&self) -> Option<T> {
+        let before = self.seq.load(Ordering::Acquire);
+        if before & 1 == 1 {
+            return None;
+        }
+        let mut copy = MaybeUninit::<T>::uninit();
+        unsafe {
+            atomic_copy::<T>(
+                self.data.get() as *const u8,
+                copy.as_mut_ptr() as *mut u8,
+                true,
+            )
+        };
+        fence(Ordering::Acquire);
+        if self.seq.load(Ordering::Relaxed) == before {
+            // The copy matches a single completed write.
+            Some(unsafe { copy.assume_init() })
+        } else {
+            None
+        }
+    }
+
+    pub fn write(&self, value: T) {
+        let _writing = self.lock_writer();
+        let value = MaybeUninit::new(value);
+        unsafe {
+            atomic_copy::<T>(
+                value.as_ptr() as *const u8,
+                self.data.get() as *mut u8,
+                false,
+            )
+        };
+    }
+
+    /// Replaces the value with `f(current)` as one write. If `f` panics the
+    /// value is left unchanged and the lock is released.
+    pub fn update<F: FnOnce(T) -> T>(&self, f: F) {
+        let _writing = self.lock_writer();
+        // Writers are exclusive, so the current bytes are stable here.
+        let mut current = MaybeUninit::<T>::uninit();
+        unsafe {
+            atomic_copy::<T>(
+                self.data.get() as *const u8,
+                current.as_mut_ptr() as *mut u8,
+                true,
+            )
+        };
+        let next = MaybeUninit::new(f(unsafe { current.assume_init() }));
+        unsafe {
+            atomic_copy::<T>(
+                next.as_ptr() as *const u8,
+                self.data.get() as *mut u8,
+                false,
+            )
+        };
+    }
+
+    /// Number of completed writes.
+    pub fn version(&self) -> usize {
+        self.seq.load(Ordering::Acquire) / 2
+    }
+
+    /// Spins until the sequence is even and makes it odd. The guard makes it
+    /// even again when dropped, including during a panic.
+    fn lock_writer(&self) -> WriteGuard<'_> {
+        let mut seq = self.seq.load(Ordering::Relaxed);
+        loop {
+            if seq & 1 == 0 {
+                match self.seq.compare_exchange_weak(
+                    seq,
+                    seq + 1,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // Keep the data stores after the odd sequence number.
+                        fence(Ordering::Release);
+                        return WriteGuard { seq: &self.seq };
+                    }
+                    Err(current) => seq = current,
+                }
+            } else {
+                std::hint::spin_loop();
+                seq = self.seq.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+struct WriteGuard<'a> {
+    seq: &'a AtomicUsize,
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        self.seq.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl<T: NoUninit + Default> Default for SeqLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Copies `len` bytes with relaxed atomic accesses on the shared
+/// side, a word at a time when the alignment allows it.
+unsafe fn atomic_copy_sized(
+    src: *const u8,
+    dst: *mut u8,
+    len: usize,
+    align: usize,
+    shared_is_src: bool,
+) {
+    let word = mem::size_of::<usize>();
+    let mut offset = 0;
+    if align >= mem::align_of::<usize>() {
+        while offset + word <= len {
+            unsafe {
+                if shared_is_src {
+                    let v = (*(src.add(offset) as *const AtomicUsize)).load(Ordering::Relaxed);
+                    ptr::write_unaligned(dst.add(offset) as *mut usize, v);
+                } else {
+                    let v = ptr::read_unaligned(src.add(offset) as *const usize);
+                    (*(dst.add(offset) as *const AtomicUsize)).store(v, Ordering::Relaxed);
+                }
+            }
+            offset += word;
+        }
+    }
+    while offset < len {
+        unsafe {
+            if shared_is_src {
+                let v = (*(src.add(offset) as *const AtomicU8)).load(Ordering::Relaxed);
+                *dst.add(offset) = v;
+            } else {
+                let v = *src.add(offset);
+                (*(dst.add(offset) as *const AtomicU8)).store(v, Ordering::Relaxed);
+            }
+        }
+        offset += 1;
+    }
+}
+
+/// `atomic_copy_sized` for one `T`; `shared_is_src` says which side other
+/// threads may touch concurrently.
+unsafe fn atomic_copy<T>(src: *const u8, dst: *mut u8, shared_is_src: bool) {
+    unsafe {
+        atomic_copy_sized(
+            src,
+            dst,
+            mem::size_of::<T>(),
+            mem::align_of::<T>(),
+            shared_is_src,
+        )
+    }
+}
+
+/// A cell whose readers get an `Arc<T>` snapshot without locking, while
+/// writers publish whole new versions (read-copy-update).
+///
+/// Readers pin the epoch while bumping the reference count of the current
+/// version; a replaced version's last cell-owned reference is dropped only
+/// after every such reader has unpinned.
+pub struct RcuCell<T: Send + Sync + 'static> {
+    current: AtomicPtr<T>,
+}
+
+impl<T: Send + Sync + 'static> RcuCell<T> {
+    pub fn new(value: T) -> Self {
+        RcuCell {
+            current: AtomicPtr::new(Arc::into_raw(Arc::new(value)) as *mut T),
+        }
+    }
+
+    /// The current version. Never blocks, even while a writer is active.
+    pub fn load(&self) -> Arc<T> {
+        let _guard = epoch::pin();
+        let ptr = self.current.load(Ordering::Acquire);
+        unsafe {
+            Arc::increment_strong_count(ptr);
+            Arc::from_raw(ptr)
+        }
+    }
+
+    /// Publishes `value` and returns the version it replaced.
+    pub fn swap(&self, value: T) -> Arc<T> {
+        let new = Arc::into_raw(Arc::new(value)) as *mut T;
+        let guard = epoch::pin();
+        let old = self.current.swap(new, Ordering::AcqRel);
+        self.retire(&guard, old)
+    }
+
+    pub fn store(&self, value: T) {
+        self.swap(value);
+    }
+
+    /// Publishes `f(current)`, retrying if another writer got there first.
+    /// `f` may run more than once. Returns the version that was published.
+    pub fn rcu<F: FnMut(&T) -> T>(&self, mut f: F) -> Arc<T> {
+        loop {
+            let current = self.load();
+            let new = Arc::into_raw(Arc::new(f(&current))) as *mut T;
+            let guard = epoch::pin();
+            match self.current.compare_exchange(
+                Arc::as_ptr(&current) as *mut T,
+                new,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(old) => {
+                    self.retire(&guard, old);
+                    return unsafe {
+                        Arc::increment_strong_count(new);
+                        Arc::from_raw(new)
+                    };
+                }
+                Err(_) => drop(unsafe { Arc::from_raw(new) }),
+            }
+        }
+    }
+
+    /// Returns a reference to the old version and defers dropping the
+    /// cell's own reference until concurrent `load`s are done with it.
+    fn retire(&self, guard: &epoch::Guard, old: *mut T) -> Arc<T> {
+        let owned = unsafe { Arc::from_raw(old) };
+        let returned = Arc::clone(&owned);
+        guard.defer(move || drop(owned));
+        returned
+    }
+}
+
+impl<T: Send + Sync + 'static> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Arc::from_raw(*self.current.get_mut()) });
+    }
+}
+
+impl<T: Send + Sync + Default + 'static> Default for RcuCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadThroughput {
+    pub name: &'static str,
+    pub readers: usize,
+    pub reads: u64,
+    pub writes: u64,
+    pub elapsed: Duration,
+}
+
+impl ReadThroughput {
+    pub fn reads_per_sec(&self) -> f64 {
+        self.reads as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Runs `readers` threads calling `read` and one thread calling `write` for
+/// `duration`, and counts completed operations.
+fn measure<R, W>(
+    name: &'static str,
+    readers: usize,
+    duration: Duration,
+    read: R,
+    write: W,
+) -> ReadThroughput
+where
+    R: Fn() -> u64 + Send + Sync + 'static,
+    W: Fn(u64) + Send + 'static,
+{
+    let read = Arc::new(read);
+    let stop = Arc::new(AtomicBool::new(false));
+    let reads = Arc::new(AtomicU64::new(0));
+    let start = Instant::now();
+    let reader_handles: Vec<_> = (0..readers)
+        .map(|_| {
+            let read = Arc::clone(&read);
+            let stop = Arc::clone(&stop);
+            let reads = Arc::clone(&reads);
+            thread::spawn(move || {
+                let mut local = 0;
+                let mut last = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    let value = read();
+                    assert!(value >= last, "reader saw an older version");
+                    last = value;
+                    local += 1;
+                }
+                reads.fetch_add(local, Ordering::Relaxed);
+            })
+        })
+        .collect();
+    let writer = {
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            let mut version = 0;
+            while !stop.load(Ordering::Relaxed) {
+                version += 1;
+                write(version);
+                thread::sleep(Duration::from_micros(50));
+            }
+            version
+        })
+    };
+    thread::sleep(duration);
+    stop.store(true, Ordering::Relaxed);
+    for h in reader_handles {
+        h.join().unwrap();
+    }
+    let writes = writer.join().unwrap();
+    ReadThroughput {
+        name,
+        readers,
+        reads: reads.load(Ordering::Relaxed),
+        writes,
+        elapsed: start.elapsed(),
+    }
+}
+
+/// A record big enough that a torn read would be detectable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Sample {
+    pub version: u64,
+    pub checksum: u64,
+    pub payload: [u64; 6],
+}
+
+// Only `u64` fields, so there is no padding.
+unsafe impl NoUninit for Sample {}
+
+impl Sample {
+    pub fn new(version: u64) -> Self {
+        let payload = [version; 6];
+        Sample {
+            version,
+            checksum: payload
+                .iter()
+                .fold(version, |acc, x| acc.wrapping_mul(31).wrapping_add(*x)),
+            payload,
+        }
+    }
+
+    pub fn is_consistent(&self) -> bool {
+        *self == Sample::new(self.version)
+    }
+}
+
+/// Compares reader throughput of `SeqLock`, `RcuCell` and `std::sync::RwLock`
+/// (as used in exercises 12 and 19) with one writer updating a `Sample`.
+pub fn compare_read_throughput(readers: usize, duration: Duration) -> Vec<ReadThroughput> {
+    let readers = readers.max(1);
+
+    let seq = Arc::new(SeqLock::new(Sample::new(0)));
+    let seq_w = Arc::clone(&seq);
+    let seq_result = measure(
+        "SeqLock",
+        readers,
+        duration,
+        move || {
+            let s = seq.read();
+            assert!(s.is_consistent());
+            s.version
+        },
+        move |v| seq_w.write(Sample::new(v)),
+    );
+
+    let rcu = Arc::new(RcuCell::new(Sample::new(0)));
+    let rcu_w = Arc::clone(&rcu);
+    let rcu_result = measure(
+        "RcuCell",
+        readers,
+        duration,
+        move || rcu.load().version,
+        move |v| rcu_w.store(Sample::new(v)),
+    );
+
+    let lock = Arc::new(RwLock::new(Sample::new(0)));
+    let lock_w = Arc::clone(&lock);
+    let lock_result = measure(
+        "RwLock",
+        readers,
+        duration,
+        move || lock.read().unwrap().version,
+        move |v| *lock_w.write().unwrap() = Sample::new(v),
+    );
+
+    vec![seq_result, rcu_result, lock_result]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4 + 4 + 1 + 3 bytes with 4-byte alignment: no padding.
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Dense {
+        count: u32,
+        grade: char,
+        active: bool,
+        flags: [u8; 3],
+    }
+
+    unsafe impl NoUninit for Dense {}
+
+    #[test]
+    fn test_seqlock_basic() {
+        let state = |count, active| Dense {
+            count,
+            grade: 'a',
+            active,
+            flags: [0; 3],
+        };
+        let lock = SeqLock::new(state(1, true));
+        assert_eq!(lock.read(), state(1, true));
+        lock.write(state(2, false));
+        assert_eq!(lock.read(), state(2, false));
+        lock.update(|s| Dense {
+            count: s.count + 1,
+            active: !s.active,
+            ..s
+        });
+        assert_eq!(lock.read(), state(3, true));
+        assert_eq!(lock.version(), 2);
+    }
+
+    #[test]
+    fn test_panicking_update_releases_lock() {
+        let lock = SeqLock::new(5u32);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            lock.update(|_| panic!("bad update"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(lock.read(), 5);
+        lock.write(6);
+        assert_eq!(lock.read(), 6);
+    }
+
+    #[test]
+    fn test_seqlock_odd_sized_type() {
+        let lock = SeqLock::new([7u8; 13]);
+        lock.write([9u8; 13]);
+        assert_eq!(lock.read(), [9u8; 13]);
+    }
+
+    struct Pixel {
+        rgb: [u8; 3],
+    }
+
+    // Not `Copy` on purpose, but plain bytes.
+    unsafe impl NoUninit for Pixel {}
+
+    #[test]
+    fn test_seqlock_non_copy_snapshot_type() {
+        let lock = SeqLock::new(Pixel { rgb: [1, 2, 3] });
+        lock.write(Pixel { rgb: [4, 5, 6] });
+        assert_eq!(lock.read().rgb, [4, 5, 6]);
+    }
+
+    #[test]
+    fn test_seqlock_never_tears() {
+        let lock = Arc::new(SeqLock::new(Sample::new(0)));
+        let writer = {
+            let lock = Arc::clone(&lock);
+            thread::spawn(move || {
+                for v in 1..=20_000 {
+                    lock.write(Sample::new(v));
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let lock = Arc::clone(&lock);
+                thread::spawn(move || {
+                    let mut last = 0;
+                    for _ in 0..20_000 {
+                        let s = lock.read();
+                        assert!(s.is_consistent(), "torn read: {:?}", s);
+                        assert!(s.version >= last);
+                        last = s.version;
+                    }
+                })
+            })
+            .collect();
+        writer.join().unwrap();
+        for r in readers {
+            r.join().unwrap();
+        }
+        assert_eq!(lock.read().version, 20_000);
+    }
+
+    #[test]
+    fn test_concurrent_updates_are_not_lost() {
+        let lock = Arc::new(SeqLock::new(0u64));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let lock = Arc::clone(&lock);
+                thread::spawn(move || {
+                    for _ in 0..1_000 {
+                        lock.update(|n| n + 1);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(lock.read(), 4_000);
+    }
+
+    #[test]
+    fn test_rcu_cell_snapshots() {
+        let cell = RcuCell::new(vec!["a".to_string()]);
+        let before = cell.load();
+        let old = cell.swap(vec!["b".to_string(), "c".to_string()]);
+        assert!(Arc::ptr_eq(&before, &old));
+        assert_eq!(*before, vec!["a".to_string()]);
+        assert_eq!(cell.load().len(), 2);
+    }
+
+    #[test]
+    fn test_rcu_cell_rcu_is_atomic() {
+        let cell = Arc::new(RcuCell::new(0u64));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let cell = Arc::clone(&cell);
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        cell.rcu(|n| n + 1);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(*cell.load(), 2_000);
+    }
+
+    #[test]
+    fn test_rcu_cell_releases_old_versions() {
+        let tracker = Arc::new(());
+        {
+            let cell = RcuCell::new(Arc::clone(&tracker));
+            for _ in 0..100 {
+                cell.store(Arc::clone(&tracker));
+            }
+            let held = cell.load();
+            drop(cell);
+            assert!(Arc::strong_count(&held) >= 1);
+        }
+        for _ in 0..2_000 {
+            epoch::flush_all();
+            if Arc::strong_count(&tracker) == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(Arc::strong_count(&tracker), 1);
+    }
+
+    #[test]
+    fn test_compare_read_throughput() {
+        let results = compare_read_throughput(2, Duration::from_millis(30));
+        let names: Vec<_> = results.iter().map(|r| r.name).collect();
+        assert_eq!(names, vec!["SeqLock", "RcuCell", "RwLock"]);
+        for r in &results {
+            assert!(r.reads > 0, "{} made no progress", r.name);
+            assert!(r.writes > 0);
+            assert!(r.reads_per_sec() > 0.0);
+        }
+    }
+}
@@ -0,0 +1,949 @@
+//! Exercise 37: Channel Library - Flavours and Select
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Build MPMC channels from a `Mutex`, `Condvar`s and a `VecDeque`
+//! - Support bounded, unbounded, rendezvous and broadcast flavours
+//! - Detect disconnection on both ends
+//! - Wait on several receivers at once, with a timeout
+//!
+//! `std::sync::mpsc` (exercises 04, 05, 08 and 18) can only block on one
+//! receiver. Here every channel keeps a list of waiting selectors; sends and
+//! disconnections wake them, so `Select` can sleep until any of its
+//! receivers becomes ready instead of polling.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Disconnected(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendTimeoutError<T> {
+    Timeout(T),
+    Disconnected(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    Timeout,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectError {
+    Timeout,
+    /// Every receiver in the selection is disconnected and drained.
+    Disconnected,
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sending on a disconnected channel")
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "sending on a full channel"),
+            TrySendError::Disconnected(_) => write!(f, "sending on a disconnected channel"),
+        }
+    }
+}
+
+impl<T> fmt::Display for SendTimeoutError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendTimeoutError::Timeout(_) => write!(f, "timed out waiting on send"),
+            SendTimeoutError::Disconnected(_) => write!(f, "sending on a disconnected channel"),
+        }
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "receiving on a disconnected channel")
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "receiving on an empty channel"),
+            TryRecvError::Disconnected => write!(f, "receiving on a disconnected channel"),
+        }
+    }
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => write!(f, "timed out waiting on receive"),
+            RecvTimeoutError::Disconnected => write!(f, "receiving on a disconnected channel"),
+        }
+    }
+}
+
+impl fmt::Display for SelectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectError::Timeout => write!(f, "timed out waiting on select"),
+            SelectError::Disconnected => write!(f, "all selected channels are disconnected"),
+        }
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for SendError<T> {}
+impl<T: fmt::Debug> std::error::Error for TrySendError<T> {}
+impl<T: fmt::Debug> std::error::Error for SendTimeoutError<T> {}
+impl std::error::Error for RecvError {}
+impl std::error::Error for TryRecvError {}
+impl std::error::Error for RecvTimeoutError {}
+impl std::error::Error for SelectError {}
+
+/// Wakes a thread blocked in `Select`.
+struct Signal {
+    fired: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Signal {
+    fn fire(&self) {
+        *self.fired.lock().unwrap() = true;
+        self.cond.notify_one();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flavor {
+    Bounded(usize),
+    Unbounded,
+    /// Capacity zero: `send` returns once a receiver has taken the value.
+    Rendezvous,
+    /// Bounded, but a full queue drops its oldest value instead of blocking.
+    Overwrite(usize),
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receivers: usize,
+    /// Values pushed and popped so far; rendezvous senders wait on these.
+    pushed: u64,
+    taken: u64,
+    waiting_receivers: usize,
+    overwritten: u64,
+    selectors: Vec<(u64, Arc<Signal>)>,
+}
+
+struct Chan<T> {
+    flavor: Flavor,
+    state: Mutex<State<T>>,
+    /// Receivers wait here for values.
+    readable: Condvar,
+    /// Senders wait here for space or, for rendezvous, for a taker.
+    writable: Condvar,
+}
+
+impl<T> Chan<T> {
+    fn new(flavor: Flavor) -> Arc<Self> {
+        Arc::new(Chan {
+            flavor,
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                senders: 1,
+                receivers: 1,
+                pushed: 0,
+                taken: 0,
+                waiting_receivers: 0,
+                overwritten: 0,
+                selectors: Vec::new(),
+            }),
+            readable: Condvar::new(),
+            writable: Condvar::new(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap()
+    }
+
+    fn has_room(&self, state: &State<T>) -> bool {
+        match self.flavor {
+            Flavor::Bounded(cap) => state.queue.len() < cap,
+            Flavor::Unbounded | Flavor::Overwrite(_) => true,
+            Flavor::Rendezvous => state.queue.is_empty(),
+        }
+    }
+
+    fn push(&self, state: &mut State<T>, value: T) -> u64 {
+        if let Flavor::Overwrite(cap) = self.flavor
+            && state.queue.len() >= cap.max(1)
+        {
+            state.queue.pop_front();
+            state.overwritten += 1;
+        }
+        state.queue.push_back(value);
+        state.pushed += 1;
+        self.readable.notify_one();
+        for (_, signal) in &state.selectors {
+            signal.fire();
+        }
+        state.pushed
+    }
+
+    fn pop(&self, state: &mut State<T>) -> Option<T> {
+        let value = state.queue.pop_front()?;
+        state.taken += 1;
+        // Rendezvous senders wait for their own sequence number.
+        self.writable.notify_all();
+        Some(value)
+    }
+
+    /// Blocks until the value fits, the receivers are gone or `deadline` passes.
+    fn send(&self, value: T, deadline: Option<Instant>) -> Result<(), SendTimeoutError<T>> {
+        let mut state = self.lock();
+        loop {
+            if state.receivers == 0 {
+                return Err(SendTimeoutError::Disconnected(value));
+            }
+            if self.has_room(&state) {
+                break;
+            }
+            state = match wait(&self.writable, state, deadline) {
+                Some(state) => state,
+                None => return Err(SendTimeoutError::Timeout(value)),
+            };
+        }
+        let seq = self.push(&mut state, value);
+        if self.flavor != Flavor::Rendezvous {
+            return Ok(());
+        }
+        // Hand-off: wait until a receiver has taken this exact value.
+        loop {
+            if state.taken >= seq {
+                return Ok(());
+            }
+            if state.receivers == 0 {
+                return Err(SendTimeoutError::Disconnected(Self::retract(&mut state)));
+            }
+            state = match wait(&self.writable, state, deadline) {
+                Some(state) => state,
+                None => {
+                    let mut state = self.lock();
+                    if state.taken >= seq {
+                        return Ok(());
+                    }
+                    return Err(SendTimeoutError::Timeout(Self::retract(&mut state)));
+                }
+            };
+        }
+    }
+
+    /// Takes back a rendezvous value nobody received; it is the only one queued.
+    fn retract(state: &mut State<T>) -> T {
+        state.pushed -= 1;
+        state.queue.pop_back().expect("untaken rendezvous value")
+    }
+
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.lock();
+        if state.receivers == 0 {
+            return Err(TrySendError::Disconnected(value));
+        }
+        let ready = match self.flavor {
+            // Succeeds only if a receiver is already blocked waiting.
+            Flavor::Rendezvous => state.queue.is_empty() && state.waiting_receivers > 0,
+            _ => self.has_room(&state),
+        };
+        if !ready {
+            return Err(TrySendError::Full(value));
+        }
+        self.push(&mut state, value);
+        Ok(())
+    }
+
+    fn recv(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let mut state = self.lock();
+        loop {
+            if let Some(value) = self.pop(&mut state) {
+                return Ok(value);
+            }
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            state.waiting_receivers += 1;
+            let next = wait(&self.readable, state, deadline);
+            state = match next {
+                Some(mut state) => {
+                    state.waiting_receivers -= 1;
+                    state
+                }
+                None => {
+                    self.lock().waiting_receivers -= 1;
+                    return Err(RecvTimeoutError::Timeout);
+                }
+            };
+        }
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.lock();
+        match self.pop(&mut state) {
+            Some(value) => Ok(value),
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+/// Waits on `cond` until notified or `deadline`; `None` means it timed out.
+fn wait<'a, S>(
+    cond: &Condvar,
+    guard: MutexGuard<'a, S>,
+    deadline: Option<Instant>,
+) -> Option<MutexGuard<'a, S>> {
+    match deadline {
+        None => Some(cond.wait(guard).unwrap()),
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            Some(cond.wait_timeout(guard, deadline - now).unwrap().0)
+        }
+    }
+}
+
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+/// A channel holding at most `capacity` values; `bounded(0)` is `rendezvous()`.
+pub fn bounded<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    if capacity == 0 {
+        return rendezvous();
+    }
+    pair(Chan::new(Flavor::Bounded(capacity)))
+}
+
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    pair(Chan::new(Flavor::Unbounded))
+}
+
+/// A zero-capacity channel: every `send` waits for a matching `recv`.
+pub fn rendezvous<T>() -> (Sender<T>, Receiver<T>) {
+    pair(Chan::new(Flavor::Rendezvous))
+}
+
+fn pair<T>(chan: Arc<Chan<T>>) -> (Sender<T>, Receiver<T>) {
+    (
+        Sender {
+            chan: Arc::clone(&chan),
+        },
+        Receiver { chan },
+    )
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.chan.send(value, None).map_err(|err| match err {
+            SendTimeoutError::Disconnected(v) | SendTimeoutError::Timeout(v) => SendError(v),
+        })
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.chan.try_send(value)
+    }
+
+    pub fn send_timeout(&self, value: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.chan.send(value, Some(Instant::now() + timeout))
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.chan.lock().receivers == 0
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.lock().senders += 1;
+        Sender {
+            chan: Arc::clone(&self.chan),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.chan.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.chan.readable.notify_all();
+            for (_, signal) in &state.selectors {
+                signal.fire();
+            }
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.chan.recv(None).map_err(|_| RecvError)
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.chan.try_recv()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.chan.recv(Some(Instant::now() + timeout))
+    }
+
+    /// Blocks for each value until all senders are gone.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.recv().ok())
+    }
+
+    pub fn len(&self) -> usize {
+        self.chan.lock().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Values a broadcast subscriber lost because it fell behind.
+    pub fn lagged(&self) -> u64 {
+        self.chan.lock().overwritten
+    }
+
+    /// True when every sender is gone and nothing is left to receive.
+    pub fn is_disconnected(&self) -> bool {
+        let state = self.chan.lock();
+        state.senders == 0 && state.queue.is_empty()
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.chan.lock().receivers += 1;
+        Receiver {
+            chan: Arc::clone(&self.chan),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.chan.lock();
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            self.chan.writable.notify_all();
+        }
+    }
+}
+
+/// Sends a clone of every value to each live subscriber.
+///
+/// Each subscriber has its own queue of `capacity` values; a subscriber that
+/// falls behind loses its oldest values (see `Receiver::lagged`) rather than
+/// blocking the sender or the other subscribers.
+pub struct BroadcastSender<T: Clone> {
+    capacity: usize,
+    subscribers: Arc<Mutex<Vec<Sender<T>>>>,
+}
+
+/// A broadcast channel and its first subscriber.
+pub fn broadcast<T: Clone>(capacity: usize) -> (BroadcastSender<T>, Receiver<T>) {
+    let sender = BroadcastSender {
+        capacity: capacity.max(1),
+        subscribers: Arc::new(Mutex::new(Vec::new())),
+    };
+    let receiver = sender.subscribe();
+    (sender, receiver)
+}
+
+impl<T: Clone> BroadcastSender<T> {
+    /// A new subscriber that sees values sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let (tx, rx) = pair(Chan::new(Flavor::Overwrite(self.capacity)));
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Returns how many subscribers received the value. Fails only if none
+    /// are left.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|tx| !tx.is_disconnected());
+        let Some((last, rest)) = subscribers.split_last() else {
+            return Err(SendError(value));
+        };
+        for tx in rest {
+            // Overwrite channels never block; a race with the last receiver
+            // dropping just loses the clone.
+            let _ = tx.try_send(value.clone());
+        }
+        let _ = last.try_send(value);
+        Ok(subscribers.len())
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|tx| !tx.is_disconnected());
+        subscribers.len()
+    }
+}
+
+impl<T: Clone> Clone for BroadcastSender<T> {
+    fn clone(&self) -> Self {
+        BroadcastSender {
+            capacity: self.capacity,
+            subscribers: Arc::clone(&self.subscribers),
+        }
+    }
+}
+
+/// Type-erased view of a receiver for `Select`.
+trait Selectable {
+    /// A value is queued or the channel is disconnected.
+    fn is_ready(&self) -> bool;
+    /// Disconnected with nothing left to receive.
+    fn is_drained(&self) -> bool;
+    fn register(&self, id: u64, signal: &Arc<Signal>);
+    fn unregister(&self, id: u64);
+}
+
+impl<T> Selectable for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        let state = self.chan.lock();
+        !state.queue.is_empty() || state.senders == 0
+    }
+
+    fn is_drained(&self) -> bool {
+        let state = self.chan.lock();
+        state.queue.is_empty() && state.senders == 0
+    }
+
+    fn register(&self, id: u64, signal: &Arc<Signal>) {
+        self.chan.lock().selectors.push((id, Arc::clone(signal)));
+    }
+
+    fn unregister(&self, id: u64) {
+        self.chan.lock().selectors.retain(|(sid, _)| *sid != id);
+    }
+}
+
+static NEXT_SELECT: AtomicU64 = AtomicU64::new(0);
+
+/// Waits until one of several receivers, possibly of different types, is
+/// ready.
+///
+/// ```ignore
+/// let mut select = Select::new();
+/// let orders = select.recv(&orders_rx);
+/// let shutdown = select.recv(&shutdown_rx);
+/// match select.ready_timeout(Duration::from_secs(1)) {
+///     Ok(i) if i == orders => handle(orders_rx.try_recv()),
+///     Ok(_) | Err(SelectError::Disconnected) => return,
+///     Err(SelectError::Timeout) => tick(),
+/// }
+/// ```
+///
+/// A disconnected receiver counts as ready, so the caller can notice and
+/// drop it. Once every receiver is disconnected and drained (or there are
+/// none), `ready` and `ready_timeout` return `SelectError::Disconnected`.
+///
+/// With cloned receivers another thread may take the value between `ready`
+/// and `try_recv`, so `try_recv` can still return `Empty`.
+pub struct Select<'a> {
+    receivers: Vec<&'a dyn Selectable>,
+    start: usize,
+}
+
+impl Default for Select<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Select<'a> {
+    pub fn new() -> Self {
+        Select {
+            receivers: Vec::new(),
+            start: NEXT_SELECT.fetch_add(1, Ordering::Relaxed) as usize,
+        }
+    }
+
+    /// Adds a receiver and returns its index.
+    pub fn recv<T>(&mut self, receiver: &'a Receiver<T>) -> usize {
+        self.receivers.push(receiver);
+        self.receivers.len() - 1
+    }
+
+    /// Blocks until a receiver has a value or is disconnected.
+    pub fn ready(&mut self) -> Result<usize, SelectError> {
+        self.wait(None)
+    }
+
+    pub fn ready_timeout(&mut self, timeout: Duration) -> Result<usize, SelectError> {
+        self.wait(Some(Instant::now() + timeout))
+    }
+
+    /// Scans from a rotating start so one busy receiver cannot starve the rest.
+    fn poll(&mut self) -> Option<usize> {
+        let n = self.receivers.len();
+        let found = (0..n)
+            .map(|i| (self.start + i) % n)
+            .find(|&i| self.receivers[i].is_ready());
+        self.start = self.start.wrapping_add(1);
+        found
+    }
+
+    /// Nothing can ever become ready again.
+    fn all_drained(&self) -> bool {
+        self.receivers.iter().all(|r| r.is_drained())
+    }
+
+    fn wait(&mut self, deadline: Option<Instant>) -> Result<usize, SelectError> {
+        if self.all_drained() {
+            return Err(SelectError::Disconnected);
+        }
+        if let Some(index) = self.poll() {
+            return Ok(index);
+        }
+        let id = NEXT_SELECT.fetch_add(1, Ordering::Relaxed);
+        let signal = Arc::new(Signal {
+            fired: Mutex::new(false),
+            cond: Condvar::new(),
+        });
+        for receiver in &self.receivers {
+            receiver.register(id, &signal);
+        }
+        let result = loop {
+            // Registered first, so a send after this check fires the signal.
+            if let Some(index) = self.poll() {
+                break Ok(index);
+            }
+            let fired = signal.fired.lock().unwrap();
+            let fired = match deadline {
+                None => signal.cond.wait_while(fired, |f| !*f).unwrap(),
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    let (fired, result) = signal
+                        .cond
+                        .wait_timeout_while(fired, timeout, |f| !*f)
+                        .unwrap();
+                    if result.timed_out() {
+                        drop(fired);
+                        break self.poll().ok_or(SelectError::Timeout);
+                    }
+                    fired
+                }
+            };
+            let mut fired = fired;
+            *fired = false;
+        };
+        for receiver in &self.receivers {
+            receiver.unregister(id);
+        }
+        result
+    }
+}
+
+/// Receives from whichever receiver has a value first, skipping
+/// disconnected ones. Returns the receiver's index with the value.
+pub fn recv_any<T>(
+    receivers: &[&Receiver<T>],
+    timeout: Duration,
+) -> Result<(usize, T), SelectError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let live: Vec<usize> = (0..receivers.len())
+            .filter(|&i| !receivers[i].is_disconnected())
+            .collect();
+        if live.is_empty() {
+            return Err(SelectError::Disconnected);
+        }
+        let mut select = Select::new();
+        for &i in &live {
+            select.recv(receivers[i]);
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let index = live[select.ready_timeout(remaining)?];
+        match receivers[index].try_recv() {
+            Ok(value) => return Ok((index, value)),
+            // Taken by another clone, or just disconnected: look again.
+            Err(_) => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_unbounded_fifo_and_disconnect() {
+        let (tx, rx) = unbounded();
+        for i in 0..5 {
+            tx.send(i).unwrap();
+        }
+        drop(tx);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(rx.recv(), Err(RecvError));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_bounded_backpressure() {
+        let (tx, rx) = bounded(2);
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        assert_eq!(tx.try_send(3), Err(TrySendError::Full(3)));
+        assert_eq!(
+            tx.send_timeout(3, Duration::from_millis(10)),
+            Err(SendTimeoutError::Timeout(3))
+        );
+        let producer = thread::spawn(move || {
+            for i in 3..=10 {
+                tx.send(i).unwrap();
+            }
+        });
+        let received: Vec<_> = rx.iter().collect();
+        producer.join().unwrap();
+        assert_eq!(received, (1..=10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_send_to_dropped_receiver() {
+        let (tx, rx) = bounded(1);
+        drop(rx);
+        assert_eq!(tx.send(7), Err(SendError(7)));
+        assert!(tx.is_disconnected());
+    }
+
+    #[test]
+    fn test_blocked_sender_wakes_on_disconnect() {
+        let (tx, rx) = bounded(1);
+        tx.send(1).unwrap();
+        let sender = thread::spawn(move || tx.send(2));
+        thread::sleep(Duration::from_millis(20));
+        drop(rx);
+        assert_eq!(sender.join().unwrap(), Err(SendError(2)));
+    }
+
+    #[test]
+    fn test_rendezvous_waits_for_receiver() {
+        let (tx, rx) = rendezvous();
+        assert_eq!(tx.try_send(1), Err(TrySendError::Full(1)));
+        assert_eq!(
+            tx.send_timeout(1, Duration::from_millis(10)),
+            Err(SendTimeoutError::Timeout(1))
+        );
+        assert!(rx.is_empty());
+
+        let start = Instant::now();
+        let receiver = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(30));
+            rx.recv().unwrap()
+        });
+        tx.send(42).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert_eq!(receiver.join().unwrap(), 42);
+    }
+
+    #[test]
+    fn test_mpmc_delivers_each_value_once() {
+        let (tx, rx) = bounded(4);
+        let producers: Vec<_> = (0..4)
+            .map(|p| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    for i in 0..250 {
+                        tx.send(p * 1000 + i).unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+        let consumers: Vec<_> = (0..3)
+            .map(|_| {
+                let rx = rx.clone();
+                thread::spawn(move || rx.iter().collect::<Vec<_>>())
+            })
+            .collect();
+        drop(rx);
+        for p in producers {
+            p.join().unwrap();
+        }
+        let mut all: Vec<_> = consumers
+            .into_iter()
+            .flat_map(|c| c.join().unwrap())
+            .collect();
+        all.sort();
+        let mut expected: Vec<_> = (0..4)
+            .flat_map(|p| (0..250).map(move |i| p * 1000 + i))
+            .collect();
+        expected.sort();
+        assert_eq!(all, expected);
+    }
+
+    #[test]
+    fn test_broadcast_fans_out_and_lags() {
+        let (tx, rx1) = broadcast(2);
+        let rx2 = tx.subscribe();
+        assert_eq!(tx.send(1), Ok(2));
+        assert_eq!(rx1.recv(), Ok(1));
+        tx.send(2).unwrap();
+        tx.send(3).unwrap();
+        // rx2 still holds 1, so 1 is overwritten by 3.
+        assert_eq!(rx2.recv(), Ok(2));
+        assert_eq!(rx2.recv(), Ok(3));
+        assert_eq!(rx2.lagged(), 1);
+        assert_eq!(rx1.lagged(), 0);
+
+        drop(rx1);
+        assert_eq!(tx.send(4), Ok(1));
+        assert_eq!(tx.subscriber_count(), 1);
+        drop(tx);
+        assert_eq!(rx2.recv(), Ok(4));
+        assert_eq!(rx2.recv(), Err(RecvError));
+    }
+
+    #[test]
+    fn test_broadcast_without_subscribers() {
+        let (tx, rx) = broadcast::<u8>(4);
+        drop(rx);
+        assert_eq!(tx.send(9), Err(SendError(9)));
+    }
+
+    #[test]
+    fn test_select_mixed_types() {
+        let (num_tx, num_rx) = unbounded::<i32>();
+        let (text_tx, text_rx) = bounded::<String>(1);
+        let mut select = Select::new();
+        let nums = select.recv(&num_rx);
+        let texts = select.recv(&text_rx);
+        assert_eq!(
+            select.ready_timeout(Duration::from_millis(10)),
+            Err(SelectError::Timeout)
+        );
+
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            text_tx.send("hello".to_string()).unwrap();
+            num_tx
+        });
+        assert_eq!(select.ready_timeout(Duration::from_secs(5)), Ok(texts));
+        assert_eq!(text_rx.try_recv().unwrap(), "hello");
+
+        let num_tx = sender.join().unwrap();
+        num_tx.send(5).unwrap();
+        assert_eq!(select.ready(), Ok(nums));
+        assert_eq!(num_rx.try_recv(), Ok(5));
+    }
+
+    #[test]
+    fn test_select_reports_disconnection() {
+        let (tx, rx) = unbounded::<i32>();
+        let (_keep, other) = unbounded::<i32>();
+        let mut select = Select::new();
+        select.recv(&other);
+        let index = select.recv(&rx);
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            drop(tx);
+        });
+        assert_eq!(select.ready_timeout(Duration::from_secs(5)), Ok(index));
+        assert!(rx.is_disconnected());
+    }
+
+    #[test]
+    fn test_select_all_disconnected_or_empty() {
+        let mut empty = Select::new();
+        assert_eq!(empty.ready(), Err(SelectError::Disconnected));
+
+        let (tx1, rx1) = unbounded::<i32>();
+        let (tx2, rx2) = bounded::<&str>(1);
+        tx1.send(1).unwrap();
+        drop((tx1, tx2));
+        let mut select = Select::new();
+        select.recv(&rx1);
+        select.recv(&rx2);
+        // A queued value keeps `rx1` selectable after its sender is gone.
+        assert!(select.ready().is_ok());
+        assert_eq!(rx1.try_recv(), Ok(1));
+        assert_eq!(select.ready(), Err(SelectError::Disconnected));
+        assert_eq!(
+            select.ready_timeout(Duration::from_secs(5)),
+            Err(SelectError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn test_recv_any() {
+        let (tx1, rx1) = unbounded();
+        let (tx2, rx2) = unbounded();
+        drop(tx1);
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            tx2.send("late").unwrap();
+        });
+        assert_eq!(
+            recv_any(&[&rx1, &rx2], Duration::from_secs(5)),
+            Ok((1, "late"))
+        );
+        assert_eq!(
+            recv_any(&[&rx1, &rx2], Duration::from_secs(5)),
+            Err(SelectError::Disconnected)
+        );
+
+        let (_tx3, rx3) = unbounded::<&str>();
+        assert_eq!(
+            recv_any(&[&rx3], Duration::from_millis(10)),
+            Err(SelectError::Timeout)
+        );
+    }
+
+    #[test]
+    fn test_select_is_fair() {
+        let (tx1, rx1) = unbounded();
+        let (tx2, rx2) = unbounded();
+        for i in 0..100 {
+            tx1.send(i).unwrap();
+            tx2.send(i).unwrap();
+        }
+        let mut counts = [0; 2];
+        for _ in 0..100 {
+            let (index, _) = recv_any(&[&rx1, &rx2], Duration::from_secs(1)).unwrap();
+            counts[index] += 1;
+        }
+        assert!(counts[0] > 20 && counts[1] > 20, "{counts:?}");
+    }
+}
@@ -0,0 +1,539 @@
+//! Exercise 38: Thread Pipelines - Multi-Stage Stream Processing
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Chain worker stages with bounded channels for backpressure
+//! - Keep stage types checked by the compiler through a builder
+//! - Restore input order after parallel stages when asked to
+//! - Count per-stage throughput
+//! - Turn a panic in any stage into a clean shutdown and an error
+//!
+//! Exercise 11 wires one set of producers to one set of consumers. A
+//! `Pipeline` generalises that to any number of stages, each with its own
+//! worker threads, connected by exercise 37's bounded MPMC channels. Every
+//! item carries its source sequence number so the sink can put results back
+//! in order. The source takes one slot of a reorder window per item and the
+//! sink gives it back on delivery, so an ordered sink waiting behind one slow
+//! item holds at most the window's worth of later results. When a stage
+//! panics, the first panic is recorded, remaining workers stop taking new
+//! items, and dropping their channel ends unblocks every thread up- and
+//! downstream.
+
+use super::exercise_37::{Receiver, bounded};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const DEFAULT_CAPACITY: usize = 16;
+/// Items in flight between source and sink, per channel slot.
+const WINDOW_PER_SLOT: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipelineError {
+    /// The first panic in the pipeline, with the name of the stage
+    /// (`"source"`, a stage name, or `"sink"`) that raised it.
+    StagePanicked { stage: String, message: String },
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineError::StagePanicked { stage, message } => {
+                write!(f, "pipeline stage '{}' panicked: {}", stage, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "<non-string panic payload>".to_string()
+    }
+}
+
+/// Throughput of one stage after the pipeline finished.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageStats {
+    pub name: String,
+    pub workers: usize,
+    pub items: u64,
+    /// Time spent inside the stage function, summed over workers.
+    pub busy: Duration,
+    /// Wall-clock time of the whole pipeline run.
+    pub elapsed: Duration,
+}
+
+impl StageStats {
+    pub fn items_per_sec(&self) -> f64 {
+        self.items as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Fraction of the available worker time spent doing work.
+    pub fn utilisation(&self) -> f64 {
+        let available = self.elapsed.as_secs_f64() * self.workers as f64;
+        (self.busy.as_secs_f64() / available.max(f64::EPSILON)).min(1.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineReport {
+    /// Source, each stage in order, then the sink.
+    pub stages: Vec<StageStats>,
+    pub elapsed: Duration,
+}
+
+struct StageCounters {
+    name: String,
+    workers: usize,
+    items: AtomicU64,
+    busy_nanos: AtomicU64,
+}
+
+impl StageCounters {
+    fn new(name: String, workers: usize) -> Arc<Self> {
+        Arc::new(StageCounters {
+            name,
+            workers,
+            items: AtomicU64::new(0),
+            busy_nanos: AtomicU64::new(0),
+        })
+    }
+
+    fn record(&self, busy: Duration) {
+        self.items.fetch_add(1, Ordering::Relaxed);
+        self.busy_nanos
+            .fetch_add(busy.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// State shared by every thread of one pipeline.
+struct Shared {
+    started: Instant,
+    abort: AtomicBool,
+    failure: Mutex<Option<PipelineError>>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    counters: Mutex<Vec<Arc<StageCounters>>>,
+    /// Free slots of the reorder window.
+    window: Mutex<usize>,
+    window_freed: Condvar,
+}
+
+impl Shared {
+    fn aborted(&self) -> bool {
+        self.abort.load(Ordering::Acquire)
+    }
+
+    /// Records the first panic and tells every worker to stop.
+    fn fail(&self, stage: &str, payload: Box<dyn Any + Send>) {
+        let mut failure = self.failure.lock().unwrap();
+        if failure.is_none() {
+            *failure = Some(PipelineError::StagePanicked {
+                stage: stage.to_string(),
+                message: panic_message(payload.as_ref()),
+            });
+        }
+        self.abort.store(true, Ordering::Release);
+        // Taking the lock orders the store before a waiting source re-checks.
+        let _window = self.window.lock().unwrap();
+        self.window_freed.notify_all();
+    }
+
+    /// Blocks until a window slot is free. Returns false once aborted.
+    fn take_slot(&self) -> bool {
+        let mut free = self
+            .window_freed
+            .wait_while(self.window.lock().unwrap(), |free| {
+                *free == 0 && !self.aborted()
+            })
+            .unwrap();
+        if self.aborted() {
+            return false;
+        }
+        *free -= 1;
+        true
+    }
+
+    fn release_slot(&self) {
+        *self.window.lock().unwrap() += 1;
+        self.window_freed.notify_one();
+    }
+
+    fn spawn(&self, name: String, f: impl FnOnce() + Send + 'static) {
+        let handle = thread::Builder::new()
+            .name(name)
+            .spawn(f)
+            .expect("failed to spawn pipeline thread");
+        self.handles.lock().unwrap().push(handle);
+    }
+}
+
+/// A running pipeline whose current output items have type `T`.
+///
+/// Threads start as soon as the source or a stage is added; bounded channels
+/// keep them from running ahead of the sink.
+pub struct Pipeline<T> {
+    shared: Arc<Shared>,
+    capacity: usize,
+    ordered: bool,
+    output: Receiver<(u64, T)>,
+}
+
+impl<T: Send + 'static> Pipeline<T> {
+    pub fn source<I>(items: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Send + 'static,
+    {
+        Self::source_with_capacity(items, DEFAULT_CAPACITY)
+    }
+
+    /// Like `source`, with `capacity` slots in every channel of the pipeline.
+    ///
+    /// At most `4 * capacity` items are between the source and the sink at
+    /// any time, including results an ordered sink is holding back.
+    pub fn source_with_capacity<I>(items: I, capacity: usize) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            started: Instant::now(),
+            abort: AtomicBool::new(false),
+            failure: Mutex::new(None),
+            handles: Mutex::new(Vec::new()),
+            counters: Mutex::new(Vec::new()),
+            window: Mutex::new(capacity.max(1) * WINDOW_PER_SLOT),
+            window_freed: Condvar::new(),
+        });
+        let counters = StageCounters::new("source".to_string(), 1);
+        shared.counters.lock().unwrap().push(Arc::clone(&counters));
+        let (tx, rx) = bounded(capacity.max(1));
+        let items = items.into_iter();
+        let worker_shared = Arc::clone(&shared);
+        shared.spawn("pipeline-source".to_string(), move || {
+            let shared = worker_shared;
+            let mut items = items;
+            let mut seq = 0;
+            while shared.take_slot() {
+                let start = Instant::now();
+                let next = match panic::catch_unwind(AssertUnwindSafe(|| items.next())) {
+                    Ok(next) => next,
+                    Err(payload) => return shared.fail("source", payload),
+                };
+                let Some(item) = next else { break };
+                counters.record(start.elapsed());
+                if tx.send((seq, item)).is_err() {
+                    break;
+                }
+                seq += 1;
+            }
+        });
+        Pipeline {
+            shared,
+            capacity: capacity.max(1),
+            ordered: false,
+            output: rx,
+        }
+    }
+
+    /// Adds a stage named `stage N` that runs `f` on `workers` threads.
+    pub fn stage<U, F>(self, workers: usize, f: F) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        F: Fn(T) -> U + Send + Sync + 'static,
+    {
+        let name = format!("stage {}", self.shared.counters.lock().unwrap().len());
+        self.named_stage(name, workers, f)
+    }
+
+    pub fn named_stage<U, F>(self, name: impl Into<String>, workers: usize, f: F) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        F: Fn(T) -> U + Send + Sync + 'static,
+    {
+        let workers = workers.max(1);
+        let counters = StageCounters::new(name.into(), workers);
+        self.shared
+            .counters
+            .lock()
+            .unwrap()
+            .push(Arc::clone(&counters));
+        let (tx, rx) = bounded(self.capacity);
+        let f = Arc::new(f);
+        for worker in 0..workers {
+            let input = self.output.clone();
+            let tx = tx.clone();
+            let f = Arc::clone(&f);
+            let shared = Arc::clone(&self.shared);
+            let counters = Arc::clone(&counters);
+            self.shared
+                .spawn(format!("{}-{}", counters.name, worker), move || {
+                    while !shared.aborted() {
+                        let Ok((seq, item)) = input.recv() else { break };
+                        let start = Instant::now();
+                        match panic::catch_unwind(AssertUnwindSafe(|| f(item))) {
+                            Ok(out) => {
+                                counters.record(start.elapsed());
+                                if tx.send((seq, out)).is_err() {
+                                    break;
+                                }
+                            }
+                            Err(payload) => return shared.fail(&counters.name, payload),
+                        }
+                    }
+                });
+        }
+        Pipeline {
+            shared: self.shared,
+            capacity: self.capacity,
+            ordered: self.ordered,
+            output: rx,
+        }
+    }
+
+    /// Deliver items to the sink in source order.
+    pub fn ordered(mut self) -> Self {
+        self.ordered = true;
+        self
+    }
+
+    /// Deliver items as soon as they are ready (the default).
+    pub fn unordered(mut self) -> Self {
+        self.ordered = false;
+        self
+    }
+
+    /// Feeds every output item to `f` on the calling thread, then waits for
+    /// all pipeline threads to finish.
+    pub fn sink<F: FnMut(T)>(self, mut f: F) -> Result<PipelineReport, PipelineError> {
+        let Pipeline {
+            shared,
+            ordered,
+            output,
+            ..
+        } = self;
+        let counters = StageCounters::new("sink".to_string(), 1);
+        shared.counters.lock().unwrap().push(Arc::clone(&counters));
+
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut deliver = |item: T| {
+                let start = Instant::now();
+                f(item);
+                counters.record(start.elapsed());
+                shared.release_slot();
+            };
+            while !shared.aborted() {
+                let Ok((seq, item)) = output.recv() else {
+                    break;
+                };
+                if !ordered {
+                    deliver(item);
+                    continue;
+                }
+                pending.insert(seq, item);
+                while let Some(item) = pending.remove(&next) {
+                    deliver(item);
+                    next += 1;
+                }
+            }
+        }));
+        if let Err(payload) = result {
+            shared.fail("sink", payload);
+        }
+        // Unblocks upstream senders if the sink stopped early.
+        drop(output);
+
+        let handles = std::mem::take(&mut *shared.handles.lock().unwrap());
+        for handle in handles {
+            // Stage panics are caught inside the workers.
+            let _ = handle.join();
+        }
+        if let Some(err) = shared.failure.lock().unwrap().take() {
+            return Err(err);
+        }
+        let elapsed = shared.started.elapsed();
+        let stages = shared
+            .counters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| StageStats {
+                name: c.name.clone(),
+                workers: c.workers,
+                items: c.items.load(Ordering::Relaxed),
+                busy: Duration::from_nanos(c.busy_nanos.load(Ordering::Relaxed)),
+                elapsed,
+            })
+            .collect();
+        Ok(PipelineReport { stages, elapsed })
+    }
+
+    /// Collects all output items.
+    pub fn collect(self) -> Result<(Vec<T>, PipelineReport), PipelineError> {
+        let mut items = Vec::new();
+        let report = self.sink(|item| items.push(item))?;
+        Ok((items, report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_single_stage() {
+        let (items, report) = Pipeline::source(0..100)
+            .stage(4, |x: i32| x * 2)
+            .collect()
+            .unwrap();
+        let mut sorted = items.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        let names: Vec<_> = report.stages.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["source", "stage 1", "sink"]);
+        assert!(report.stages.iter().all(|s| s.items == 100));
+    }
+
+    #[test]
+    fn test_typed_stages_in_order() {
+        let (items, report) = Pipeline::source(1..=50u64)
+            .named_stage("square", 3, |x| x * x)
+            .named_stage("format", 2, |x: u64| {
+                // Uneven work so workers finish out of order.
+                thread::sleep(Duration::from_micros(x % 7 * 100));
+                format!("#{x}")
+            })
+            .ordered()
+            .collect()
+            .unwrap();
+        let expected: Vec<_> = (1..=50u64).map(|x| format!("#{}", x * x)).collect();
+        assert_eq!(items, expected);
+        assert_eq!(report.stages[2].name, "format");
+        assert_eq!(report.stages[2].workers, 2);
+        assert!(report.stages[2].items_per_sec() > 0.0);
+        assert!(report.stages[2].busy > Duration::ZERO);
+        assert!(report.stages[2].utilisation() <= 1.0);
+    }
+
+    #[test]
+    fn test_backpressure_bounds_in_flight_items() {
+        let produced = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&produced);
+        let source = (0..1_000).inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let mut seen = 0;
+        let observed = Arc::clone(&produced);
+        Pipeline::source_with_capacity(source, 2)
+            .stage(1, |x: i32| x)
+            .sink(|_| {
+                seen += 1;
+                if seen == 10 {
+                    thread::sleep(Duration::from_millis(20));
+                    // source channel + stage worker + stage channel, plus slack.
+                    assert!(observed.load(Ordering::SeqCst) <= 10 + 8);
+                }
+            })
+            .unwrap();
+        assert_eq!(produced.load(Ordering::SeqCst), 1_000);
+    }
+
+    #[test]
+    fn test_ordered_sink_bounds_reorder_buffer() {
+        let produced = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&produced);
+        let source = (0..1_000).inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let observed = Arc::clone(&produced);
+        let while_stuck = Arc::new(AtomicUsize::new(0));
+        let record = Arc::clone(&while_stuck);
+        let (items, _) = Pipeline::source_with_capacity(source, 2)
+            .stage(4, move |x: i32| {
+                if x == 0 {
+                    thread::sleep(Duration::from_millis(100));
+                    record.store(observed.load(Ordering::SeqCst), Ordering::SeqCst);
+                }
+                x
+            })
+            .ordered()
+            .collect()
+            .unwrap();
+        assert_eq!(items, (0..1_000).collect::<Vec<_>>());
+        // The other workers finish everything the window lets through while
+        // item 0 sleeps; without the window they would drain the source.
+        assert!(while_stuck.load(Ordering::SeqCst) <= 2 * WINDOW_PER_SLOT);
+    }
+
+    #[test]
+    fn test_stage_panic_shuts_down_pipeline() {
+        let start = Instant::now();
+        // An endless source: only shutdown propagation can stop it.
+        let result = Pipeline::source(0u64..)
+            .named_stage("parse", 2, |x| x + 1)
+            .named_stage("validate", 3, |x: u64| {
+                if x == 500 {
+                    panic!("bad record {x}");
+                }
+                x
+            })
+            .stage(2, |x: u64| x)
+            .collect();
+        assert_eq!(
+            result.unwrap_err(),
+            PipelineError::StagePanicked {
+                stage: "validate".to_string(),
+                message: "bad record 500".to_string(),
+            }
+        );
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_sink_and_source_panics() {
+        let err = Pipeline::source(0..10)
+            .stage(1, |x: i32| x)
+            .sink(|x| assert!(x < 3, "sink rejected {x}"))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            PipelineError::StagePanicked {
+                stage: "sink".to_string(),
+                message: "sink rejected 3".to_string(),
+            }
+        );
+
+        let source = (0..10).map(|x| if x == 4 { panic!("source broke") } else { x });
+        let err = Pipeline::source(source)
+            .stage(2, |x: i32| x)
+            .collect()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "pipeline stage 'source' panicked: source broke"
+        );
+    }
+
+    #[test]
+    fn test_source_only() {
+        let (items, _) = Pipeline::source(vec!["a", "b"])
+            .ordered()
+            .collect()
+            .unwrap();
+        assert_eq!(items, vec!["a", "b"]);
+    }
+}
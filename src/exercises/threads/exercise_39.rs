@@ -0,0 +1,618 @@
+//! Exercise 39: Custom RwLock - Policies and Upgradable Reads
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Build a reader-writer lock from a `Mutex` and a `Condvar`
+//! - Choose who goes first when readers and writers compete
+//! - Prevent writer starvation
+//! - Upgrade a read lock to a write lock without letting another writer in
+//!
+//! `std::sync::RwLock` (exercises 12 and 19) leaves fairness to the platform
+//! and cannot upgrade. This lock keeps its bookkeeping in a small `Mutex`
+//! (as in exercise 22) and lets the caller pick a `Policy`. An upgradable
+//! read coexists with plain readers but excludes writers and other
+//! upgradable readers, so it can turn into a write lock once the plain
+//! readers have left, with nobody else writing in between.
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// New readers join active readers even while writers wait. Maximises
+    /// read throughput; writers can starve.
+    ReaderPreferring,
+    /// New readers wait while a writer is waiting.
+    WriterPreferring,
+    /// Threads are served in arrival order; consecutive readers share.
+    Fair,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read,
+    Upgradable,
+    Write,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    readers: usize,
+    writer: bool,
+    upgradable: bool,
+    /// The upgradable holder is waiting for readers to leave.
+    upgrading: bool,
+    waiting_readers: usize,
+    waiting_writers: usize,
+    /// Ticket dispenser for `Policy::Fair`.
+    next_ticket: u64,
+    serving: u64,
+}
+
+pub struct RwLock<T> {
+    policy: Policy,
+    state: Mutex<State>,
+    cond: Condvar,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// A writer-preferring lock.
+    pub fn new(value: T) -> Self {
+        Self::with_policy(value, Policy::WriterPreferring)
+    }
+
+    pub fn with_policy(value: T, policy: Policy) -> Self {
+        RwLock {
+            policy,
+            state: Mutex::new(State::default()),
+            cond: Condvar::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.acquire(Access::Read);
+        RwLockReadGuard { lock: self }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.acquire(Access::Write);
+        RwLockWriteGuard { lock: self }
+    }
+
+    /// Shared access that can later be upgraded with
+    /// `RwLockUpgradableReadGuard::upgrade`.
+    pub fn upgradable_read(&self) -> RwLockUpgradableReadGuard<'_, T> {
+        self.acquire(Access::Upgradable);
+        RwLockUpgradableReadGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.try_acquire(Access::Read)
+            .then(|| RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.try_acquire(Access::Write)
+            .then(|| RwLockWriteGuard { lock: self })
+    }
+
+    pub fn try_upgradable_read(&self) -> Option<RwLockUpgradableReadGuard<'_, T>> {
+        self.try_acquire(Access::Upgradable)
+            .then(|| RwLockUpgradableReadGuard { lock: self })
+    }
+
+    /// Threads currently blocked in `write`.
+    pub fn waiting_writers(&self) -> usize {
+        self.lock_state().waiting_writers
+    }
+
+    /// Threads currently blocked in `read` or `upgradable_read`.
+    pub fn waiting_readers(&self) -> usize {
+        self.lock_state().waiting_readers
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, State> {
+        // Only counters live under this mutex; no user code runs while it
+        // is held, so poisoning cannot leave it inconsistent.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether `access` is compatible with the current holders.
+    fn compatible(state: &State, access: Access) -> bool {
+        match access {
+            Access::Read => !state.writer && !state.upgrading,
+            Access::Upgradable => !state.writer && !state.upgradable,
+            Access::Write => !state.writer && !state.upgradable && state.readers == 0,
+        }
+    }
+
+    /// Whether a blocked thread (holding `ticket` under `Fair`) may go now.
+    fn may_enter(&self, state: &State, access: Access, ticket: u64) -> bool {
+        if !Self::compatible(state, access) {
+            return false;
+        }
+        match self.policy {
+            Policy::ReaderPreferring => true,
+            Policy::WriterPreferring => access == Access::Write || state.waiting_writers == 0,
+            Policy::Fair => state.serving == ticket,
+        }
+    }
+
+    fn acquire(&self, access: Access) {
+        let mut state = self.lock_state();
+        let ticket = state.next_ticket;
+        if self.policy == Policy::Fair {
+            state.next_ticket += 1;
+        }
+        match access {
+            Access::Write => state.waiting_writers += 1,
+            _ => state.waiting_readers += 1,
+        }
+        while !self.may_enter(&state, access, ticket) {
+            state = self.cond.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        match access {
+            Access::Write => state.waiting_writers -= 1,
+            _ => state.waiting_readers -= 1,
+        }
+        self.grant(&mut state, access);
+    }
+
+    fn try_acquire(&self, access: Access) -> bool {
+        let mut state = self.lock_state();
+        let queue_empty = state.next_ticket == state.serving;
+        let allowed = Self::compatible(&state, access)
+            && match self.policy {
+                Policy::ReaderPreferring => true,
+                Policy::WriterPreferring => access == Access::Write || state.waiting_writers == 0,
+                Policy::Fair => queue_empty,
+            };
+        if allowed {
+            if self.policy == Policy::Fair {
+                state.next_ticket += 1;
+            }
+            self.grant(&mut state, access);
+        }
+        allowed
+    }
+
+    fn grant(&self, state: &mut State, access: Access) {
+        match access {
+            Access::Read => state.readers += 1,
+            Access::Upgradable => state.upgradable = true,
+            Access::Write => state.writer = true,
+        }
+        if self.policy == Policy::Fair {
+            state.serving += 1;
+            // The next ticket may be a reader that can share with us.
+            self.cond.notify_all();
+        }
+    }
+
+    fn release(&self, access: Access) {
+        let mut state = self.lock_state();
+        match access {
+            Access::Read => state.readers -= 1,
+            Access::Upgradable => state.upgradable = false,
+            Access::Write => state.writer = false,
+        }
+        drop(state);
+        self.cond.notify_all();
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("RwLock");
+        d.field("policy", &self.policy);
+        match self.try_read() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockUpgradableReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLockUpgradableReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+// Guards hand out `&T` across threads only if `T: Sync`.
+unsafe impl<T: Sync> Sync for RwLockReadGuard<'_, T> {}
+unsafe impl<T: Sync> Sync for RwLockWriteGuard<'_, T> {}
+unsafe impl<T: Sync> Sync for RwLockUpgradableReadGuard<'_, T> {}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Deref for RwLockUpgradableReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release(Access::Read);
+    }
+}
+
+impl<T> Drop for RwLockUpgradableReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release(Access::Upgradable);
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release(Access::Write);
+    }
+}
+
+impl<'a, T> RwLockUpgradableReadGuard<'a, T> {
+    /// Waits for the plain readers to leave and becomes a write guard.
+    /// New readers are held back meanwhile; no writer can get in between
+    /// because writers are excluded for as long as this guard exists.
+    pub fn upgrade(self) -> RwLockWriteGuard<'a, T> {
+        let lock = self.lock;
+        mem::forget(self);
+        let mut state = lock.lock_state();
+        state.upgrading = true;
+        while state.readers > 0 {
+            state = lock.cond.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        state.upgrading = false;
+        state.upgradable = false;
+        state.writer = true;
+        RwLockWriteGuard { lock }
+    }
+
+    /// Upgrades only if no plain reader is active.
+    pub fn try_upgrade(self) -> Result<RwLockWriteGuard<'a, T>, Self> {
+        let mut state = self.lock.lock_state();
+        if state.readers > 0 {
+            drop(state);
+            return Err(self);
+        }
+        state.upgradable = false;
+        state.writer = true;
+        drop(state);
+        let lock = self.lock;
+        mem::forget(self);
+        Ok(RwLockWriteGuard { lock })
+    }
+
+    /// Gives up the upgrade right but keeps reading.
+    pub fn downgrade(self) -> RwLockReadGuard<'a, T> {
+        let lock = self.lock;
+        mem::forget(self);
+        let mut state = lock.lock_state();
+        state.upgradable = false;
+        state.readers += 1;
+        drop(state);
+        lock.cond.notify_all();
+        RwLockReadGuard { lock }
+    }
+}
+
+impl<'a, T> RwLockWriteGuard<'a, T> {
+    /// Turns into a read guard without letting a writer in between.
+    pub fn downgrade(self) -> RwLockReadGuard<'a, T> {
+        let lock = self.lock;
+        mem::forget(self);
+        let mut state = lock.lock_state();
+        state.writer = false;
+        state.readers += 1;
+        drop(state);
+        lock.cond.notify_all();
+        RwLockReadGuard { lock }
+    }
+}
+
+/// Exercise 19's `rwlock_multi_writer` on this lock with the given policy.
+pub fn rwlock_multi_writer(
+    policy: Policy,
+    n_readers: usize,
+    n_writers: usize,
+    increments_per_writer: usize,
+) -> i32 {
+    let lock = Arc::new(RwLock::with_policy(0i32, policy));
+    let mut handles = Vec::new();
+    for _ in 0..n_readers {
+        let lock = Arc::clone(&lock);
+        handles.push(thread::spawn(move || {
+            for _ in 0..increments_per_writer {
+                let _ = *lock.read();
+            }
+        }));
+    }
+    for _ in 0..n_writers {
+        let lock = Arc::clone(&lock);
+        handles.push(thread::spawn(move || {
+            for _ in 0..increments_per_writer {
+                *lock.write() += 1;
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    *lock.read()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    const POLICIES: [Policy; 3] = [
+        Policy::ReaderPreferring,
+        Policy::WriterPreferring,
+        Policy::Fair,
+    ];
+
+    fn wait_until(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "condition not reached");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_readers_share_writers_exclude() {
+        for policy in POLICIES {
+            let lock = RwLock::with_policy(5, policy);
+            let a = lock.read();
+            let b = lock.read();
+            assert_eq!(*a + *b, 10);
+            assert!(lock.try_write().is_none());
+            drop((a, b));
+            let mut w = lock.write();
+            *w = 6;
+            assert!(lock.try_read().is_none());
+            drop(w);
+            assert_eq!(*lock.read(), 6);
+        }
+    }
+
+    #[test]
+    fn test_multi_writer_counts() {
+        for policy in POLICIES {
+            assert_eq!(rwlock_multi_writer(policy, 4, 3, 200), 600);
+        }
+    }
+
+    #[test]
+    fn test_waiting_writer_blocks_new_readers_only_when_preferred() {
+        for policy in POLICIES {
+            let lock = Arc::new(RwLock::with_policy(0, policy));
+            let reader = lock.read();
+            let writer = {
+                let lock = Arc::clone(&lock);
+                thread::spawn(move || *lock.write() += 1)
+            };
+            wait_until(|| lock.waiting_writers() == 1);
+            let second_reader = lock.try_read();
+            assert_eq!(
+                second_reader.is_some(),
+                policy == Policy::ReaderPreferring,
+                "{policy:?}"
+            );
+            drop(second_reader);
+            drop(reader);
+            writer.join().unwrap();
+            assert_eq!(*lock.read(), 1);
+        }
+    }
+
+    /// Readers that overlap continuously never leave the lock free. With
+    /// writer preference every write must still complete promptly.
+    #[test]
+    fn test_writer_preferring_writers_do_not_starve() {
+        let lock = Arc::new(RwLock::with_policy(0u64, Policy::WriterPreferring));
+        let stop = Arc::new(AtomicBool::new(false));
+        let reads = Arc::new(AtomicUsize::new(0));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let (lock, stop, reads) =
+                    (Arc::clone(&lock), Arc::clone(&stop), Arc::clone(&reads));
+                thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        let guard = lock.read();
+                        thread::sleep(Duration::from_micros(500));
+                        drop(guard);
+                        reads.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect();
+        wait_until(|| reads.load(Ordering::Relaxed) > 20);
+
+        let mut worst = Duration::ZERO;
+        for _ in 0..20 {
+            let attempt = Instant::now();
+            *lock.write() += 1;
+            worst = worst.max(attempt.elapsed());
+        }
+        let reads_before_stop = reads.load(Ordering::Relaxed);
+        stop.store(true, Ordering::Relaxed);
+        for r in readers {
+            r.join().unwrap();
+        }
+        assert_eq!(*lock.read(), 20);
+        assert!(reads_before_stop > 20);
+        // Each write only waits for the readers already inside (~0.5ms).
+        assert!(
+            worst < Duration::from_millis(500),
+            "worst write wait {worst:?}"
+        );
+    }
+
+    #[test]
+    fn test_fair_serves_in_arrival_order() {
+        let lock = Arc::new(RwLock::with_policy((), Policy::Fair));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let writer = lock.write();
+        let mut handles = Vec::new();
+        let queue = [("A", false), ("B", true), ("C", false), ("D", false)];
+        for (i, (name, write)) in queue.into_iter().enumerate() {
+            let (lock_clone, order) = (Arc::clone(&lock), Arc::clone(&order));
+            handles.push(thread::spawn(move || {
+                if write {
+                    let _guard = lock_clone.write();
+                    order.lock().unwrap().push(name);
+                    thread::sleep(Duration::from_millis(10));
+                } else {
+                    let _guard = lock_clone.read();
+                    order.lock().unwrap().push(name);
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }));
+            wait_until(|| lock.waiting_readers() + lock.waiting_writers() == i + 1);
+        }
+        drop(writer);
+        for h in handles {
+            h.join().unwrap();
+        }
+        // C and D share the lock after B, in either order.
+        let order = order.lock().unwrap();
+        assert_eq!(order[..2], ["A", "B"]);
+        assert_eq!(order.len(), 4);
+    }
+
+    #[test]
+    fn test_upgrade_is_atomic() {
+        for policy in POLICIES {
+            let lock = Arc::new(RwLock::with_policy(0, policy));
+            let upgradable = lock.upgradable_read();
+            // Plain readers may join, other upgradable readers and writers may not.
+            assert!(lock.try_read().is_some());
+            assert!(lock.try_upgradable_read().is_none());
+            assert!(lock.try_write().is_none());
+
+            let entered = Arc::new(Barrier::new(2));
+            let reader = {
+                let (lock, entered) = (Arc::clone(&lock), Arc::clone(&entered));
+                thread::spawn(move || {
+                    let _guard = lock.read();
+                    entered.wait();
+                    thread::sleep(Duration::from_millis(10));
+                })
+            };
+            entered.wait();
+            let writer = {
+                let lock = Arc::clone(&lock);
+                thread::spawn(move || {
+                    let mut guard = lock.write();
+                    // Must observe the upgraded write.
+                    assert_eq!(*guard, 1, "{policy:?}");
+                    *guard = 2;
+                })
+            };
+            wait_until(|| lock.waiting_writers() == 1);
+            let seen = *upgradable;
+            let mut write = upgradable.upgrade();
+            *write = seen + 1;
+            drop(write);
+            reader.join().unwrap();
+            writer.join().unwrap();
+            assert_eq!(*lock.read(), 2);
+        }
+    }
+
+    #[test]
+    fn test_try_upgrade_and_downgrades() {
+        let lock = RwLock::new(1);
+        let upgradable = lock.upgradable_read();
+        let reader = lock.read();
+        let upgradable = upgradable.try_upgrade().unwrap_err();
+        drop(reader);
+        let mut write = upgradable.try_upgrade().ok().unwrap();
+        *write = 2;
+        let read = write.downgrade();
+        assert!(lock.try_write().is_none());
+        assert!(lock.try_upgradable_read().is_some());
+        drop(read);
+
+        let read = lock.upgradable_read().downgrade();
+        assert!(lock.try_upgradable_read().is_some());
+        assert_eq!(*read, 2);
+    }
+}
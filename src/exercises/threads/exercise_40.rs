@@ -0,0 +1,495 @@
+//! Exercise 40: Sharded Concurrent HashMap
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Reduce lock contention by splitting a map into independently locked shards
+//! - Pick a shard from the key's hash
+//! - Make read-modify-write updates atomic per key
+//! - Take consistent snapshots and re-shard a live map
+//! - Measure scaling against a single-lock map
+//!
+//! A single `RwLock<HashMap>` (as in `async_rust::exercise_15::shared_cache`)
+//! serialises every writer. `ShardedMap` hashes each key to one of N shards,
+//! each behind its own `RwLock`, so operations on different shards proceed in
+//! parallel. The shard vector itself sits behind an outer `RwLock` that
+//! normal operations only read-lock; `resize` write-locks it to rehash.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::collections::hash_map::{Entry, RandomState};
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Shards per available CPU when no count is given.
+const SHARDS_PER_CPU: usize = 4;
+
+type Shard<K, V> = RwLock<HashMap<K, V>>;
+
+pub struct ShardedMap<K, V, S = RandomState> {
+    hasher: S,
+    shards: RwLock<Vec<Shard<K, V>>>,
+}
+
+fn default_shard_count() -> usize {
+    thread::available_parallelism().map_or(4, |n| n.get()) * SHARDS_PER_CPU
+}
+
+impl<K: Hash + Eq, V> ShardedMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_shards(default_shard_count())
+    }
+
+    pub fn with_shards(shards: usize) -> Self {
+        Self::with_shards_and_hasher(shards, RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, V> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> ShardedMap<K, V, S> {
+    pub fn with_shards_and_hasher(shards: usize, hasher: S) -> Self {
+        ShardedMap {
+            hasher,
+            shards: RwLock::new(new_shards(shards.max(1))),
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.read_shards().len()
+    }
+
+    fn read_shards(&self) -> RwLockReadGuard<'_, Vec<Shard<K, V>>> {
+        self.shards.read().unwrap()
+    }
+
+    fn hash<Q: Hash + ?Sized>(&self, key: &Q) -> u64 {
+        self.hasher.hash_one(key)
+    }
+
+    fn shard_index(hash: u64, shards: usize) -> usize {
+        // The low bits also pick the bucket inside the shard's own table;
+        // use the high bits so the two choices stay independent.
+        ((hash >> 32) as usize) % shards
+    }
+
+    /// Runs `f` on the shard owning `hash`, write-locked.
+    fn with_shard_mut<R>(&self, hash: u64, f: impl FnOnce(&mut HashMap<K, V>) -> R) -> R {
+        let shards = self.read_shards();
+        let mut shard = shards[Self::shard_index(hash, shards.len())]
+            .write()
+            .unwrap();
+        f(&mut shard)
+    }
+
+    fn with_shard<R>(&self, hash: u64, f: impl FnOnce(&HashMap<K, V>) -> R) -> R {
+        let shards = self.read_shards();
+        let shard = shards[Self::shard_index(hash, shards.len())]
+            .read()
+            .unwrap();
+        f(&shard)
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.with_shard_mut(self.hash(&key), |shard| shard.insert(key, value))
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.with_shard_mut(self.hash(key), |shard| shard.remove(key))
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.with_shard(self.hash(key), |shard| shard.contains_key(key))
+    }
+
+    /// A clone of the value for `key`.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.with_shard(self.hash(key), |shard| shard.get(key).cloned())
+    }
+
+    /// Applies `f` to the value under the shard's read lock.
+    pub fn get_with<Q, R>(&self, key: &Q, f: impl FnOnce(&V) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.with_shard(self.hash(key), |shard| shard.get(key).map(f))
+    }
+
+    /// Runs `f` on the standard `Entry` for `key` while its shard is
+    /// write-locked, so the whole read-modify-write is atomic:
+    ///
+    /// ```ignore
+    /// map.entry(word, |e| *e.or_insert(0) += 1);
+    /// ```
+    pub fn entry<R>(&self, key: K, f: impl FnOnce(Entry<'_, K, V>) -> R) -> R {
+        self.with_shard_mut(self.hash(&key), |shard| f(shard.entry(key)))
+    }
+
+    /// Modifies an existing value in place; returns whether it existed.
+    pub fn update<Q>(&self, key: &Q, f: impl FnOnce(&mut V)) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.with_shard_mut(self.hash(key), |shard| shard.get_mut(key).map(f).is_some())
+    }
+
+    /// Returns the value for `key`, inserting `f()` first if it is missing.
+    /// `f` runs at most once per missing key, even under contention.
+    pub fn get_or_insert_with(&self, key: K, f: impl FnOnce() -> V) -> V
+    where
+        V: Clone,
+    {
+        // Most calls find the key; try under the cheaper read lock first.
+        if let Some(value) = self.get(&key) {
+            return value;
+        }
+        self.entry(key, |entry| entry.or_insert_with(f).clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.read_shards()
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Keeps the entries for which `f` returns true, one shard at a time.
+    pub fn retain(&self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        for shard in self.read_shards().iter() {
+            shard.write().unwrap().retain(|k, v| f(k, v));
+        }
+    }
+
+    pub fn clear(&self) {
+        for shard in self.read_shards().iter() {
+            shard.write().unwrap().clear();
+        }
+    }
+
+    /// A point-in-time copy of every entry.
+    ///
+    /// All shards are read-locked together (in index order, so concurrent
+    /// snapshots cannot deadlock), so no write lands half-way through.
+    pub fn snapshot(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let shards = self.read_shards();
+        let guards: Vec<_> = shards.iter().map(|s| s.read().unwrap()).collect();
+        guards
+            .iter()
+            .flat_map(|shard| shard.iter().map(|(k, v)| (k.clone(), v.clone())))
+            .collect()
+    }
+
+    /// Entries per shard, to check how evenly keys spread.
+    pub fn shard_sizes(&self) -> Vec<usize> {
+        self.read_shards()
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .collect()
+    }
+
+    /// Rehashes every entry into `shards` new shards. Blocks all other
+    /// operations for the duration.
+    pub fn resize(&self, shards: usize) {
+        let shards = shards.max(1);
+        let mut current = self.shards.write().unwrap();
+        if current.len() == shards {
+            return;
+        }
+        let old = std::mem::replace(&mut *current, new_shards(shards));
+        for shard in old {
+            for (key, value) in shard.into_inner().unwrap() {
+                let index = Self::shard_index(self.hash(&key), shards);
+                current[index].get_mut().unwrap().insert(key, value);
+            }
+        }
+    }
+}
+
+fn new_shards<K, V>(n: usize) -> Vec<Shard<K, V>> {
+    (0..n).map(|_| RwLock::new(HashMap::new())).collect()
+}
+
+/// One run of the map benchmark.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapBenchResult {
+    pub name: String,
+    pub threads: usize,
+    pub ops: u64,
+    pub elapsed: Duration,
+}
+
+impl MapBenchResult {
+    pub fn ops_per_sec(&self) -> f64 {
+        self.ops as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// The operations the benchmark needs from either map.
+trait BenchMap: Send + Sync + 'static {
+    fn bench_get(&self, key: u64) -> Option<u64>;
+    fn bench_add(&self, key: u64, delta: u64);
+}
+
+impl BenchMap for ShardedMap<u64, u64> {
+    fn bench_get(&self, key: u64) -> Option<u64> {
+        self.get(&key)
+    }
+
+    fn bench_add(&self, key: u64, delta: u64) {
+        self.entry(key, |e| *e.or_insert(0) += delta);
+    }
+}
+
+impl BenchMap for RwLock<HashMap<u64, u64>> {
+    fn bench_get(&self, key: u64) -> Option<u64> {
+        self.read().unwrap().get(&key).copied()
+    }
+
+    fn bench_add(&self, key: u64, delta: u64) {
+        *self.write().unwrap().entry(key).or_insert(0) += delta;
+    }
+}
+
+/// `threads` threads each do `ops_per_thread` operations over `keys` keys,
+/// one write for every four reads.
+fn run_bench<M: BenchMap>(
+    name: &str,
+    map: Arc<M>,
+    threads: usize,
+    ops_per_thread: u64,
+    keys: u64,
+) -> MapBenchResult {
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let map = Arc::clone(&map);
+            thread::spawn(move || {
+                let mut x = (t as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+                for i in 0..ops_per_thread {
+                    x ^= x << 13;
+                    x ^= x >> 7;
+                    x ^= x << 17;
+                    let key = x % keys;
+                    if i % 5 == 0 {
+                        map.bench_add(key, 1);
+                    } else {
+                        std::hint::black_box(map.bench_get(key));
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    MapBenchResult {
+        name: name.to_string(),
+        threads,
+        ops: threads as u64 * ops_per_thread,
+        elapsed: start.elapsed(),
+    }
+}
+
+/// Runs the same mixed workload on a `ShardedMap` and on a single
+/// `RwLock<HashMap>` for every thread count in `thread_counts`.
+///
+/// Returns `(sharded, single_lock)` pairs in the order of `thread_counts`.
+pub fn compare_scaling(
+    thread_counts: &[usize],
+    ops_per_thread: u64,
+    shards: usize,
+) -> Vec<(MapBenchResult, MapBenchResult)> {
+    const KEYS: u64 = 10_000;
+    thread_counts
+        .iter()
+        .map(|&threads| {
+            let threads = threads.max(1);
+            let sharded = run_bench(
+                "sharded",
+                Arc::new(ShardedMap::with_shards(shards)),
+                threads,
+                ops_per_thread,
+                KEYS,
+            );
+            let single = run_bench(
+                "single-lock",
+                Arc::new(RwLock::new(HashMap::new())),
+                threads,
+                ops_per_thread,
+                KEYS,
+            );
+            (sharded, single)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_basic_operations() {
+        let map = ShardedMap::with_shards(4);
+        assert!(map.is_empty());
+        assert_eq!(map.insert("a".to_string(), 1), None);
+        assert_eq!(map.insert("a".to_string(), 2), Some(1));
+        map.insert("b".to_string(), 3);
+        // Borrowed lookups, like `HashMap`.
+        assert_eq!(map.get("a"), Some(2));
+        assert_eq!(map.get_with("b", |v| v * 10), Some(30));
+        assert!(map.contains_key("b"));
+        assert!(map.update("b", |v| *v += 1));
+        assert!(!map.update("zzz", |v| *v += 1));
+        assert_eq!(map.remove("b"), Some(4));
+        assert_eq!(map.len(), 1);
+        map.clear();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_entry_is_atomic_across_threads() {
+        let map = Arc::new(ShardedMap::with_shards(8));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let map = Arc::clone(&map);
+                thread::spawn(move || {
+                    for i in 0..1_000u32 {
+                        map.entry(i % 10, |e| *e.or_insert(0) += 1);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        for key in 0..10 {
+            assert_eq!(map.get(&key), Some(800));
+        }
+    }
+
+    #[test]
+    fn test_get_or_insert_with_runs_once() {
+        let map = Arc::new(ShardedMap::with_shards(2));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (map, calls) = (Arc::clone(&map), Arc::clone(&calls));
+                thread::spawn(move || {
+                    map.get_or_insert_with("config", || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(5));
+                        42
+                    })
+                })
+            })
+            .collect();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), 42);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_snapshot_is_consistent() {
+        // One writer inserts keys in order. A point-in-time snapshot always
+        // holds a prefix 0..n; shard-by-shard copying could see key 5 but
+        // miss key 3.
+        let map = Arc::new(ShardedMap::with_shards(16));
+        let writer = {
+            let map = Arc::clone(&map);
+            thread::spawn(move || {
+                for i in 0..5_000u32 {
+                    map.insert(i, ());
+                }
+            })
+        };
+        for _ in 0..200 {
+            let mut keys: Vec<_> = map.snapshot().into_iter().map(|(k, _)| k).collect();
+            keys.sort();
+            assert!(keys.iter().enumerate().all(|(i, &k)| k == i as u32));
+        }
+        writer.join().unwrap();
+        assert_eq!(map.snapshot().len(), 5_000);
+    }
+
+    #[test]
+    fn test_resize_keeps_entries() {
+        let map = ShardedMap::with_shards(2);
+        for i in 0..1_000 {
+            map.insert(i, i * 2);
+        }
+        map.resize(32);
+        assert_eq!(map.shard_count(), 32);
+        assert_eq!(map.len(), 1_000);
+        assert!((0..1_000).all(|i| map.get(&i) == Some(i * 2)));
+        let sizes = map.shard_sizes();
+        assert!(sizes.iter().all(|&n| n > 0), "{sizes:?}");
+        map.resize(1);
+        assert_eq!(map.shard_sizes(), vec![1_000]);
+    }
+
+    #[test]
+    fn test_resize_under_load() {
+        let map = Arc::new(ShardedMap::with_shards(1));
+        let writers: Vec<_> = (0..4u64)
+            .map(|t| {
+                let map = Arc::clone(&map);
+                thread::spawn(move || {
+                    for i in 0..2_000 {
+                        map.insert(t * 10_000 + i, i);
+                    }
+                })
+            })
+            .collect();
+        for shards in [2, 8, 3, 16] {
+            map.resize(shards);
+        }
+        for w in writers {
+            w.join().unwrap();
+        }
+        assert_eq!(map.len(), 8_000);
+        assert_eq!(map.get(&30_123), Some(123));
+    }
+
+    #[test]
+    fn test_compare_scaling() {
+        let results = compare_scaling(&[1, 4], 2_000, 16);
+        assert_eq!(results.len(), 2);
+        for (sharded, single) in &results {
+            assert_eq!(sharded.ops, single.ops);
+            assert_eq!(sharded.name, "sharded");
+            assert_eq!(single.name, "single-lock");
+            assert!(sharded.ops_per_sec() > 0.0 && single.ops_per_sec() > 0.0);
+        }
+        assert_eq!(results[1].0.threads, 4);
+    }
+}
@@ -0,0 +1,602 @@
+//! Exercise 41: Metrics - Striped Counters, Gauges and Histograms
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Avoid cache-line contention by striping a counter across padded slots
+//! - Store floating-point values in atomics via their bit patterns
+//! - Record distributions lock-free in fixed log-scale buckets
+//! - Register metrics by name and render the Prometheus text format
+//!
+//! Exercises 23, 25 and 27 count with one shared atomic or with per-thread
+//! sums merged at the end. A `Counter` here combines both ideas: each thread
+//! adds to one of several `CachePadded` (exercise 33) atomics, so concurrent
+//! increments rarely touch the same cache line, and reads sum the stripes.
+
+use super::exercise_33::CachePadded;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Gives each thread a stable stripe, spreading threads round-robin.
+fn stripe_index(stripes: usize) -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static INDEX: usize = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    INDEX.with(|i| *i) % stripes
+}
+
+/// A monotonically increasing count, cheap to update from many threads.
+pub struct Counter {
+    stripes: Box<[CachePadded<AtomicU64>]>,
+}
+
+impl Counter {
+    /// A counter with one stripe per available CPU.
+    pub fn new() -> Self {
+        Self::with_stripes(thread::available_parallelism().map_or(4, |n| n.get()))
+    }
+
+    pub fn with_stripes(stripes: usize) -> Self {
+        Counter {
+            stripes: (0..stripes.max(1))
+                .map(|_| CachePadded(AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.stripes[stripe_index(self.stripes.len())].fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// The sum of all stripes. Concurrent adds may or may not be included.
+    pub fn get(&self) -> u64 {
+        self.stripes.iter().map(|s| s.load(Ordering::Relaxed)).sum()
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Atomic `f64` built on the bit pattern in an `AtomicU64`.
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    fn new(value: f64) -> Self {
+        AtomicF64(AtomicU64::new(value.to_bits()))
+    }
+
+    fn load(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn store(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    fn add(&self, delta: f64) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + delta).to_bits())
+            });
+    }
+}
+
+/// A value that can go up and down.
+pub struct Gauge {
+    value: AtomicF64,
+}
+
+impl Gauge {
+    pub fn new() -> Self {
+        Gauge {
+            value: AtomicF64::new(0.0),
+        }
+    }
+
+    pub fn set(&self, value: f64) {
+        self.value.store(value);
+    }
+
+    pub fn add(&self, delta: f64) {
+        self.value.add(delta);
+    }
+
+    pub fn inc(&self) {
+        self.add(1.0);
+    }
+
+    pub fn dec(&self) {
+        self.add(-1.0);
+    }
+
+    pub fn get(&self) -> f64 {
+        self.value.load()
+    }
+}
+
+impl Default for Gauge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Upper bounds `start, start * factor, ...` for `count` buckets; values
+/// above the last bound land in an implicit `+Inf` bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LogBuckets {
+    pub start: f64,
+    pub factor: f64,
+    pub count: usize,
+}
+
+impl LogBuckets {
+    /// 1ms to ~65s in powers of two, for latencies in seconds.
+    pub fn latency_seconds() -> Self {
+        LogBuckets {
+            start: 0.001,
+            factor: 2.0,
+            count: 17,
+        }
+    }
+
+    fn bounds(&self) -> Vec<f64> {
+        (0..self.count)
+            .scan(self.start, |bound, _| {
+                let current = *bound;
+                *bound *= self.factor;
+                Some(current)
+            })
+            .collect()
+    }
+}
+
+/// A distribution of observed values in fixed buckets.
+///
+/// Every field is updated with its own relaxed atomic, so a concurrent read
+/// may see an observation in `count` but not yet in `sum`.
+pub struct Histogram {
+    bounds: Vec<f64>,
+    /// One count per bound plus the `+Inf` bucket; not cumulative.
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    sum: AtomicF64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramSnapshot {
+    /// `(upper bound, cumulative count)`; the last bound is `f64::INFINITY`.
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+impl Histogram {
+    pub fn new(buckets: LogBuckets) -> Self {
+        assert!(
+            buckets.start > 0.0 && buckets.factor > 1.0,
+            "log buckets need start > 0 and factor > 1"
+        );
+        let bounds = buckets.bounds();
+        Histogram {
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            bounds,
+            count: AtomicU64::new(0),
+            sum: AtomicF64::new(0.0),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        // First bucket whose upper bound is >= value (`le` semantics).
+        let index = self.bounds.partition_point(|&bound| bound < value);
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.add(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum.load()
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = self
+            .bounds
+            .iter()
+            .copied()
+            .chain(std::iter::once(f64::INFINITY))
+            .zip(self.buckets.iter())
+            .map(|(bound, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                (bound, cumulative)
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            count: self.count(),
+            sum: self.sum(),
+        }
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile (0.0..=1.0), or
+    /// `None` with no observations.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let snapshot = self.snapshot();
+        let total = snapshot.buckets.last()?.1;
+        if total == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+        snapshot
+            .buckets
+            .iter()
+            .find(|(_, cumulative)| *cumulative >= rank)
+            .map(|(bound, _)| *bound)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetricsError {
+    /// Not a valid Prometheus metric name.
+    InvalidName(String),
+    /// The name is already registered as another kind of metric.
+    KindMismatch {
+        name: String,
+        registered: &'static str,
+        requested: &'static str,
+    },
+}
+
+impl fmt::Display for MetricsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetricsError::InvalidName(name) => write!(f, "invalid metric name '{}'", name),
+            MetricsError::KindMismatch {
+                name,
+                registered,
+                requested,
+            } => write!(
+                f,
+                "metric '{}' is registered as a {}, not a {}",
+                name, registered, requested
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MetricsError {}
+
+#[derive(Clone)]
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    Histogram(Arc<Histogram>),
+}
+
+impl Metric {
+    fn kind(&self) -> &'static str {
+        match self {
+            Metric::Counter(_) => "counter",
+            Metric::Gauge(_) => "gauge",
+            Metric::Histogram(_) => "histogram",
+        }
+    }
+}
+
+/// Metrics by name. Registering an existing name returns the same metric,
+/// so independent components can share a counter by agreeing on its name.
+#[derive(Default)]
+pub struct Registry {
+    metrics: Mutex<BTreeMap<String, (String, Metric)>>,
+}
+
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Prometheus float formatting.
+fn format_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else if value.is_nan() {
+        "NaN".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn register(
+        &self,
+        name: &str,
+        help: &str,
+        requested: &'static str,
+        create: impl FnOnce() -> Metric,
+    ) -> Result<Metric, MetricsError> {
+        if !valid_name(name) {
+            return Err(MetricsError::InvalidName(name.to_string()));
+        }
+        let mut metrics = self.metrics.lock().unwrap();
+        let (_, metric) = metrics
+            .entry(name.to_string())
+            .or_insert_with(|| (help.to_string(), create()));
+        if metric.kind() != requested {
+            return Err(MetricsError::KindMismatch {
+                name: name.to_string(),
+                registered: metric.kind(),
+                requested,
+            });
+        }
+        Ok(metric.clone())
+    }
+
+    pub fn counter(&self, name: &str, help: &str) -> Result<Arc<Counter>, MetricsError> {
+        match self.register(name, help, "counter", || {
+            Metric::Counter(Arc::new(Counter::new()))
+        })? {
+            Metric::Counter(counter) => Ok(counter),
+            _ => unreachable!("kind checked by register"),
+        }
+    }
+
+    pub fn gauge(&self, name: &str, help: &str) -> Result<Arc<Gauge>, MetricsError> {
+        match self.register(name, help, "gauge", || {
+            Metric::Gauge(Arc::new(Gauge::new()))
+        })? {
+            Metric::Gauge(gauge) => Ok(gauge),
+            _ => unreachable!("kind checked by register"),
+        }
+    }
+
+    /// `buckets` only applies when the histogram is first registered.
+    pub fn histogram(
+        &self,
+        name: &str,
+        help: &str,
+        buckets: LogBuckets,
+    ) -> Result<Arc<Histogram>, MetricsError> {
+        match self.register(name, help, "histogram", || {
+            Metric::Histogram(Arc::new(Histogram::new(buckets)))
+        })? {
+            Metric::Histogram(histogram) => Ok(histogram),
+            _ => unreachable!("kind checked by register"),
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.metrics.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Renders every metric in the Prometheus text exposition format,
+    /// sorted by name.
+    pub fn render(&self) -> String {
+        // Clone the handles so rendering does not block registration.
+        let metrics: Vec<_> = self
+            .metrics
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, (help, metric))| (name.clone(), help.clone(), metric.clone()))
+            .collect();
+        let mut out = String::new();
+        for (name, help, metric) in metrics {
+            if !help.is_empty() {
+                let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+                let _ = writeln!(out, "# HELP {} {}", name, help);
+            }
+            let _ = writeln!(out, "# TYPE {} {}", name, metric.kind());
+            match metric {
+                Metric::Counter(counter) => {
+                    let _ = writeln!(out, "{} {}", name, counter.get());
+                }
+                Metric::Gauge(gauge) => {
+                    let _ = writeln!(out, "{} {}", name, format_value(gauge.get()));
+                }
+                Metric::Histogram(histogram) => {
+                    let snapshot = histogram.snapshot();
+                    for (bound, cumulative) in &snapshot.buckets {
+                        let _ = writeln!(
+                            out,
+                            "{}_bucket{{le=\"{}\"}} {}",
+                            name,
+                            format_value(*bound),
+                            cumulative
+                        );
+                    }
+                    let _ = writeln!(out, "{}_sum {}", name, format_value(snapshot.sum));
+                    let _ = writeln!(out, "{}_count {}", name, snapshot.count);
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_across_threads() {
+        let counter = Arc::new(Counter::with_stripes(4));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let counter = Arc::clone(&counter);
+                thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        counter.inc();
+                    }
+                    counter.add(5);
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(counter.get(), 8 * 10_005);
+    }
+
+    #[test]
+    fn test_stripes_are_padded() {
+        assert!(std::mem::size_of::<CachePadded<AtomicU64>>() >= 64);
+        let counter = Counter::with_stripes(0);
+        counter.inc();
+        assert_eq!(counter.get(), 1);
+    }
+
+    #[test]
+    fn test_gauge() {
+        let gauge = Arc::new(Gauge::new());
+        gauge.set(10.5);
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let gauge = Arc::clone(&gauge);
+                thread::spawn(move || {
+                    for _ in 0..1_000 {
+                        gauge.inc();
+                        gauge.dec();
+                        gauge.add(0.25);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(gauge.get(), 10.5 + 4.0 * 250.0);
+    }
+
+    #[test]
+    fn test_histogram_buckets() {
+        let histogram = Histogram::new(LogBuckets {
+            start: 1.0,
+            factor: 10.0,
+            count: 3,
+        });
+        for value in [0.5, 1.0, 5.0, 50.0, 100.0, 1_000.0] {
+            histogram.observe(value);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(
+            snapshot.buckets,
+            vec![(1.0, 2), (10.0, 3), (100.0, 5), (f64::INFINITY, 6)]
+        );
+        assert_eq!(snapshot.count, 6);
+        assert_eq!(snapshot.sum, 1_156.5);
+        assert_eq!(histogram.quantile(0.5), Some(10.0));
+        assert_eq!(histogram.quantile(0.99), Some(f64::INFINITY));
+        assert_eq!(
+            Histogram::new(LogBuckets::latency_seconds()).quantile(0.5),
+            None
+        );
+    }
+
+    #[test]
+    fn test_histogram_concurrent_observations() {
+        let histogram = Arc::new(Histogram::new(LogBuckets::latency_seconds()));
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let histogram = Arc::clone(&histogram);
+                thread::spawn(move || {
+                    for i in 0..1_000 {
+                        histogram.observe(((t * 1_000 + i) % 100) as f64 / 1_000.0);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 4_000);
+        assert_eq!(snapshot.buckets.last().unwrap().1, 4_000);
+        assert!(snapshot.buckets.windows(2).all(|w| w[0].1 <= w[1].1));
+    }
+
+    #[test]
+    fn test_registry_shares_and_checks_kinds() {
+        let registry = Registry::new();
+        let a = registry.counter("requests_total", "Requests").unwrap();
+        let b = registry.counter("requests_total", "ignored").unwrap();
+        a.inc();
+        assert_eq!(b.get(), 1);
+        assert_eq!(
+            registry.gauge("requests_total", "").err(),
+            Some(MetricsError::KindMismatch {
+                name: "requests_total".to_string(),
+                registered: "counter",
+                requested: "gauge",
+            })
+        );
+        assert_eq!(
+            registry.counter("9lives", "").err(),
+            Some(MetricsError::InvalidName("9lives".to_string()))
+        );
+        assert!(registry.counter("bad-name", "").is_err());
+        assert_eq!(registry.names(), vec!["requests_total"]);
+    }
+
+    #[test]
+    fn test_render_prometheus_text() {
+        let registry = Registry::new();
+        registry
+            .counter("http_requests_total", "Total requests")
+            .unwrap()
+            .add(3);
+        registry.gauge("queue_depth", "").unwrap().set(-2.5);
+        let latency = registry
+            .histogram(
+                "latency_seconds",
+                "Request latency\nin seconds",
+                LogBuckets {
+                    start: 0.1,
+                    factor: 10.0,
+                    count: 2,
+                },
+            )
+            .unwrap();
+        latency.observe(0.05);
+        latency.observe(0.5);
+        latency.observe(3.0);
+
+        let expected = "\
+# HELP http_requests_total Total requests
+# TYPE http_requests_total counter
+http_requests_total 3
+# HELP latency_seconds Request latency\\nin seconds
+# TYPE latency_seconds histogram
+latency_seconds_bucket{le=\"0.1\"} 1
+latency_seconds_bucket{le=\"1\"} 2
+latency_seconds_bucket{le=\"+Inf\"} 3
+latency_seconds_sum 3.55
+latency_seconds_count 3
+# TYPE queue_depth gauge
+queue_depth -2.5
+";
+        assert_eq!(registry.render(), expected);
+    }
+}
@@ -0,0 +1,618 @@
+//! Exercise 42: Phaser - Reusable Barrier with Dynamic Parties
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Let parties join and leave a barrier between (and during) phases
+//! - Number phases and run a callback exactly once per advance
+//! - Wait for an advance with a timeout
+//! - Share one state machine between a blocking and an async front end
+//!
+//! `std::sync::Barrier` (exercise 21) and `tokio::sync::Barrier`
+//! (`async_rust::exercise_17`) fix the number of parties up front. A
+//! `Phaser` tracks registered and arrived parties per phase; when the last
+//! registered party arrives, `on_advance` runs, the phase number goes up,
+//! and everyone waiting on the old phase is released. By default the phaser
+//! terminates once the last party deregisters. The bookkeeping lives in
+//! `Core`; `Phaser` waits on a `Condvar`, `AsyncPhaser` on a
+//! `tokio::sync::watch` channel.
+
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhaserError {
+    /// The phaser terminated before (or instead of) the awaited advance.
+    Terminated,
+    /// Every registered party has already arrived in this phase.
+    NoUnarrivedParties,
+    /// The phase did not advance in time. The arrival still counts.
+    Timeout { phase: u64 },
+}
+
+impl fmt::Display for PhaserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PhaserError::Terminated => write!(f, "phaser is terminated"),
+            PhaserError::NoUnarrivedParties => {
+                write!(f, "all registered parties already arrived")
+            }
+            PhaserError::Timeout { phase } => {
+                write!(f, "timed out waiting for phase {} to advance", phase)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PhaserError {}
+
+/// Called with the completed phase and the number of registered parties;
+/// returning `true` terminates the phaser.
+pub type OnAdvance = Box<dyn FnMut(u64, usize) -> bool + Send>;
+
+/// The state machine both variants share.
+struct Core {
+    phase: u64,
+    parties: usize,
+    arrived: usize,
+    terminated: bool,
+    on_advance: Option<OnAdvance>,
+}
+
+impl Core {
+    fn new(parties: usize, on_advance: Option<OnAdvance>) -> Self {
+        Core {
+            phase: 0,
+            parties,
+            arrived: 0,
+            terminated: false,
+            on_advance,
+        }
+    }
+
+    fn register(&mut self, parties: usize) -> Result<u64, PhaserError> {
+        if self.terminated {
+            return Err(PhaserError::Terminated);
+        }
+        self.parties += parties;
+        Ok(self.phase)
+    }
+
+    /// Records one arrival; returns the phase arrived at.
+    fn arrive(&mut self, deregister: bool) -> Result<u64, PhaserError> {
+        if self.terminated {
+            return Err(PhaserError::Terminated);
+        }
+        if self.arrived >= self.parties {
+            return Err(PhaserError::NoUnarrivedParties);
+        }
+        let phase = self.phase;
+        if deregister {
+            self.parties -= 1;
+        } else {
+            self.arrived += 1;
+        }
+        if self.arrived == self.parties {
+            self.advance();
+        }
+        Ok(phase)
+    }
+
+    fn advance(&mut self) {
+        let terminate = match self.on_advance.as_mut() {
+            Some(f) => f(self.phase, self.parties),
+            None => self.parties == 0,
+        };
+        self.phase += 1;
+        self.arrived = 0;
+        self.terminated = terminate;
+    }
+
+    /// Outcome for a waiter on `phase`, or `None` to keep waiting.
+    fn released(&self, phase: u64) -> Option<Result<u64, PhaserError>> {
+        if self.phase != phase {
+            Some(Ok(self.phase))
+        } else if self.terminated {
+            Some(Err(PhaserError::Terminated))
+        } else {
+            None
+        }
+    }
+}
+
+/// A blocking phaser. Clones share the same phaser.
+#[derive(Clone)]
+pub struct Phaser {
+    inner: Arc<(Mutex<Core>, Condvar)>,
+}
+
+impl Phaser {
+    pub fn new(parties: usize) -> Self {
+        Self::build(Core::new(parties, None))
+    }
+
+    pub fn with_on_advance<F>(parties: usize, on_advance: F) -> Self
+    where
+        F: FnMut(u64, usize) -> bool + Send + 'static,
+    {
+        Self::build(Core::new(parties, Some(Box::new(on_advance))))
+    }
+
+    fn build(core: Core) -> Self {
+        Phaser {
+            inner: Arc::new((Mutex::new(core), Condvar::new())),
+        }
+    }
+
+    fn core(&self) -> MutexGuard<'_, Core> {
+        self.inner.0.lock().unwrap()
+    }
+
+    /// Adds a party to the current phase; returns the phase number.
+    pub fn register(&self) -> Result<u64, PhaserError> {
+        self.bulk_register(1)
+    }
+
+    pub fn bulk_register(&self, parties: usize) -> Result<u64, PhaserError> {
+        self.core().register(parties)
+    }
+
+    /// Arrives without waiting; returns the phase arrived at.
+    pub fn arrive(&self) -> Result<u64, PhaserError> {
+        self.arrive_inner(false)
+    }
+
+    /// Arrives and leaves: later phases no longer wait for this party.
+    pub fn arrive_and_deregister(&self) -> Result<u64, PhaserError> {
+        self.arrive_inner(true)
+    }
+
+    fn arrive_inner(&self, deregister: bool) -> Result<u64, PhaserError> {
+        let mut core = self.core();
+        let phase = core.arrive(deregister)?;
+        if core.phase != phase || core.terminated {
+            self.inner.1.notify_all();
+        }
+        Ok(phase)
+    }
+
+    /// Arrives and blocks until the phase advances; returns the new phase.
+    pub fn arrive_and_await(&self) -> Result<u64, PhaserError> {
+        let phase = self.arrive()?;
+        self.wait(phase, None)
+    }
+
+    pub fn arrive_and_await_timeout(&self, timeout: Duration) -> Result<u64, PhaserError> {
+        let phase = self.arrive()?;
+        self.wait(phase, Some(Instant::now() + timeout))
+    }
+
+    /// Blocks until `phase` is over; returns the current phase.
+    pub fn await_advance(&self, phase: u64) -> Result<u64, PhaserError> {
+        self.wait(phase, None)
+    }
+
+    fn wait(&self, phase: u64, deadline: Option<Instant>) -> Result<u64, PhaserError> {
+        let mut core = self.core();
+        loop {
+            if let Some(result) = core.released(phase) {
+                return result;
+            }
+            core = match deadline {
+                None => self.inner.1.wait(core).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(PhaserError::Timeout { phase });
+                    }
+                    self.inner.1.wait_timeout(core, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// Terminates now; waiters get `Err(Terminated)`.
+    pub fn force_termination(&self) {
+        self.core().terminated = true;
+        self.inner.1.notify_all();
+    }
+
+    pub fn phase(&self) -> u64 {
+        self.core().phase
+    }
+
+    pub fn registered(&self) -> usize {
+        self.core().parties
+    }
+
+    pub fn arrived(&self) -> usize {
+        self.core().arrived
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.core().terminated
+    }
+}
+
+/// The async twin of `Phaser`, with the same semantics. Clones share the
+/// same phaser.
+#[derive(Clone)]
+pub struct AsyncPhaser {
+    core: Arc<Mutex<Core>>,
+    /// Publishes `(phase, terminated)` after every change.
+    state: Arc<watch::Sender<(u64, bool)>>,
+}
+
+impl AsyncPhaser {
+    pub fn new(parties: usize) -> Self {
+        Self::build(Core::new(parties, None))
+    }
+
+    pub fn with_on_advance<F>(parties: usize, on_advance: F) -> Self
+    where
+        F: FnMut(u64, usize) -> bool + Send + 'static,
+    {
+        Self::build(Core::new(parties, Some(Box::new(on_advance))))
+    }
+
+    fn build(core: Core) -> Self {
+        AsyncPhaser {
+            core: Arc::new(Mutex::new(core)),
+            state: Arc::new(watch::Sender::new((0, false))),
+        }
+    }
+
+    fn core(&self) -> MutexGuard<'_, Core> {
+        self.core.lock().unwrap()
+    }
+
+    pub fn register(&self) -> Result<u64, PhaserError> {
+        self.bulk_register(1)
+    }
+
+    pub fn bulk_register(&self, parties: usize) -> Result<u64, PhaserError> {
+        self.core().register(parties)
+    }
+
+    pub fn arrive(&self) -> Result<u64, PhaserError> {
+        self.arrive_inner(false)
+    }
+
+    pub fn arrive_and_deregister(&self) -> Result<u64, PhaserError> {
+        self.arrive_inner(true)
+    }
+
+    fn arrive_inner(&self, deregister: bool) -> Result<u64, PhaserError> {
+        let mut core = self.core();
+        let phase = core.arrive(deregister)?;
+        // Published under the lock so updates reach the channel in order.
+        self.state.send_replace((core.phase, core.terminated));
+        Ok(phase)
+    }
+
+    pub async fn arrive_and_await(&self) -> Result<u64, PhaserError> {
+        let phase = self.arrive()?;
+        self.await_advance(phase).await
+    }
+
+    pub async fn arrive_and_await_timeout(&self, timeout: Duration) -> Result<u64, PhaserError> {
+        let phase = self.arrive()?;
+        tokio::time::timeout(timeout, self.await_advance(phase))
+            .await
+            .unwrap_or(Err(PhaserError::Timeout { phase }))
+    }
+
+    pub async fn await_advance(&self, phase: u64) -> Result<u64, PhaserError> {
+        let mut rx = self.state.subscribe();
+        // `wait_for` checks the current value first, so an advance that
+        // happened before subscribing is not missed.
+        let result = rx
+            .wait_for(|&(current, terminated)| current != phase || terminated)
+            .await;
+        match result {
+            Ok(state) if state.0 != phase => Ok(state.0),
+            _ => Err(PhaserError::Terminated),
+        }
+    }
+
+    pub fn force_termination(&self) {
+        let mut core = self.core();
+        core.terminated = true;
+        self.state.send_replace((core.phase, true));
+    }
+
+    pub fn phase(&self) -> u64 {
+        self.core().phase
+    }
+
+    pub fn registered(&self) -> usize {
+        self.core().parties
+    }
+
+    pub fn arrived(&self) -> usize {
+        self.core().arrived
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.core().terminated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    /// The operations the shared scenarios need; the async variant is
+    /// driven from plain threads through a runtime handle.
+    trait TestPhaser: Clone + Send + 'static {
+        fn create(parties: usize) -> Self;
+        fn create_with(parties: usize, f: OnAdvance) -> Self;
+        fn register(&self) -> Result<u64, PhaserError>;
+        fn arrive(&self) -> Result<u64, PhaserError>;
+        fn arrive_and_deregister(&self) -> Result<u64, PhaserError>;
+        fn arrive_and_await(&self) -> Result<u64, PhaserError>;
+        fn arrive_and_await_timeout(&self, timeout: Duration) -> Result<u64, PhaserError>;
+        fn await_advance(&self, phase: u64) -> Result<u64, PhaserError>;
+        fn force_termination(&self);
+        fn phase(&self) -> u64;
+        fn registered(&self) -> usize;
+        fn is_terminated(&self) -> bool;
+    }
+
+    impl TestPhaser for Phaser {
+        fn create(parties: usize) -> Self {
+            Phaser::new(parties)
+        }
+        fn create_with(parties: usize, f: OnAdvance) -> Self {
+            Phaser::with_on_advance(parties, f)
+        }
+        fn register(&self) -> Result<u64, PhaserError> {
+            Phaser::register(self)
+        }
+        fn arrive(&self) -> Result<u64, PhaserError> {
+            Phaser::arrive(self)
+        }
+        fn arrive_and_deregister(&self) -> Result<u64, PhaserError> {
+            Phaser::arrive_and_deregister(self)
+        }
+        fn arrive_and_await(&self) -> Result<u64, PhaserError> {
+            Phaser::arrive_and_await(self)
+        }
+        fn arrive_and_await_timeout(&self, timeout: Duration) -> Result<u64, PhaserError> {
+            Phaser::arrive_and_await_timeout(self, timeout)
+        }
+        fn await_advance(&self, phase: u64) -> Result<u64, PhaserError> {
+            Phaser::await_advance(self, phase)
+        }
+        fn force_termination(&self) {
+            Phaser::force_termination(self)
+        }
+        fn phase(&self) -> u64 {
+            Phaser::phase(self)
+        }
+        fn registered(&self) -> usize {
+            Phaser::registered(self)
+        }
+        fn is_terminated(&self) -> bool {
+            Phaser::is_terminated(self)
+        }
+    }
+
+    fn runtime() -> &'static tokio::runtime::Runtime {
+        static RUNTIME: std::sync::OnceLock<tokio::runtime::Runtime> = std::sync::OnceLock::new();
+        RUNTIME.get_or_init(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(2)
+                .enable_all()
+                .build()
+                .unwrap()
+        })
+    }
+
+    impl TestPhaser for AsyncPhaser {
+        fn create(parties: usize) -> Self {
+            AsyncPhaser::new(parties)
+        }
+        fn create_with(parties: usize, f: OnAdvance) -> Self {
+            AsyncPhaser::with_on_advance(parties, f)
+        }
+        fn register(&self) -> Result<u64, PhaserError> {
+            AsyncPhaser::register(self)
+        }
+        fn arrive(&self) -> Result<u64, PhaserError> {
+            AsyncPhaser::arrive(self)
+        }
+        fn arrive_and_deregister(&self) -> Result<u64, PhaserError> {
+            AsyncPhaser::arrive_and_deregister(self)
+        }
+        fn arrive_and_await(&self) -> Result<u64, PhaserError> {
+            runtime().block_on(AsyncPhaser::arrive_and_await(self))
+        }
+        fn arrive_and_await_timeout(&self, timeout: Duration) -> Result<u64, PhaserError> {
+            runtime().block_on(AsyncPhaser::arrive_and_await_timeout(self, timeout))
+        }
+        fn await_advance(&self, phase: u64) -> Result<u64, PhaserError> {
+            runtime().block_on(AsyncPhaser::await_advance(self, phase))
+        }
+        fn force_termination(&self) {
+            AsyncPhaser::force_termination(self)
+        }
+        fn phase(&self) -> u64 {
+            AsyncPhaser::phase(self)
+        }
+        fn registered(&self) -> usize {
+            AsyncPhaser::registered(self)
+        }
+        fn is_terminated(&self) -> bool {
+            AsyncPhaser::is_terminated(self)
+        }
+    }
+
+    fn phases_advance_for_all_parties<P: TestPhaser>() {
+        let phaser = P::create(4);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let handles: Vec<_> = (0..4)
+            .map(|id| {
+                let (phaser, log) = (phaser.clone(), Arc::clone(&log));
+                thread::spawn(move || {
+                    for round in 0..3u64 {
+                        log.lock().unwrap().push((round, id));
+                        assert_eq!(phaser.arrive_and_await(), Ok(round + 1));
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(phaser.phase(), 3);
+        // Nobody starts round r+1 before everyone finished round r.
+        let log = log.lock().unwrap();
+        assert!(log.windows(2).all(|w| w[0].0 <= w[1].0), "{log:?}");
+    }
+
+    fn dynamic_registration<P: TestPhaser>() {
+        let phaser = P::create(1);
+        assert_eq!(phaser.register(), Ok(0));
+        assert_eq!(phaser.registered(), 2);
+        let late = {
+            let phaser = phaser.clone();
+            thread::spawn(move || (phaser.arrive_and_await(), phaser.arrive_and_deregister()))
+        };
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(phaser.phase(), 0);
+        assert_eq!(phaser.arrive(), Ok(0));
+        assert_eq!(late.join().unwrap(), (Ok(1), Ok(1)));
+
+        // The departed party no longer holds up later phases.
+        assert_eq!(phaser.registered(), 1);
+        assert_eq!(phaser.phase(), 1);
+        assert_eq!(phaser.arrive_and_await(), Ok(2));
+    }
+
+    fn last_deregistration_terminates<P: TestPhaser>() {
+        let phaser = P::create(2);
+        let waiter = {
+            let phaser = phaser.clone();
+            thread::spawn(move || phaser.arrive_and_await())
+        };
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(phaser.arrive_and_deregister(), Ok(0));
+        assert_eq!(waiter.join().unwrap(), Ok(1));
+        assert!(!phaser.is_terminated());
+        assert_eq!(phaser.arrive_and_deregister(), Ok(1));
+        assert!(phaser.is_terminated());
+        assert_eq!(phaser.register(), Err(PhaserError::Terminated));
+        assert_eq!(phaser.arrive(), Err(PhaserError::Terminated));
+    }
+
+    fn on_advance_runs_once_per_phase<P: TestPhaser>() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&calls);
+        let phaser = P::create_with(
+            3,
+            Box::new(move |phase, parties| {
+                recorded.lock().unwrap().push((phase, parties));
+                phase == 1
+            }),
+        );
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let phaser = phaser.clone();
+                thread::spawn(move || {
+                    assert_eq!(phaser.arrive_and_await(), Ok(1));
+                    assert_eq!(phaser.arrive_and_await(), Ok(2));
+                    assert_eq!(phaser.arrive(), Err(PhaserError::Terminated));
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(*calls.lock().unwrap(), vec![(0, 3), (1, 3)]);
+        assert!(phaser.is_terminated());
+    }
+
+    fn timeout_keeps_arrival<P: TestPhaser>() {
+        let phaser = P::create(2);
+        assert_eq!(
+            phaser.arrive_and_await_timeout(Duration::from_millis(20)),
+            Err(PhaserError::Timeout { phase: 0 })
+        );
+        // The timed-out arrival counted; one more arrival advances.
+        assert_eq!(phaser.arrive(), Ok(0));
+        assert_eq!(phaser.phase(), 1);
+        assert_eq!(phaser.await_advance(0), Ok(1));
+    }
+
+    fn too_many_arrivals<P: TestPhaser>() {
+        let phaser = P::create(0);
+        assert_eq!(phaser.arrive(), Err(PhaserError::NoUnarrivedParties));
+    }
+
+    fn force_termination_releases_waiters<P: TestPhaser>() {
+        let phaser = P::create(2);
+        let released = Arc::new(AtomicUsize::new(0));
+        let waiter = {
+            let (phaser, released) = (phaser.clone(), Arc::clone(&released));
+            thread::spawn(move || {
+                let result = phaser.arrive_and_await();
+                released.fetch_add(1, Ordering::SeqCst);
+                result
+            })
+        };
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(released.load(Ordering::SeqCst), 0);
+        phaser.force_termination();
+        assert_eq!(waiter.join().unwrap(), Err(PhaserError::Terminated));
+    }
+
+    macro_rules! phaser_tests {
+        ($($name:ident),* $(,)?) => {
+            mod blocking {
+                use super::*;
+                $(#[test] fn $name() { super::$name::<Phaser>(); })*
+            }
+            mod nonblocking {
+                use super::*;
+                $(#[test] fn $name() { super::$name::<AsyncPhaser>(); })*
+            }
+        };
+    }
+
+    phaser_tests!(
+        phases_advance_for_all_parties,
+        dynamic_registration,
+        last_deregistration_terminates,
+        on_advance_runs_once_per_phase,
+        timeout_keeps_arrival,
+        too_many_arrivals,
+        force_termination_releases_waiters,
+    );
+
+    #[tokio::test]
+    async fn test_async_phaser_with_tasks() {
+        let phaser = AsyncPhaser::new(0);
+        phaser.bulk_register(5).unwrap();
+        let sum = Arc::new(AtomicUsize::new(0));
+        let mut tasks = Vec::new();
+        for i in 1..=5 {
+            let (phaser, sum) = (phaser.clone(), Arc::clone(&sum));
+            tasks.push(tokio::spawn(async move {
+                sum.fetch_add(i, Ordering::SeqCst);
+                phaser.arrive_and_await().await.unwrap();
+                // Every task sees the full first-phase total.
+                let seen = sum.load(Ordering::SeqCst);
+                phaser.arrive_and_deregister().unwrap();
+                seen
+            }));
+        }
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), 15);
+        }
+        assert!(phaser.is_terminated());
+    }
+}
@@ -0,0 +1,342 @@
+//! Exercise 43: Scoped-Thread Algorithms - Sort, Scan and Chunked For-Each
+//! Difficulty: Expert
+//!
+//! # Learning Objectives
+//! - Split `&mut` slices between scoped threads without `Arc` or cloning
+//! - Bound the number of threads with a sequential cutoff and a depth budget
+//! - Implement a stable parallel merge sort for any `T: Send`
+//! - Implement a two-pass parallel prefix sum for any associative operator
+//! - Balance uneven chunk work dynamically
+//!
+//! Exercise 16 hands each scoped thread one piece of a slice. The same
+//! borrowing trick drives real algorithms: `split_at_mut` gives two threads
+//! disjoint halves to sort, and `chunks_mut` gives workers disjoint blocks to
+//! scan. Merging needs to move elements, which for a bare `T: Send` (no
+//! `Clone`, no `Default`) is done by computing the merged order as indices
+//! and then applying that permutation with swaps, so a panicking comparator
+//! never leaves the slice with duplicated or missing elements.
+
+use std::cmp::Ordering;
+use std::sync::Mutex;
+use std::thread;
+
+/// Runs shorter than this are sorted sequentially.
+pub const DEFAULT_CUTOFF: usize = 2048;
+
+fn available_threads() -> usize {
+    thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+/// Sorts `data` in parallel. Stable, like `slice::sort`.
+pub fn par_merge_sort<T: Ord + Send>(data: &mut [T]) {
+    par_merge_sort_by(data, DEFAULT_CUTOFF, T::cmp);
+}
+
+/// Sorts with `compare`, splitting in parallel until runs are shorter than
+/// `cutoff` or every available CPU has a half to work on.
+pub fn par_merge_sort_by<T, F>(data: &mut [T], cutoff: usize, compare: F)
+where
+    T: Send,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    // Each level doubles the number of threads.
+    let depth = available_threads().next_power_of_two().trailing_zeros() as usize;
+    sort_rec(data, cutoff.max(1), depth, &compare);
+}
+
+fn sort_rec<T, F>(data: &mut [T], cutoff: usize, depth: usize, compare: &F)
+where
+    T: Send,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    if data.len() <= cutoff {
+        data.sort_by(compare);
+        return;
+    }
+    let mid = data.len() / 2;
+    {
+        let (left, right) = data.split_at_mut(mid);
+        if depth > 0 {
+            thread::scope(|s| {
+                s.spawn(|| sort_rec(left, cutoff, depth - 1, compare));
+                sort_rec(right, cutoff, depth - 1, compare);
+            });
+        } else {
+            sort_rec(left, cutoff, 0, compare);
+            sort_rec(right, cutoff, 0, compare);
+        }
+    }
+    merge(data, mid, compare);
+}
+
+/// Merges the sorted runs `data[..mid]` and `data[mid..]` in place.
+fn merge<T, F>(data: &mut [T], mid: usize, compare: &F)
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    // Already in order: nothing to move.
+    if mid == 0 || mid == data.len() || compare(&data[mid - 1], &data[mid]) != Ordering::Greater {
+        return;
+    }
+    // `order[i]` is the current index of the element that belongs at `i`.
+    let mut order = Vec::with_capacity(data.len());
+    let (mut i, mut j) = (0, mid);
+    while i < mid && j < data.len() {
+        // Take from the left on ties to stay stable.
+        if compare(&data[j], &data[i]) == Ordering::Less {
+            order.push(j);
+            j += 1;
+        } else {
+            order.push(i);
+            i += 1;
+        }
+    }
+    order.extend(i..mid);
+    order.extend(j..data.len());
+    apply_permutation(data, &order);
+}
+
+/// Rearranges `data` so that `new[i] == old[order[i]]`, one cycle at a time.
+fn apply_permutation<T>(data: &mut [T], order: &[usize]) {
+    let mut done = vec![false; data.len()];
+    for start in 0..data.len() {
+        if done[start] {
+            continue;
+        }
+        let mut current = start;
+        loop {
+            done[current] = true;
+            let next = order[current];
+            if next == start {
+                break;
+            }
+            data.swap(current, next);
+            current = next;
+        }
+    }
+}
+
+/// Inclusive prefix scan in place: `data[i] = data[0] op ... op data[i]`.
+///
+/// `op` must be associative (it need not be commutative). Each of `threads`
+/// workers scans its own chunk, the chunk totals are scanned sequentially,
+/// and then every chunk but the first folds in the total of the chunks
+/// before it.
+pub fn par_scan<T, F>(data: &mut [T], threads: usize, op: F)
+where
+    T: Clone + Send + Sync,
+    F: Fn(&T, &T) -> T + Sync,
+{
+    if data.is_empty() {
+        return;
+    }
+    let chunk_size = data.len().div_ceil(threads.max(1));
+    let op = &op;
+
+    let totals: Vec<T> = thread::scope(|s| {
+        let handles: Vec<_> = data
+            .chunks_mut(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    scan_sequential(chunk, op);
+                    chunk[chunk.len() - 1].clone()
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    // offsets[k] = total of all chunks before chunk k + 1.
+    let mut offsets = totals;
+    scan_sequential(&mut offsets, op);
+
+    thread::scope(|s| {
+        for (chunk, offset) in data.chunks_mut(chunk_size).skip(1).zip(&offsets) {
+            s.spawn(move || {
+                for value in chunk.iter_mut() {
+                    *value = op(offset, value);
+                }
+            });
+        }
+    });
+}
+
+/// The sequential inclusive scan `par_scan` is checked against.
+pub fn scan_sequential<T: Clone, F: Fn(&T, &T) -> T>(data: &mut [T], op: F) {
+    for i in 1..data.len() {
+        data[i] = op(&data[i - 1], &data[i]);
+    }
+}
+
+/// Prefix sums of `values` using all available CPUs.
+pub fn prefix_sums(values: &[i64]) -> Vec<i64> {
+    let mut out = values.to_vec();
+    par_scan(&mut out, available_threads(), |a, b| a + b);
+    out
+}
+
+/// Calls `f(chunk_index, chunk)` for every `chunk_size` block of `data`
+/// on `threads` scoped threads. Workers pull the next chunk from a shared
+/// iterator, so slow chunks do not hold up a fixed share of the work.
+pub fn par_chunks_mut_for_each<T, F>(data: &mut [T], chunk_size: usize, threads: usize, f: F)
+where
+    T: Send,
+    F: Fn(usize, &mut [T]) + Sync,
+{
+    let chunks = Mutex::new(data.chunks_mut(chunk_size.max(1)).enumerate());
+    let (chunks, f) = (&chunks, &f);
+    thread::scope(|s| {
+        for _ in 0..threads.max(1) {
+            s.spawn(move || {
+                loop {
+                    // Release the lock before running `f`.
+                    let next = chunks.lock().unwrap().next();
+                    let Some((index, chunk)) = next else { break };
+                    f(index, chunk);
+                }
+            });
+        }
+    });
+}
+
+/// Exercise 16's `parallel_increment` on top of `par_chunks_mut_for_each`.
+pub fn parallel_increment(data: &mut [i32], n_threads: usize) {
+    let chunk_size = data.len().div_ceil(n_threads.max(1) * 4).max(1);
+    par_chunks_mut_for_each(data, chunk_size, n_threads, |_, chunk| {
+        for value in chunk {
+            *value += 1;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+    fn random_values(n: usize, seed: u64) -> Vec<u64> {
+        let mut x = seed | 1;
+        (0..n)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x % 10_000
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_sort_matches_std_sort() {
+        for (n, cutoff) in [
+            (0, 4),
+            (1, 4),
+            (7, 2),
+            (1_000, 16),
+            (100_000, DEFAULT_CUTOFF),
+        ] {
+            let mut data = random_values(n, n as u64 + 3);
+            let mut expected = data.clone();
+            expected.sort();
+            par_merge_sort_by(&mut data, cutoff, u64::cmp);
+            assert_eq!(data, expected, "n={n}");
+        }
+    }
+
+    #[test]
+    fn test_merge_sort_non_copy_and_stable() {
+        let mut words: Vec<String> = random_values(5_000, 11)
+            .into_iter()
+            .map(|v| format!("w{}", v % 300))
+            .collect();
+        let mut expected = words.clone();
+        expected.sort();
+        par_merge_sort(&mut words);
+        assert_eq!(words, expected);
+
+        // Sort by key only; equal keys must keep their input order.
+        let mut pairs: Vec<(u64, usize)> = random_values(10_000, 5)
+            .into_iter()
+            .map(|v| v % 50)
+            .enumerate()
+            .map(|(i, k)| (k, i))
+            .collect();
+        let mut expected = pairs.clone();
+        expected.sort_by_key(|p| p.0);
+        par_merge_sort_by(&mut pairs, 64, |a, b| a.0.cmp(&b.0));
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn test_merge_sort_panicking_comparator_keeps_elements() {
+        let mut data: Vec<Box<u64>> = random_values(4_000, 9).into_iter().map(Box::new).collect();
+        let mut before: Vec<u64> = data.iter().map(|b| **b).collect();
+        let calls = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            par_merge_sort_by(&mut data, 32, |a, b| {
+                if calls.fetch_add(1, AtomicOrdering::Relaxed) == 20_000 {
+                    panic!("comparator failed");
+                }
+                a.cmp(b)
+            })
+        }));
+        assert!(result.is_err());
+        // Some permutation of the input; nothing dropped or duplicated.
+        let mut after: Vec<u64> = data.iter().map(|b| **b).collect();
+        before.sort();
+        after.sort();
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn test_scan_matches_sequential() {
+        for (n, threads) in [(0, 4), (1, 4), (10, 3), (10_001, 8), (50, 64)] {
+            let values: Vec<i64> = random_values(n, 17)
+                .into_iter()
+                .map(|v| v as i64 - 5_000)
+                .collect();
+            let mut parallel = values.clone();
+            par_scan(&mut parallel, threads, |a, b| a + b);
+            let mut sequential = values;
+            scan_sequential(&mut sequential, |a, b| a + b);
+            assert_eq!(parallel, sequential, "n={n} threads={threads}");
+        }
+        assert_eq!(prefix_sums(&[1, 2, 3, 4]), vec![1, 3, 6, 10]);
+    }
+
+    #[test]
+    fn test_scan_non_commutative_operator() {
+        // Concatenation is associative but not commutative, so chunk
+        // offsets must be folded in on the left.
+        let letters: Vec<String> = "abcdefghijklmnop".chars().map(String::from).collect();
+        let mut parallel = letters.clone();
+        par_scan(&mut parallel, 5, |a, b| format!("{a}{b}"));
+        let mut sequential = letters;
+        scan_sequential(&mut sequential, |a, b| format!("{a}{b}"));
+        assert_eq!(parallel, sequential);
+        assert_eq!(parallel[15], "abcdefghijklmnop");
+    }
+
+    #[test]
+    fn test_chunks_for_each() {
+        let mut data = vec![0usize; 1_003];
+        par_chunks_mut_for_each(&mut data, 10, 4, |index, chunk| {
+            for value in chunk.iter_mut() {
+                *value = index;
+            }
+        });
+        assert!(data.iter().enumerate().all(|(i, &v)| v == i / 10));
+        assert_eq!(data[1_002], 100);
+    }
+
+    #[test]
+    fn test_parallel_increment() {
+        let mut data = vec![1, 2, 3, 4, 5];
+        parallel_increment(&mut data, 2);
+        assert_eq!(data, vec![2, 3, 4, 5, 6]);
+        let mut empty: Vec<i32> = Vec::new();
+        parallel_increment(&mut empty, 3);
+        assert!(empty.is_empty());
+    }
+}
@@ -25,11 +25,11 @@
 //! - Lock-free data structures
 //! - Memory ordering and happens-before relationships
 //!
-//! ## Difficulty Distribution (30 exercises)
+//! ## Difficulty Distribution (43 exercises)
 //! - Easy: 8 exercises (01-08)
 //! - Medium: 12 exercises (09-20)
 //! - Hard: 8 exercises (21-28)
-//! - Expert: 2 exercises (29-30)
+//! - Expert: 15 exercises (29-43)
 
 pub mod exercise_01;
 pub mod exercise_02;
@@ -61,3 +61,16 @@ pub mod exercise_27;
 pub mod exercise_28;
 pub mod exercise_29;
 pub mod exercise_30;
+pub mod exercise_31;
+pub mod exercise_32;
+pub mod exercise_33;
+pub mod exercise_34;
+pub mod exercise_35;
+pub mod exercise_36;
+pub mod exercise_37;
+pub mod exercise_38;
+pub mod exercise_39;
+pub mod exercise_40;
+pub mod exercise_41;
+pub mod exercise_42;
+pub mod exercise_43;